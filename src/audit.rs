@@ -0,0 +1,214 @@
+//! Security audit records.
+//!
+//! When `security_audit` is enabled, exactly one record is written per
+//! websocket upgrade attempt, describing who connected, to what, and
+//! whether they were let in. These go to their own logger so they can be
+//! routed separately from the debug and access logs.
+//!
+//! Connection attempts, and when accepted connections end, are also published
+//! to the `event_bus_url`, if set, without the remote address.
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use slog::{info, slog_o, Drain};
+use slog_mozlog_json::MozLogJson;
+
+use crate::event_bus::EventBus;
+use crate::meta::SenderData;
+use crate::settings::Settings;
+
+/// The result of a connection attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Accepted,
+    Rejected(&'static str),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AuditLog {
+    log: Option<slog::Logger>,
+    events: EventBus,
+}
+
+impl AuditLog {
+    pub fn new(settings: &Settings) -> Self {
+        if !settings.security_audit {
+            return Self::default();
+        }
+        let drain = MozLogJson::new(io::stdout())
+            .logger_name(format!(
+                "{}-{}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            ))
+            .msg_type(format!("{}:audit", env!("CARGO_PKG_NAME")))
+            .build()
+            .fuse();
+        let drain = slog_async::Async::new(drain).build().fuse();
+        Self {
+            log: Some(slog::Logger::root(drain, slog_o!())),
+            events: EventBus::default(),
+        }
+    }
+
+    /// Also publish connection events to `events`.
+    pub fn with_events(self, events: EventBus) -> Self {
+        Self { events, ..self }
+    }
+
+    /// Write the audit record for a connection attempt.
+    pub fn record(&self, meta: &SenderData, initial_connection: bool, outcome: Outcome) {
+        let timestamp = now();
+        let action = if initial_connection { "create" } else { "join" };
+        let (result, reason) = match outcome {
+            Outcome::Accepted => ("accepted", ""),
+            Outcome::Rejected(reason) => ("rejected", reason),
+        };
+        self.events.publish(json!({
+            "event": if outcome == Outcome::Accepted { "connect" } else { "reject" },
+            "timestamp": timestamp,
+            "country": meta.country,
+            "action": action,
+            "reason": reason,
+        }));
+        let log = match &self.log {
+            Some(log) => log,
+            None => return,
+        };
+        info!(
+            log,
+            "Connection attempt";
+            "timestamp" => timestamp,
+            "remote_ip" => meta.remote.as_deref().unwrap_or_default(),
+            "country" => meta.country.as_deref().unwrap_or_default(),
+            "action" => action,
+            "outcome" => result,
+            "reason" => reason,
+        );
+    }
+
+    /// Note that an accepted connection has ended. This isn't an audit
+    /// record, so is only published to the event bus.
+    pub fn disconnected(&self, meta: &SenderData, initial_connection: bool, duration: Duration) {
+        self.events.publish(json!({
+            "event": "disconnect",
+            "timestamp": now(),
+            "country": meta.country,
+            "action": if initial_connection { "create" } else { "join" },
+            "duration_ms": duration.as_millis() as u64,
+        }));
+    }
+}
+
+/// Milliseconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+
+    pub type Records = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+    struct Fields(HashMap<String, String>);
+
+    impl slog::Serializer for Fields {
+        fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+            self.0.insert(key.to_string(), val.to_string());
+            Ok(())
+        }
+    }
+
+    struct Capture(Records);
+
+    impl Drain for Capture {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(
+            &self,
+            record: &slog::Record,
+            values: &slog::OwnedKVList,
+        ) -> Result<Self::Ok, Self::Err> {
+            use slog::KV;
+            let mut fields = Fields(HashMap::new());
+            record.kv().serialize(record, &mut fields).unwrap();
+            values.serialize(record, &mut fields).unwrap();
+            self.0.lock().unwrap().push(fields.0);
+            Ok(())
+        }
+    }
+
+    /// An audit log that keeps its records for inspection.
+    pub fn capture() -> (AuditLog, Records) {
+        let records = Records::default();
+        let log = slog::Logger::root(Capture(records.clone()), slog_o!());
+        let audit = AuditLog {
+            log: Some(log),
+            events: EventBus::default(),
+        };
+        (audit, records)
+    }
+
+    #[test]
+    fn test_record() {
+        let (audit, records) = capture();
+        let meta = SenderData {
+            remote: Some("1.2.3.4".to_owned()),
+            country: Some("CA".to_owned()),
+            ..Default::default()
+        };
+        audit.record(&meta, true, Outcome::Accepted);
+        audit.record(&meta, false, Outcome::Rejected("channel_full"));
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["remote_ip"], "1.2.3.4");
+        assert_eq!(records[0]["country"], "CA");
+        assert_eq!(records[0]["action"], "create");
+        assert_eq!(records[0]["outcome"], "accepted");
+        assert_eq!(records[1]["action"], "join");
+        assert_eq!(records[1]["outcome"], "rejected");
+        assert_eq!(records[1]["reason"], "channel_full");
+        assert!(records[1]["timestamp"].parse::<u64>().unwrap() > 0);
+
+        // disabled by default
+        AuditLog::new(&Settings::default()).record(&meta, true, Outcome::Accepted);
+    }
+
+    #[test]
+    fn test_events() {
+        let (bus, publisher) = crate::event_bus::test::mock();
+        let audit = AuditLog::default().with_events(bus);
+        let meta = SenderData {
+            remote: Some("1.2.3.4".to_owned()),
+            country: Some("CA".to_owned()),
+            ..Default::default()
+        };
+        audit.record(&meta, true, Outcome::Accepted);
+        audit.record(&meta, false, Outcome::Rejected("channel_full"));
+        audit.disconnected(&meta, true, Duration::from_millis(1500));
+        let events = crate::event_bus::test::published(&publisher, 3);
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["event"], "connect");
+        assert_eq!(events[0]["action"], "create");
+        assert_eq!(events[0]["country"], "CA");
+        assert_eq!(events[1]["event"], "reject");
+        assert_eq!(events[1]["action"], "join");
+        assert_eq!(events[1]["reason"], "channel_full");
+        assert_eq!(events[2]["event"], "disconnect");
+        assert_eq!(events[2]["duration_ms"], 1500);
+        // Nothing identifying is published.
+        assert!(events
+            .iter()
+            .all(|event| !event.to_string().contains("1.2.3.4")));
+    }
+}
@@ -0,0 +1,172 @@
+//! Ask an external service whether to allow a connection.
+//!
+//! When `auth_hook_url` is set, each connection attempt is POSTed there as
+//! `{"remote": .., "country": .., "action": "create" | "join"}`, and the
+//! service answers `{"allow": true}` or `{"allow": false}`. A hook that
+//! errors, or doesn't answer within `auth_hook_deadline_ms`, is treated per
+//! `auth_hook_error_policy`.
+use std::time::Duration;
+
+use serde_json::json;
+use slog::warn;
+
+use crate::error::{HandlerError, HandlerErrorKind};
+use crate::logging::MozLogger;
+use crate::meta::SenderData;
+use crate::settings::Settings;
+
+#[derive(Debug)]
+pub struct AuthHook {
+    client: reqwest::Client,
+    url: String,
+    // give up on the hook after this long
+    deadline: Option<Duration>,
+    // allow connections the hook couldn't decide on
+    allow_on_error: bool,
+    log: MozLogger,
+}
+
+impl AuthHook {
+    /// Returns `None` if no `auth_hook_url` is configured.
+    pub fn new(settings: &Settings, log: &MozLogger) -> Option<Self> {
+        if settings.auth_hook_url.is_empty() {
+            return None;
+        }
+        Some(Self {
+            client: reqwest::Client::new(),
+            url: settings.auth_hook_url.clone(),
+            deadline: Some(Duration::from_millis(settings.auth_hook_deadline_ms))
+                .filter(|deadline| !deadline.is_zero()),
+            allow_on_error: settings.auth_hook_error_policy == "allow",
+            log: log.clone(),
+        })
+    }
+
+    /// Should the connection go ahead? `initial_connection` is whether it
+    /// creates a channel, rather than joining one.
+    pub async fn allows(&self, meta: &SenderData, initial_connection: bool) -> bool {
+        let ask = self.ask(meta, initial_connection);
+        let answer = match self.deadline {
+            Some(deadline) => actix_rt::time::timeout(deadline, ask)
+                .await
+                .unwrap_or_else(|_| Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into())),
+            None => ask.await,
+        };
+        answer.unwrap_or_else(|err| {
+            warn!(
+                self.log.log,
+                "Auth hook failed: {:?}", err;
+                "remote_ip" => &meta.remote,
+                "allow" => self.allow_on_error,
+            );
+            self.allow_on_error
+        })
+    }
+
+    async fn ask(&self, meta: &SenderData, initial_connection: bool) -> Result<bool, HandlerError> {
+        let body: serde_json::Value = self
+            .client
+            .post(&self.url)
+            .json(&json!({
+                "remote": meta.remote,
+                "country": meta.country,
+                "action": if initial_connection { "create" } else { "join" },
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        body["allow"]
+            .as_bool()
+            .ok_or_else(|| HandlerErrorKind::AuthHookError(body.to_string()).into())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// A stand in hook that answers every request with `body`, returning its
+    /// url and the requests made.
+    pub fn mock_hook(body: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                // Read up to the end of the JSON body.
+                let mut request = String::new();
+                let mut buf = [0; 4096];
+                while !request.ends_with('}') {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.push_str(&String::from_utf8_lossy(&buf[..n])),
+                    }
+                }
+                seen.lock().unwrap().push(request);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (format!("http://{}/check", addr), requests)
+    }
+
+    fn hook(url: String, policy: &str) -> AuthHook {
+        let settings = Settings {
+            auth_hook_url: url,
+            auth_hook_deadline_ms: 100,
+            auth_hook_error_policy: policy.to_owned(),
+            ..Default::default()
+        };
+        AuthHook::new(&settings, &MozLogger::new_human()).unwrap()
+    }
+
+    fn meta() -> SenderData {
+        SenderData {
+            remote: Some("1.2.3.4".to_owned()),
+            country: Some("Canada".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_allow() {
+        let (url, requests) = mock_hook(r#"{"allow": true}"#);
+        assert!(hook(url, "deny").allows(&meta(), true).await);
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].starts_with("POST /check "));
+        assert!(requests[0].contains(r#""action":"create""#));
+        assert!(requests[0].contains(r#""remote":"1.2.3.4""#));
+        assert!(requests[0].contains(r#""country":"Canada""#));
+    }
+
+    #[actix_rt::test]
+    async fn test_deny() {
+        let (url, requests) = mock_hook(r#"{"allow": false}"#);
+        assert!(!hook(url, "allow").allows(&meta(), false).await);
+        assert!(requests.lock().unwrap()[0].contains(r#""action":"join""#));
+    }
+
+    #[actix_rt::test]
+    async fn test_error_policy() {
+        let (url, _) = mock_hook("nonsense");
+        assert!(hook(url.clone(), "allow").allows(&meta(), true).await);
+        assert!(!hook(url, "deny").allows(&meta(), true).await);
+
+        // A hook that never answers misses its deadline.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/check", listener.local_addr().unwrap());
+        assert!(hook(url.clone(), "allow").allows(&meta(), true).await);
+        assert!(!hook(url, "deny").allows(&meta(), true).await);
+    }
+}
@@ -0,0 +1,154 @@
+//! Per channel limits, signed by a trusted orchestrator.
+//!
+//! A token is `<payload>.<signature>`, where the payload is the URL safe
+//! base64 of a JSON object such as `{"max_connections":2,"max_age":60}`, and
+//! the signature is the URL safe base64 HMAC-SHA256 of the encoded payload,
+//! keyed with `channel_token_secret`.
+use base64::Engine;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::Deserialize;
+
+use crate::settings::Settings;
+
+/// Limits for a single channel, in place of the server wide settings.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ChannelLimits {
+    /// replaces `max_channel_connections`
+    pub max_connections: Option<u8>,
+    /// replaces `max_channel_age`, in seconds
+    pub max_age: Option<u64>,
+    /// replaces `max_exchanges`
+    pub max_exchanges: Option<u8>,
+    /// replaces `max_data`
+    pub max_data: Option<u64>,
+}
+
+impl ChannelLimits {
+    /// Read the limits from a token, returning `None` if it wasn't signed
+    /// with `secret` or can't be read.
+    pub fn verify(token: &str, secret: &str) -> Option<Self> {
+        let (payload, signature) = token.split_once('.')?;
+        if !verify_signature(payload, signature, secret) {
+            return None;
+        }
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()?;
+        serde_json::from_slice(&payload).ok()
+    }
+
+    /// Keep the limits within the server's own, so a token can only tighten
+    /// them.
+    pub fn capped(self, settings: &Settings) -> Self {
+        Self {
+            max_connections: self
+                .max_connections
+                .map(|max| max.min(settings.max_channel_connections)),
+            max_age: tighter(self.max_age, settings.max_channel_age),
+            max_exchanges: tighter(self.max_exchanges, settings.max_exchanges),
+            max_data: tighter(self.max_data, settings.max_data),
+        }
+    }
+}
+
+/// The tighter of `limit` and `cap`, where 0 is unlimited.
+fn tighter<T: Copy + Ord + Default>(limit: Option<T>, cap: T) -> Option<T> {
+    let unlimited = T::default();
+    limit.map(|limit| {
+        if cap == unlimited || (limit != unlimited && limit < cap) {
+            limit
+        } else {
+            cap
+        }
+    })
+}
+
+/// HMAC-SHA256 of the encoded payload.
+pub fn sign(payload: &str, secret: &str) -> Option<Vec<u8>> {
+    let key = PKey::hmac(secret.as_bytes()).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).ok()?;
+    signer.update(payload.as_bytes()).ok()?;
+    signer.sign_to_vec().ok()
+}
+
+/// Whether `signature`, URL safe base64 encoded, is the HMAC-SHA256 of
+/// `payload` keyed with `secret`.
+pub fn verify_signature(payload: &str, signature: &str, secret: &str) -> bool {
+    let Ok(signature) = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(signature) else {
+        return false;
+    };
+    let Some(expected) = sign(payload, secret) else {
+        return false;
+    };
+    // Compare in constant time, so a signature can't be forged a byte at a time.
+    signature.len() == expected.len() && memcmp::eq(&signature, &expected)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// Sign `limits` as an orchestrator would.
+    pub fn token(limits: &serde_json::Value, secret: &str) -> String {
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(limits.to_string());
+        let signature = sign(&payload, secret).unwrap();
+        format!(
+            "{}.{}",
+            payload,
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    #[test]
+    fn test_verify() {
+        let limits = serde_json::json!({"max_connections": 2, "max_data": 1024});
+        let token = token(&limits, "s3cret");
+        assert_eq!(
+            ChannelLimits::verify(&token, "s3cret"),
+            Some(ChannelLimits {
+                max_connections: Some(2),
+                max_data: Some(1024),
+                ..Default::default()
+            })
+        );
+        assert_eq!(ChannelLimits::verify(&token, "guess"), None);
+
+        // tampered with
+        let (_, signature) = token.split_once('.').unwrap();
+        let payload =
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(r#"{"max_connections":200}"#);
+        let forged = format!("{}.{}", payload, signature);
+        assert_eq!(ChannelLimits::verify(&forged, "s3cret"), None);
+        assert_eq!(ChannelLimits::verify("garbage", "s3cret"), None);
+    }
+
+    #[test]
+    fn test_capped() {
+        let settings = Settings {
+            max_channel_connections: 3,
+            max_channel_age: 0,
+            max_exchanges: 10,
+            max_data: 100,
+            ..Default::default()
+        };
+        let limits = ChannelLimits {
+            max_connections: Some(5),
+            max_age: Some(60),
+            max_exchanges: Some(0),
+            max_data: Some(50),
+        };
+        assert_eq!(
+            limits.capped(&settings),
+            ChannelLimits {
+                max_connections: Some(3),
+                max_age: Some(60),
+                max_exchanges: Some(10),
+                max_data: Some(50),
+            }
+        );
+    }
+}
@@ -1,44 +1,100 @@
 use std::fmt;
+use std::str::FromStr;
 
 use base64::Engine;
 
-use rand::RngCore;
+use rand::{rngs::OsRng, RngCore};
+use serde::de::{self, Deserialize, Deserializer};
 use serde::ser::{Serialize, Serializer};
 
-const CHANNELID_LEN: usize = 16;
+/// Default length of a channel id in bytes.
+pub const CHANNELID_LEN: usize = 16;
+/// Shortest `channelid_len` allowed.
+pub const MIN_CHANNELID_LEN: usize = 16;
+/// Longest `channelid_len` allowed.
+pub const MAX_CHANNELID_LEN: usize = 64;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct ChannelID {
-    value: [u8; CHANNELID_LEN],
+    // Only the first `len` bytes are used, the rest are always 0.
+    value: [u8; MAX_CHANNELID_LEN],
+    len: usize,
 }
 
 impl ChannelID {
+    /// Generate a new random channel id of `len` bytes.
+    ///
+    /// Channel ids are bearer secrets (anyone who knows one can join the
+    /// channel), so they are drawn directly from the operating system's CSPRNG
+    /// rather than a userspace generator.
+    pub fn random(len: usize) -> Self {
+        let len = len.clamp(MIN_CHANNELID_LEN, MAX_CHANNELID_LEN);
+        let mut value = [0; MAX_CHANNELID_LEN];
+        OsRng.fill_bytes(&mut value[..len]);
+        Self { value, len }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.value[..self.len]
+    }
+
     pub fn as_string(self) -> String {
-        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.value)
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.as_bytes())
+    }
+
+    /// Return the URL safe base64 form including trailing `=` padding.
+    /// `from_str` accepts either form.
+    pub fn as_padded_string(self) -> String {
+        base64::engine::general_purpose::URL_SAFE.encode(self.as_bytes())
     }
 
-    pub fn from_str(string: &str) -> Result<ChannelID, base64::DecodeError> {
+    /// Parse a channel id, which must decode to exactly `len` bytes.
+    ///
+    /// This is the only way to parse ids of the configured `channelid_len`.
+    /// `FromStr`, `TryFrom` and `Deserialize` can't see the settings, so they
+    /// only accept ids of the default `CHANNELID_LEN`.
+    pub fn parse(string: &str, len: usize) -> Result<ChannelID, base64::DecodeError> {
         let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
             .decode(string.trim_end_matches('='))?;
-        let mut array = [0; 16];
-        array.copy_from_slice(&bytes[..16]);
-        Ok(ChannelID { value: array })
+        if bytes.len() != len || len > MAX_CHANNELID_LEN {
+            return Err(base64::DecodeError::InvalidLength(string.len()));
+        }
+        let mut value = [0; MAX_CHANNELID_LEN];
+        value[..len].copy_from_slice(&bytes);
+        Ok(ChannelID { value, len })
+    }
+}
+
+impl FromStr for ChannelID {
+    type Err = base64::DecodeError;
+
+    /// Parse a channel id of the default length. Use `ChannelID::parse` for
+    /// ids of any other `channelid_len`.
+    fn from_str(string: &str) -> Result<Self, Self::Err> {
+        Self::parse(string, CHANNELID_LEN)
+    }
+}
+
+impl TryFrom<&str> for ChannelID {
+    type Error = base64::DecodeError;
+
+    /// As `from_str`, so only ids of the default length.
+    fn try_from(string: &str) -> Result<Self, Self::Error> {
+        string.parse()
     }
 }
 
 impl Default for ChannelID {
+    /// Generate a new random channel id of the default length.
     fn default() -> Self {
-        let mut rng = rand::thread_rng();
-        let mut bytes = [0; CHANNELID_LEN];
-        rng.fill_bytes(&mut bytes);
-        Self { value: bytes }
+        Self::random(CHANNELID_LEN)
     }
 }
 
 impl fmt::Display for ChannelID {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // calling to_string() causes a stack overflow.
-        let as_b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.value);
+        let as_b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(self.as_bytes());
         write!(f, "{}", as_b64)
     }
 }
@@ -52,6 +108,20 @@ impl Serialize for ChannelID {
     }
 }
 
+impl<'de> Deserialize<'de> for ChannelID {
+    /// Accepts the same strings as `from_str`, so only ids of the default
+    /// length.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let string = String::deserialize(deserializer)?;
+        string
+            .parse()
+            .map_err(|err| de::Error::custom(format!("invalid channel id {:?}: {}", string, err)))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -65,5 +135,89 @@ mod test {
         ChannelID::from_str("invalid").expect_err("rejected");
         let output = format!("{}", chan);
         assert_eq!("j6jLPVPeQR6diyrkQinRAQ".to_owned(), output);
+
+        for len in [CHANNELID_LEN, 32, MAX_CHANNELID_LEN] {
+            let chan = ChannelID::random(len);
+            assert_eq!(chan.as_bytes().len(), len);
+            assert_eq!(ChannelID::parse(&chan.as_string(), len).unwrap(), chan);
+            assert_eq!(format!("{}", chan), chan.as_string());
+        }
+    }
+
+    #[test]
+    fn test_traits() {
+        let raw_id = "j6jLPVPeQR6diyrkQinRAQ";
+        let chan: ChannelID = raw_id.parse().unwrap();
+        assert_eq!(chan.as_string(), raw_id);
+        assert_eq!(ChannelID::try_from(raw_id).unwrap(), chan);
+        assert!("AAAA".parse::<ChannelID>().is_err());
+        assert!(ChannelID::try_from("invalid").is_err());
+
+        // Longer ids need `parse`.
+        let long = ChannelID::random(32).as_string();
+        assert!(long.parse::<ChannelID>().is_err());
+        assert!(ChannelID::parse(&long, 32).is_ok());
+    }
+
+    #[test]
+    fn test_serde() {
+        let chan = ChannelID::default();
+        let json = serde_json::to_string(&chan).unwrap();
+        assert_eq!(json, format!("\"{}\"", chan.as_string()));
+        assert_eq!(serde_json::from_str::<ChannelID>(&json).unwrap(), chan);
+
+        let padded = format!("\"{}\"", chan.as_padded_string());
+        assert_eq!(serde_json::from_str::<ChannelID>(&padded).unwrap(), chan);
+        assert!(serde_json::from_str::<ChannelID>("\"AAAA\"").is_err());
+        assert!(serde_json::from_str::<ChannelID>("16").is_err());
+    }
+
+    #[test]
+    fn test_default_randomness() {
+        for len in [CHANNELID_LEN, 32] {
+            let count = 1000;
+            let ids: Vec<ChannelID> = (0..count).map(|_| ChannelID::random(len)).collect();
+            let unique: std::collections::HashSet<&ChannelID> = ids.iter().collect();
+            assert_eq!(unique.len(), count);
+
+            // Each bit position should be set about half the time. With 1000
+            // samples the odds of a fair bit landing outside 35-65% are negligible.
+            for bit in 0..len * 8 {
+                let set = ids
+                    .iter()
+                    .filter(|id| id.as_bytes()[bit / 8] & (1 << (bit % 8)) != 0)
+                    .count();
+                assert!((350..=650).contains(&set), "bit {} set {} times", bit, set);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bad_length() {
+        // valid base64, but too short
+        ChannelID::from_str("AAAA").expect_err("rejected");
+        // ...or too long
+        ChannelID::from_str("j6jLPVPeQR6diyrkQinRAQAA").expect_err("rejected");
+        ChannelID::from_str("").expect_err("rejected");
+
+        // The right length for the default, but not the configured, length.
+        let chan = ChannelID::default();
+        ChannelID::parse(&chan.as_string(), 32).expect_err("rejected");
+        let long = ChannelID::random(32);
+        ChannelID::from_str(&long.as_string()).expect_err("rejected");
+        ChannelID::parse(&long.as_string(), 32).unwrap();
+    }
+
+    #[test]
+    fn test_padded() {
+        for len in [CHANNELID_LEN, 32] {
+            let chan = ChannelID::random(len);
+            let padded = chan.as_padded_string();
+            assert_eq!(ChannelID::parse(&padded, len).unwrap(), chan);
+        }
+        let chan = ChannelID::from_str("j6jLPVPeQR6diyrkQinRAQ").unwrap();
+        let padded = chan.as_padded_string();
+        assert_eq!("j6jLPVPeQR6diyrkQinRAQ==".to_owned(), padded);
+        assert_eq!(ChannelID::from_str(&padded).unwrap(), chan);
     }
 }
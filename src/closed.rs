@@ -0,0 +1,118 @@
+//! Remember recently closed channels, so that late joiners can be told why
+//! the channel is gone rather than just that it's unknown.
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::channelid::ChannelID;
+use crate::server::DisconnectReason;
+
+/// A bounded record of recently closed channels.
+///
+/// Entries are kept for a retention period that depends on why the channel
+/// closed; channels shut down for abuse are remembered longer. Once `capacity`
+/// is reached the oldest entry is evicted.
+#[derive(Debug)]
+pub struct ClosedChannels {
+    capacity: usize,
+    ttl: Duration,
+    abuse_ttl: Duration,
+    entries: HashMap<ChannelID, (Instant, DisconnectReason)>,
+    // close order, oldest first
+    order: VecDeque<ChannelID>,
+}
+
+impl ClosedChannels {
+    pub fn new(capacity: usize, ttl: Duration, abuse_ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            abuse_ttl,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn retention(&self, reason: &DisconnectReason) -> Duration {
+        match reason {
+            DisconnectReason::ExcessData | DisconnectReason::ExcessMessages => self.abuse_ttl,
+            _ => self.ttl,
+        }
+    }
+
+    /// Record that a channel closed.
+    pub fn insert(&mut self, channel: ChannelID, reason: DisconnectReason) {
+        if self.capacity == 0 || self.retention(&reason).is_zero() {
+            return;
+        }
+        if self
+            .entries
+            .insert(channel, (Instant::now(), reason))
+            .is_some()
+        {
+            self.order.retain(|c| *c != channel);
+        }
+        self.order.push_back(channel);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Why did the channel close, if it did so recently?
+    pub fn get(&mut self, channel: &ChannelID) -> Option<DisconnectReason> {
+        let (closed, reason) = *self.entries.get(channel)?;
+        if closed.elapsed() >= self.retention(&reason) {
+            self.entries.remove(channel);
+            self.order.retain(|c| c != channel);
+            return None;
+        }
+        Some(reason)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_evict_by_size() {
+        let mut closed = ClosedChannels::new(2, Duration::from_secs(60), Duration::from_secs(60));
+        let channels: Vec<ChannelID> = (0..3).map(|_| ChannelID::default()).collect();
+        for channel in &channels {
+            closed.insert(*channel, DisconnectReason::None);
+        }
+        assert_eq!(closed.len(), 2);
+        assert_eq!(closed.get(&channels[0]), None);
+        assert_eq!(closed.get(&channels[1]), Some(DisconnectReason::None));
+        assert_eq!(closed.get(&channels[2]), Some(DisconnectReason::None));
+    }
+
+    #[test]
+    fn test_expire_by_reason() {
+        let mut closed =
+            ClosedChannels::new(10, Duration::from_millis(50), Duration::from_secs(60));
+        let normal = ChannelID::default();
+        let abusive = ChannelID::default();
+        closed.insert(normal, DisconnectReason::None);
+        closed.insert(abusive, DisconnectReason::ExcessMessages);
+        assert_eq!(closed.get(&normal), Some(DisconnectReason::None));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(closed.get(&normal), None);
+        assert_eq!(closed.get(&abusive), Some(DisconnectReason::ExcessMessages));
+        assert_eq!(closed.len(), 1);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut closed = ClosedChannels::new(0, Duration::from_secs(60), Duration::from_secs(60));
+        let channel = ChannelID::default();
+        closed.insert(channel, DisconnectReason::None);
+        assert_eq!(closed.get(&channel), None);
+    }
+}
@@ -49,4 +49,8 @@ pub enum HandlerErrorKind {
     MetricsError(#[from] cadence::MetricError),
     #[error("Bad remote address: {:?}", _0)]
     BadRemoteAddrError(String),
+    #[error(transparent)]
+    IPRepError(#[from] reqwest::Error),
+    #[error("Bad auth hook answer: {:?}", _0)]
+    AuthHookError(String),
 }
@@ -0,0 +1,294 @@
+//! Publish connection events to a message bus, for real time abuse detection.
+//!
+//! Events are queued and published from their own thread, so a slow or
+//! unreachable bus never holds up connections. Once `event_bus_queue` events
+//! are waiting, further events are dropped. Like the analytics event log,
+//! events don't carry anything that identifies a participant.
+//!
+//! Publishing to NATS (`nats://host:port/subject`) requires building with the
+//! `event_bus` feature.
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+
+use cadence::{CountedExt, StatsdClient};
+use slog::{error, warn};
+
+use crate::logging::MozLogger;
+use crate::settings::Settings;
+
+/// Somewhere to send events.
+pub trait Publisher: Send {
+    fn publish(&mut self, event: &str) -> std::io::Result<()>;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct EventBus {
+    queue: Option<SyncSender<String>>,
+    metrics: Option<Arc<StatsdClient>>,
+}
+
+impl EventBus {
+    pub fn new(settings: &Settings, log: &MozLogger, metrics: &Arc<StatsdClient>) -> Self {
+        if settings.event_bus_url.is_empty() {
+            return Self::default();
+        }
+        match publisher(&settings.event_bus_url) {
+            Ok(publisher) => Self::spawn(publisher, settings.event_bus_queue, log, metrics),
+            Err(err) => {
+                error!(
+                    log.log,
+                    "Not publishing events: {}", err;
+                    "event_bus_url" => &settings.event_bus_url,
+                );
+                Self::default()
+            }
+        }
+    }
+
+    /// Publish queued events with `publisher` from a thread of its own.
+    pub fn spawn(
+        mut publisher: Box<dyn Publisher>,
+        size: usize,
+        log: &MozLogger,
+        metrics: &Arc<StatsdClient>,
+    ) -> Self {
+        let (bus, queue) = Self::bounded(size, metrics);
+        let log = log.clone();
+        let metrics = metrics.clone();
+        thread::spawn(move || {
+            for event in queue {
+                if let Err(err) = publisher.publish(&event) {
+                    warn!(log.log, "Could not publish event: {:?}", err);
+                    metrics.incr("event_bus.error").ok();
+                }
+            }
+        });
+        bus
+    }
+
+    /// A bus holding up to `size` events, and where to read them from.
+    fn bounded(size: usize, metrics: &Arc<StatsdClient>) -> (Self, Receiver<String>) {
+        let (tx, rx) = sync_channel(size);
+        let bus = Self {
+            queue: Some(tx),
+            metrics: Some(metrics.clone()),
+        };
+        (bus, rx)
+    }
+
+    /// Queue an event to publish, dropping it if the queue is full.
+    pub fn publish(&self, event: serde_json::Value) {
+        let queue = match &self.queue {
+            Some(queue) => queue,
+            None => return,
+        };
+        if let Err(TrySendError::Full(_)) = queue.try_send(event.to_string()) {
+            if let Some(metrics) = &self.metrics {
+                metrics.incr("event_bus.dropped").ok();
+            }
+        }
+    }
+}
+
+/// The publisher for the bus at `url`.
+fn publisher(url: &str) -> Result<Box<dyn Publisher>, String> {
+    let url = reqwest::Url::parse(url).map_err(|err| err.to_string())?;
+    match url.scheme() {
+        #[cfg(feature = "event_bus")]
+        "nats" => nats::Nats::new(&url).map(|nats| Box::new(nats) as Box<dyn Publisher>),
+        #[cfg(not(feature = "event_bus"))]
+        "nats" => Err("built without the event_bus feature".to_owned()),
+        scheme => Err(format!("unsupported event bus {:?}", scheme)),
+    }
+}
+
+#[cfg(feature = "event_bus")]
+mod nats {
+    //! Just enough of the NATS client protocol to publish.
+    use std::io::{self, Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use super::Publisher;
+
+    const DEFAULT_PORT: u16 = 4222;
+    const DEFAULT_SUBJECT: &str = "channelserver.events";
+
+    pub struct Nats {
+        addr: String,
+        subject: String,
+        conn: Option<TcpStream>,
+    }
+
+    impl Nats {
+        pub fn new(url: &reqwest::Url) -> Result<Self, String> {
+            let host = url.host_str().ok_or("missing host")?;
+            let subject = url.path().trim_matches('/');
+            Ok(Self {
+                addr: format!("{}:{}", host, url.port().unwrap_or(DEFAULT_PORT)),
+                subject: if subject.is_empty() {
+                    DEFAULT_SUBJECT.to_owned()
+                } else {
+                    subject.replace('/', ".")
+                },
+                conn: None,
+            })
+        }
+
+        fn connect(&self) -> io::Result<TcpStream> {
+            let mut conn = TcpStream::connect(&self.addr)?;
+            conn.set_write_timeout(Some(Duration::from_secs(5)))?;
+            conn.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")?;
+            conn.set_nonblocking(true)?;
+            Ok(conn)
+        }
+
+        fn send(conn: &mut TcpStream, subject: &str, event: &str) -> io::Result<()> {
+            // Answer the server's keepalive pings, or it drops us.
+            let mut buf = [0u8; 1024];
+            match conn.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::ConnectionReset.into()),
+                Ok(n) if buf[..n].windows(4).any(|w| w == b"PING") => {
+                    conn.write_all(b"PONG\r\n")?
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+            conn.set_nonblocking(false)?;
+            let sent = write!(conn, "PUB {} {}\r\n{}\r\n", subject, event.len(), event);
+            conn.set_nonblocking(true)?;
+            sent
+        }
+    }
+
+    impl Publisher for Nats {
+        fn publish(&mut self, event: &str) -> io::Result<()> {
+            if let Some(mut conn) = self.conn.take() {
+                if Self::send(&mut conn, &self.subject, event).is_ok() {
+                    self.conn = Some(conn);
+                    return Ok(());
+                }
+                // The server went away since the last event: send this one
+                // again once we're back.
+            }
+            let mut conn = self.connect()?;
+            Self::send(&mut conn, &self.subject, event)?;
+            self.conn = Some(conn);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use cadence::SpyMetricSink;
+    use serde_json::json;
+
+    /// Keeps what it's sent for inspection.
+    #[derive(Clone, Default)]
+    pub struct MockPublisher(pub Arc<Mutex<Vec<serde_json::Value>>>);
+
+    impl Publisher for MockPublisher {
+        fn publish(&mut self, event: &str) -> std::io::Result<()> {
+            self.0.lock().unwrap().push(serde_json::from_str(event)?);
+            Ok(())
+        }
+    }
+
+    /// A bus publishing to a `MockPublisher`.
+    pub fn mock() -> (EventBus, MockPublisher) {
+        let publisher = MockPublisher::default();
+        let metrics = Arc::new(StatsdClient::from_sink("test", cadence::NopMetricSink));
+        let bus = EventBus::spawn(
+            Box::new(publisher.clone()),
+            10,
+            &MozLogger::new_human(),
+            &metrics,
+        );
+        (bus, publisher)
+    }
+
+    /// Wait for the bus's thread to catch up.
+    pub fn published(publisher: &MockPublisher, count: usize) -> Vec<serde_json::Value> {
+        for _ in 0..100 {
+            if publisher.0.lock().unwrap().len() >= count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        publisher.0.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn test_publish() {
+        let (bus, publisher) = mock();
+        bus.publish(json!({"event": "a"}));
+        bus.publish(json!({"event": "b"}));
+        assert_eq!(
+            published(&publisher, 2),
+            vec![json!({"event": "a"}), json!({"event": "b"})]
+        );
+
+        // disabled by default
+        EventBus::default().publish(json!({"event": "c"}));
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::from_sink("test", sink));
+        // Nothing reads the queue, as if the bus had stalled.
+        let (bus, _queue) = EventBus::bounded(1, &metrics);
+        bus.publish(json!({"event": "a"}));
+        bus.publish(json!({"event": "b"}));
+        assert_eq!(rx.try_recv().unwrap(), b"test.event_bus.dropped:1|c");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[cfg(feature = "event_bus")]
+    #[test]
+    fn test_nats() {
+        use std::io::Read;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("nats://{}/pair/events", listener.local_addr().unwrap());
+        let mut nats = publisher(&url).unwrap();
+        nats.publish(r#"{"event":"a"}"#).unwrap();
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut received = String::new();
+        let mut buf = [0u8; 256];
+        while !received.ends_with("}\r\n") {
+            let n = conn.read(&mut buf).unwrap();
+            received.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+        }
+        assert!(received.starts_with("CONNECT "));
+        assert!(received.ends_with("PUB pair.events 13\r\n{\"event\":\"a\"}\r\n"));
+
+        // The server restarts: the next event goes out on a new connection.
+        drop(conn);
+        nats.publish(r#"{"event":"b"}"#).unwrap();
+        let (mut conn, _) = listener.accept().unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut received = String::new();
+        while !received.ends_with("}\r\n") {
+            let n = conn.read(&mut buf).unwrap();
+            received.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+        }
+        assert!(received.starts_with("CONNECT "));
+        assert!(received.ends_with("PUB pair.events 13\r\n{\"event\":\"b\"}\r\n"));
+    }
+
+    #[test]
+    fn test_publisher() {
+        assert!(publisher("kafka://localhost:9092/events").is_err());
+        assert!(publisher("bogus").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Remember recent GeoIP lookups, so reconnect storms from the same few
+//! addresses don't each need a fresh database lookup.
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+/// Where an address resolved to, with names in the requested languages.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Location {
+    pub city: Option<String>,
+    pub region: Option<String>,
+    pub country: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub timezone: Option<String>,
+}
+
+/// The address looked up, and the preferred languages the names were picked
+/// for.
+pub type GeoKey = (IpAddr, String);
+
+/// A bounded, least recently used, record of lookups.
+///
+/// Once `capacity` is reached the least recently used entry is evicted.
+#[derive(Debug)]
+pub struct GeoCache {
+    capacity: usize,
+    entries: HashMap<GeoKey, Location>,
+    // use order, least recent first
+    order: VecDeque<GeoKey>,
+}
+
+impl GeoCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Where the address resolved to, if it was looked up recently.
+    pub fn get(&mut self, key: &GeoKey) -> Option<Location> {
+        let location = self.entries.get(key)?.clone();
+        self.touch(key);
+        Some(location)
+    }
+
+    /// Record where an address resolved to.
+    pub fn insert(&mut self, key: GeoKey, location: Location) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.insert(key.clone(), location).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget everything, e.g. once the database has been reloaded.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn touch(&mut self, key: &GeoKey) {
+        if let Some(index) = self.order.iter().position(|k| k == key) {
+            if let Some(key) = self.order.remove(index) {
+                self.order.push_back(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(ip: &str) -> GeoKey {
+        (ip.parse().unwrap(), "en".to_owned())
+    }
+
+    fn location(city: &str) -> Location {
+        Location {
+            city: Some(city.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_evict_least_recent() {
+        let mut cache = GeoCache::new(2);
+        cache.insert(key("1.1.1.1"), location("a"));
+        cache.insert(key("2.2.2.2"), location("b"));
+        // Using the first makes the second the least recent.
+        assert_eq!(cache.get(&key("1.1.1.1")), Some(location("a")));
+        cache.insert(key("3.3.3.3"), location("c"));
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.get(&key("2.2.2.2")), None);
+        assert_eq!(cache.get(&key("1.1.1.1")), Some(location("a")));
+        assert_eq!(cache.get(&key("3.3.3.3")), Some(location("c")));
+        // Languages are part of the key.
+        assert_eq!(
+            cache.get(&("1.1.1.1".parse().unwrap(), "fr".to_owned())),
+            None
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut cache = GeoCache::new(0);
+        cache.insert(key("1.1.1.1"), location("a"));
+        assert_eq!(cache.get(&key("1.1.1.1")), None);
+    }
+}
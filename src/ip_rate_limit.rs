@@ -0,0 +1,454 @@
+//! Client for the [iprepd](https://github.com/mozilla-services/iprepd) IP
+//! reputation service.
+//!
+//! Requests are made with reqwest's async client, so waiting on iprepd never
+//! holds up the actix worker handling other connections.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use cadence::{CountedExt, StatsdClient};
+use serde_json::json;
+use slog::{debug, info, warn};
+
+use crate::error::HandlerError;
+use crate::logging::MozLogger;
+use crate::settings::Settings;
+
+// Longest any one iprepd request may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug)]
+pub struct IPReputation {
+    client: reqwest::Client,
+    // base url of the iprepd server
+    server: String,
+    // reputations below this are abusive
+    min: u8,
+    // name of the violation to report
+    violation: String,
+    // don't report the same address more often than this
+    report_cooldown: Duration,
+    // addresses recently reported, and when
+    reported: Mutex<HashMap<String, Instant>>,
+    // how long to remember an address's reputation
+    cache_ttl: Duration,
+    // addresses recently looked up, when, and their reputation
+    cache: Mutex<HashMap<String, (Instant, u64)>>,
+    // give up on a reputation check after this long
+    deadline: Option<Duration>,
+    // treat addresses as abusive when their check fails or is given up on
+    fail_closed: bool,
+    log: MozLogger,
+    metrics: Arc<StatsdClient>,
+}
+
+impl IPReputation {
+    /// Returns `None` if no `ip_reputation_server` is configured.
+    pub fn new(settings: &Settings, log: &MozLogger, metrics: &Arc<StatsdClient>) -> Option<Self> {
+        if settings.ip_reputation_server.is_empty() {
+            return None;
+        }
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Some(Self {
+            client,
+            server: settings
+                .ip_reputation_server
+                .trim_end_matches('/')
+                .to_owned(),
+            min: settings.iprep_min,
+            violation: settings.ip_violation.clone(),
+            report_cooldown: Duration::from_secs(settings.iprepd_report_cooldown),
+            reported: Mutex::new(HashMap::new()),
+            cache_ttl: Duration::from_secs(settings.iprep_cache_ttl),
+            cache: Mutex::new(HashMap::new()),
+            deadline: Some(Duration::from_millis(settings.iprepd_deadline_ms))
+                .filter(|deadline| !deadline.is_zero()),
+            fail_closed: settings.iprepd_timeout_policy == "closed",
+            log: log.clone(),
+            metrics: metrics.clone(),
+        })
+    }
+
+    /// Is the address's reputation below `iprep_min`?
+    pub async fn is_abusive(&self, remote: &str) -> Result<bool, HandlerError> {
+        let reputation = match self.cached(remote) {
+            Some(reputation) => reputation,
+            None => {
+                let reputation = self.reputation(remote).await?;
+                self.remember(remote, reputation);
+                reputation
+            }
+        };
+        Ok(reputation < u64::from(self.min))
+    }
+
+    /// Ask iprepd for the address's reputation.
+    async fn reputation(&self, remote: &str) -> Result<u64, HandlerError> {
+        let resp = self
+            .client
+            .get(format!("{}/type/ip/{}", self.server, remote))
+            .send()
+            .await?;
+        // iprepd doesn't know about the address, so it's in good standing.
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(100);
+        }
+        let body: serde_json::Value = resp.error_for_status()?.json().await?;
+        Ok(body["reputation"].as_u64().unwrap_or(100))
+    }
+
+    /// The address's reputation, if it was looked up within `iprep_cache_ttl`.
+    fn cached(&self, remote: &str) -> Option<u64> {
+        if self.cache_ttl.is_zero() {
+            return None;
+        }
+        let cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        let reputation = cache
+            .get(remote)
+            .filter(|(at, _)| at.elapsed() < self.cache_ttl)
+            .map(|(_, reputation)| *reputation);
+        let metric = if reputation.is_some() {
+            "iprep.cache.hit"
+        } else {
+            "iprep.cache.miss"
+        };
+        self.metrics.incr(metric).ok();
+        reputation
+    }
+
+    fn remember(&self, remote: &str, reputation: u64) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        cache.retain(|_, (at, _)| now.duration_since(*at) < self.cache_ttl);
+        cache.insert(remote.to_owned(), (now, reputation));
+    }
+
+    /// `is_abusive`, but giving up after `iprepd_deadline_ms`, so a slow
+    /// iprepd can't hold up connections. Addresses it gives up on, or can't
+    /// check at all, are judged by `iprepd_timeout_policy`.
+    pub async fn check(&self, remote: &str) -> bool {
+        let result = match self.deadline {
+            Some(deadline) => {
+                match actix_rt::time::timeout(deadline, self.is_abusive(remote)).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        info!(
+                            self.log.log,
+                            "iprepd check missed its deadline";
+                            "remote_ip" => remote,
+                            "fail_closed" => self.fail_closed,
+                        );
+                        return self.fail_closed;
+                    }
+                }
+            }
+            None => self.is_abusive(remote).await,
+        };
+        result.unwrap_or_else(|err| {
+            warn!(
+                self.log.log,
+                "Could not check IP reputation: {:?}", err;
+                "remote_ip" => remote,
+                "fail_closed" => self.fail_closed,
+            );
+            self.fail_closed
+        })
+    }
+
+    /// Report the address for `ip_violation`, unless it was already reported
+    /// within `iprepd_report_cooldown`.
+    pub async fn add_abuser(&self, remote: &str) -> Result<(), HandlerError> {
+        if self.recently_reported(remote) {
+            debug!(self.log.log, "Skipping repeat iprepd report"; "remote_ip" => remote);
+            return Ok(());
+        }
+        let result = self
+            .client
+            .put(format!("{}/violations/type/ip/{}", self.server, remote))
+            .json(&json!({
+                "object": remote,
+                "type": "ip",
+                "violation": self.violation,
+            }))
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        if let Err(err) = result {
+            warn!(self.log.log, "Could not report abuser: {:?}", err; "remote_ip" => remote);
+            return Err(err.into());
+        }
+        // Only once it's been reported, so a failure is tried again.
+        if !self.report_cooldown.is_zero() {
+            let mut reported = self.reported.lock().unwrap_or_else(|e| e.into_inner());
+            reported.insert(remote.to_owned(), Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Was the address reported within `iprepd_report_cooldown`?
+    fn recently_reported(&self, remote: &str) -> bool {
+        let now = Instant::now();
+        let mut reported = self.reported.lock().unwrap_or_else(|e| e.into_inner());
+        reported.retain(|_, at| now.duration_since(*at) < self.report_cooldown);
+        reported.contains_key(remote)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn nop_metrics() -> Arc<StatsdClient> {
+        Arc::new(StatsdClient::from_sink("test", cadence::NopMetricSink))
+    }
+
+    /// A stand in iprepd that accepts everything, returning the address it
+    /// listens on and a count of requests made.
+    pub fn mock_iprepd() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                count.fetch_add(1, Ordering::SeqCst);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                );
+            }
+        });
+        (format!("http://{}", addr), requests)
+    }
+
+    /// A stand in iprepd that gives every address `reputation`, returning
+    /// the address it listens on and a count of requests made.
+    pub fn reputation_iprepd(reputation: u8) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                count.fetch_add(1, Ordering::SeqCst);
+                let body = json!({"reputation": reputation}).to_string();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (format!("http://{}", addr), requests)
+    }
+
+    /// A stand in iprepd that never answers.
+    fn stalled_iprepd() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut held = Vec::new();
+            for stream in listener.incoming().flatten() {
+                held.push(stream);
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    /// Where nothing is listening.
+    fn unreachable_iprepd() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    fn stalled_settings(policy: &str) -> Settings {
+        Settings {
+            ip_reputation_server: stalled_iprepd(),
+            iprep_min: 50,
+            iprepd_deadline_ms: 50,
+            iprepd_timeout_policy: policy.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_is_abusive() {
+        for (reputation, abusive) in [(10, true), (49, true), (50, false), (90, false)] {
+            let settings = Settings {
+                ip_reputation_server: reputation_iprepd(reputation).0,
+                iprep_min: 50,
+                ..Default::default()
+            };
+            let iprep =
+                IPReputation::new(&settings, &MozLogger::new_human(), &nop_metrics()).unwrap();
+            assert_eq!(
+                iprep.is_abusive("1.2.3.4").await.unwrap(),
+                abusive,
+                "{}",
+                reputation
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_cache() {
+        let (server, requests) = reputation_iprepd(10);
+        let settings = Settings {
+            ip_reputation_server: server,
+            iprep_min: 50,
+            iprep_cache_ttl: 60,
+            ..Default::default()
+        };
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::from_sink("test", sink));
+        let iprep = IPReputation::new(&settings, &MozLogger::new_human(), &metrics).unwrap();
+        let cached = || -> Vec<String> {
+            rx.try_iter()
+                .map(|metric| String::from_utf8(metric).unwrap())
+                .collect()
+        };
+
+        assert!(iprep.is_abusive("1.2.3.4").await.unwrap());
+        assert_eq!(cached(), vec!["test.iprep.cache.miss:1|c"]);
+        assert!(iprep.is_abusive("1.2.3.4").await.unwrap());
+        assert_eq!(cached(), vec!["test.iprep.cache.hit:1|c"]);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(iprep.is_abusive("2.3.4.5").await.unwrap());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Expired entries are looked up afresh.
+        iprep
+            .cache
+            .lock()
+            .unwrap()
+            .values_mut()
+            .for_each(|(at, _)| *at -= Duration::from_secs(60));
+        assert!(iprep.is_abusive("1.2.3.4").await.unwrap());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        // Or always, without a ttl.
+        let iprep = IPReputation::new(
+            &Settings {
+                iprep_cache_ttl: 0,
+                ..settings
+            },
+            &MozLogger::new_human(),
+            &metrics,
+        )
+        .unwrap();
+        iprep.is_abusive("1.2.3.4").await.unwrap();
+        iprep.is_abusive("1.2.3.4").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 5);
+    }
+
+    #[actix_rt::test]
+    async fn test_non_blocking() {
+        let settings = Settings {
+            iprepd_deadline_ms: 500,
+            ..stalled_settings("open")
+        };
+        let iprep = IPReputation::new(&settings, &MozLogger::new_human(), &nop_metrics()).unwrap();
+        let start = Instant::now();
+        // The test runtime has a single thread, so this only gets to run
+        // while the check is waiting if the check isn't blocking it.
+        let (abusive, ticked) = futures::join!(iprep.check("1.2.3.4"), async {
+            actix_rt::time::sleep(Duration::from_millis(10)).await;
+            start.elapsed()
+        });
+        assert!(!abusive);
+        assert!(ticked < Duration::from_millis(500), "{:?}", ticked);
+    }
+
+    #[actix_rt::test]
+    async fn test_deadline_fail_open() {
+        let settings = stalled_settings("open");
+        let iprep = IPReputation::new(&settings, &MozLogger::new_human(), &nop_metrics()).unwrap();
+        let start = Instant::now();
+        assert!(!iprep.check("1.2.3.4").await);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[actix_rt::test]
+    async fn test_deadline_fail_closed() {
+        let settings = stalled_settings("closed");
+        let iprep = IPReputation::new(&settings, &MozLogger::new_human(), &nop_metrics()).unwrap();
+        let start = Instant::now();
+        assert!(iprep.check("1.2.3.4").await);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[actix_rt::test]
+    async fn test_error_policy() {
+        for (policy, abusive) in [("open", false), ("closed", true)] {
+            let settings = Settings {
+                ip_reputation_server: unreachable_iprepd(),
+                iprepd_timeout_policy: policy.to_owned(),
+                ..Default::default()
+            };
+            let iprep =
+                IPReputation::new(&settings, &MozLogger::new_human(), &nop_metrics()).unwrap();
+            assert_eq!(iprep.check("1.2.3.4").await, abusive, "{}", policy);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_report_cooldown() {
+        let (server, requests) = mock_iprepd();
+        let settings = Settings {
+            ip_reputation_server: server,
+            iprepd_report_cooldown: 60,
+            ..Default::default()
+        };
+        let iprep = IPReputation::new(&settings, &MozLogger::new_human(), &nop_metrics()).unwrap();
+
+        iprep.add_abuser("1.2.3.4").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        // suppressed
+        iprep.add_abuser("1.2.3.4").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        iprep.add_abuser("2.3.4.5").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        let iprep = IPReputation::new(
+            &Settings {
+                iprepd_report_cooldown: 0,
+                ..settings
+            },
+            &MozLogger::new_human(),
+            &nop_metrics(),
+        )
+        .unwrap();
+        iprep.add_abuser("1.2.3.4").await.unwrap();
+        iprep.add_abuser("1.2.3.4").await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        // A report that failed is tried again.
+        let iprep = IPReputation::new(
+            &Settings {
+                ip_reputation_server: unreachable_iprepd(),
+                iprepd_report_cooldown: 60,
+                ..Default::default()
+            },
+            &MozLogger::new_human(),
+            &nop_metrics(),
+        )
+        .unwrap();
+        assert!(iprep.add_abuser("1.2.3.4").await.is_err());
+        assert!(!iprep.recently_reported("1.2.3.4"));
+    }
+}
@@ -1,12 +1,15 @@
 use std::collections::HashMap;
+use std::fmt::Write as _;
 use std::fmt::{Debug, Display, Formatter, Result};
 use std::io;
+use std::sync::Mutex;
 
 use actix::prelude::{Actor, Context};
 
 use slog::slog_o;
-use slog::Drain;
+use slog::{Drain, Key, OwnedKVList, Record, KV};
 use slog_mozlog_json::MozLogJson;
+use time::format_description::well_known::Rfc3339;
 
 #[derive(Clone, Debug)]
 pub struct MozLogger {
@@ -59,6 +62,16 @@ impl MozLogger {
         Self::new()
     }
 
+    pub fn new_logfmt() -> Self {
+        let drain = slog_async::Async::new(Logfmt::new(io::stdout()).fuse())
+            .build()
+            .fuse();
+
+        Self {
+            log: slog::Logger::root(drain, slog_o!()).new(slog_o!()),
+        }
+    }
+
     pub fn new_human() -> Self {
         let decorator = slog_term::TermDecorator::new().build();
         let drain = slog_term::CompactFormat::new(decorator).build().fuse();
@@ -70,6 +83,60 @@ impl MozLogger {
     }
 }
 
+/// Drain writing one `key=value` line per record.
+pub struct Logfmt<W: io::Write> {
+    io: Mutex<W>,
+}
+
+impl<W: io::Write> Logfmt<W> {
+    pub fn new(io: W) -> Self {
+        Self { io: Mutex::new(io) }
+    }
+}
+
+/// Append a ` key=value` pair, quoting the value if needed.
+fn logfmt_pair(line: &mut String, key: &str, val: &str) {
+    if val.is_empty() || val.contains(|c: char| c == ' ' || c == '=' || c == '"' || c.is_control())
+    {
+        let _ = write!(line, " {}={:?}", key, val);
+    } else {
+        let _ = write!(line, " {}={}", key, val);
+    }
+}
+
+struct LogfmtSerializer(String);
+
+impl slog::Serializer for LogfmtSerializer {
+    fn emit_arguments(&mut self, key: Key, val: &std::fmt::Arguments) -> slog::Result {
+        logfmt_pair(&mut self.0, key, &val.to_string());
+        Ok(())
+    }
+}
+
+impl<W: io::Write> Drain for Logfmt<W> {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        let ts = time::OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let mut line = LogfmtSerializer(format!("ts={}", ts));
+        logfmt_pair(
+            &mut line.0,
+            "level",
+            &record.level().as_str().to_lowercase(),
+        );
+        logfmt_pair(&mut line.0, "msg", &record.msg().to_string());
+        record.kv().serialize(record, &mut line)?;
+        values.serialize(record, &mut line)?;
+        line.0.push('\n');
+        let mut io = self.io.lock().unwrap_or_else(|e| e.into_inner());
+        io.write_all(line.0.as_bytes())?;
+        io.flush()
+    }
+}
+
 impl Default for MozLogger {
     fn default() -> Self {
         Self::new_json()
@@ -81,6 +148,7 @@ impl Actor for MozLogger {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct LogMessage {
     pub level: ErrorLevel,
     pub msg: String,
@@ -103,3 +171,57 @@ impl Display for LogMessage {
         Ok(write!(f, "{}", msg)?)
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use slog::info;
+
+    /// Shared output for a test drain.
+    #[derive(Clone, Default)]
+    pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Buffer {
+        /// Everything written so far, clearing the buffer.
+        pub fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_logfmt() {
+        let buffer = Buffer::default();
+        let log = slog::Logger::root(
+            Logfmt::new(buffer.clone()).fuse(),
+            slog_o!("version" => "1.2"),
+        );
+        info!(log, "New connection"; "channel" => "abc", "remote_ip" => "1.2.3.4", "reason" => "");
+
+        let line = buffer.take();
+        assert!(line.starts_with("ts="));
+        assert!(line.ends_with('\n'));
+        for pair in [
+            " level=info",
+            r#" msg="New connection""#,
+            " channel=abc",
+            " remote_ip=1.2.3.4",
+            r#" reason="""#,
+            " version=1.2",
+        ] {
+            assert!(line.contains(pair), "{:?} not in {:?}", pair, line);
+        }
+    }
+}
@@ -4,19 +4,31 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use cadence::CountedExt;
-use serde_json::Value;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use slog::{error, info, warn};
 
 use actix::{Actor, Addr};
-use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Next};
+use actix_web::{http::header, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
 
+mod audit;
+mod auth_hook;
+mod channel_token;
 #[macro_use]
 mod channelid;
+mod closed;
 mod error;
+mod event_bus;
+mod geo_cache;
+mod ip_rate_limit;
 mod logging;
 mod meta;
 mod metrics;
+mod resume_token;
 mod server;
 mod session;
 mod settings;
@@ -25,10 +37,146 @@ mod settings;
    Which might explain random uses of "chat" appearing in portions of the code.
 */
 
-/// How often heartbeat pings are sent
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
-/// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Generate a new channel id that isn't reserved.
+fn new_channel(state: &session::WsChannelSessionState) -> channelid::ChannelID {
+    loop {
+        let channel = channelid::ChannelID::random(state.settings.channelid_len);
+        if !state.is_reserved(&channel.as_string()) {
+            return channel;
+        }
+    }
+}
+
+/// The longest `X-Correlation-Id` kept.
+const MAX_CORRELATION_ID_LEN: usize = 64;
+
+/// The client's own `X-Correlation-Id`, if `accept_correlation_id` is set,
+/// keeping only characters that are safe to log.
+fn client_correlation_id(req: &HttpRequest, settings: &settings::Settings) -> Option<String> {
+    if !settings.accept_correlation_id {
+        return None;
+    }
+    req.headers()
+        .get("X-Correlation-Id")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric() || "-_.:".contains(*c))
+                .take(MAX_CORRELATION_ID_LEN)
+                .collect::<String>()
+        })
+        .filter(|value| !value.is_empty())
+}
+
+/// Who is connecting, according to `integration_header`, if set.
+fn integration(req: &HttpRequest, settings: &settings::Settings) -> Option<String> {
+    if settings.integration_header.is_empty() {
+        return None;
+    }
+    req.headers()
+        .get(settings.integration_header.as_str())
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_owned())
+        .filter(|value| !value.is_empty())
+}
+
+/// The version the client says it is, from the `cv` query parameter or the
+/// `X-Client-Version` header.
+fn client_version(req: &HttpRequest) -> Option<String> {
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok();
+    query
+        .and_then(|query| query.get("cv").cloned())
+        .or_else(|| {
+            req.headers()
+                .get("x-client-version")
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        })
+        .map(|version| version.trim().to_owned())
+        .filter(|version| !version.is_empty())
+}
+
+/// The major version of the client, which must match its peers' when
+/// `match_versions` is set.
+fn protocol_version(req: &HttpRequest) -> Option<u64> {
+    client_version(req)
+        .and_then(|version| semver::Version::parse(&version).ok())
+        .map(|version| version.major)
+}
+
+/// The error to send a client older than `min_client_version`, if it is.
+fn check_client_version(req: &HttpRequest, settings: &settings::Settings) -> Option<String> {
+    if settings.min_client_version.is_empty() {
+        return None;
+    }
+    // Checked by `Settings::validate`.
+    let min = semver::Version::parse(&settings.min_client_version).ok()?;
+    let allowed = match client_version(req).and_then(|v| semver::Version::parse(&v).ok()) {
+        Some(version) => version >= min,
+        None => settings.allow_unknown_client_version,
+    };
+    if allowed {
+        return None;
+    }
+    Some(json!({"error": "client_too_old", "min": settings.min_client_version}).to_string())
+}
+
+/// The limits in a creating client's `token` query parameter, or the error to
+/// send it if the token wasn't signed with `channel_token_secret`.
+fn channel_limits(
+    req: &HttpRequest,
+    settings: &settings::Settings,
+    initial_connection: bool,
+) -> Result<channel_token::ChannelLimits, String> {
+    if settings.channel_token_secret.is_empty() || !initial_connection {
+        return Ok(Default::default());
+    }
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok();
+    match query.as_ref().and_then(|query| query.get("token")) {
+        None => Ok(Default::default()),
+        Some(token) => channel_token::ChannelLimits::verify(token, &settings.channel_token_secret)
+            .ok_or_else(|| json!({"error": "bad_token"}).to_string()),
+    }
+}
+
+/// WebSocket subprotocols the server speaks, one per version of the wire format.
+const SUBPROTOCOLS: [&str; 1] = ["fxa-pairing.v1"];
+
+/// The first of the client's `Sec-WebSocket-Protocol`s the server speaks,
+/// `None` if it didn't ask for any, or an error if it only asked for others.
+fn subprotocol(req: &HttpRequest) -> Result<Option<&'static str>, ()> {
+    let requested = match req.headers().get(header::SEC_WEBSOCKET_PROTOCOL) {
+        Some(requested) => requested.to_str().map_err(|_| ())?,
+        None => return Ok(None),
+    };
+    requested
+        .split(',')
+        .find_map(|protocol| SUBPROTOCOLS.iter().find(|known| **known == protocol.trim()))
+        .map(|known| Some(*known))
+        .ok_or(())
+}
+
+/// Whether the request would create a channel rather than join one, judging
+/// by its path alone.
+fn creates_channel(req: &HttpRequest, settings: &settings::Settings) -> bool {
+    req.match_info()
+        .get("channel")
+        .is_none_or(|id| channelid::ChannelID::parse(id, settings.channelid_len).is_err())
+}
+
+/// A joining client's `resume` query parameter, if resume tokens are enabled.
+fn resume_token(
+    req: &HttpRequest,
+    settings: &settings::Settings,
+    initial_connection: bool,
+) -> Option<String> {
+    if settings.resume_token_secret.is_empty() || initial_connection {
+        return None;
+    }
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string()).ok()?;
+    query.get("resume").cloned()
+}
 
 /// Entry point for our route
 async fn channel_route(
@@ -43,64 +191,613 @@ async fn channel_route(
             return Ok(HttpResponse::InternalServerError().body("Invalid or missing state"));
         }
     };
-    let meta = meta::SenderData::new(&req, state);
-    let mut path: Vec<&str> = req.path().split('/').collect();
+    // Held until the upgrade is done.
+    let _upgrade = match state.start_upgrade() {
+        Some(permit) => permit,
+        None => {
+            state.metrics.incr("conn.max.upgrades").ok();
+            // Skip the geo lookup, as that's part of what's being shed.
+            let meta = meta::SenderData::without_geo(&req, state);
+            state.audit.record(
+                &meta,
+                creates_channel(&req, &state.settings),
+                audit::Outcome::Rejected("upgrades"),
+            );
+            return Ok(HttpResponse::ServiceUnavailable().body("Too many connections"));
+        }
+    };
+    let meta = sender_data(&req, state).await;
+    if state.settings.strict_ws_version
+        && req
+            .headers()
+            .get(header::SEC_WEBSOCKET_VERSION)
+            .is_none_or(|version| version != "13")
+    {
+        state.metrics.incr("conn.bad_ws_version").ok();
+        state.audit.record(
+            &meta,
+            creates_channel(&req, &state.settings),
+            audit::Outcome::Rejected("bad_ws_version"),
+        );
+        return Ok(HttpResponse::UpgradeRequired()
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .finish());
+    }
+    let subprotocol = match subprotocol(&req) {
+        Ok(subprotocol) => subprotocol,
+        Err(_) => {
+            state.metrics.incr("conn.bad_subprotocol").ok();
+            state.audit.record(
+                &meta,
+                creates_channel(&req, &state.settings),
+                audit::Outcome::Rejected("bad_subprotocol"),
+            );
+            return Ok(HttpResponse::BadRequest().body("Unsupported subprotocol"));
+        }
+    };
     let log = logging::MozLogger::default();
     let metrics = state.metrics.clone();
     let mut initial_connection: bool = true;
-    let channel = match path.pop() {
-        Some(id) => {
-            if id.is_empty() {
+    let channel = match req.match_info().get("channel") {
+        Some(id) if state.is_reserved(id) => {
+            warn!(state.log.log, "Attempt to use reserved channel"; "channel" => id);
+            metrics.incr("conn.reserved").ok();
+            state.audit.record(
+                &meta,
+                creates_channel(&req, &state.settings),
+                audit::Outcome::Rejected("reserved"),
+            );
+            return Ok(HttpResponse::BadRequest().body("Reserved channel"));
+        }
+        Some(id) => match channelid::ChannelID::parse(id, state.settings.channelid_len) {
+            Ok(channelid) => {
+                initial_connection = false;
+                metrics
+                    .incr_with_tags("conn.request")
+                    .with_tag_value("existing")
+                    .send();
+                channelid
+            }
+            Err(err) if state.settings.require_preallocated => {
+                warn!(state.log.log, "Routing error: {:?}", err);
                 metrics
                     .incr_with_tags("conn.request")
-                    .with_tag_value("new")
+                    .with_tag_value("error")
                     .send();
-                channelid::ChannelID::default()
-            } else {
-                match channelid::ChannelID::from_str(id) {
-                    Ok(channelid) => {
-                        initial_connection = false;
-                        metrics
-                            .incr_with_tags("conn.request")
-                            .with_tag_value("existing")
-                            .send();
-                        channelid
-                    }
-                    Err(err) => {
-                        warn!(state.log.log, "Routing error: {:?}", err);
-                        metrics
-                            .incr_with_tags("conn.request")
-                            .with_tag_value("error")
-                            .send();
-                        channelid::ChannelID::default()
-                    }
-                }
+                state
+                    .audit
+                    .record(&meta, false, audit::Outcome::Rejected("invalid_channel"));
+                return Ok(HttpResponse::BadRequest().body("Invalid channel"));
             }
+            Err(err) => {
+                warn!(state.log.log, "Routing error: {:?}", err);
+                metrics
+                    .incr_with_tags("conn.request")
+                    .with_tag_value("error")
+                    .send();
+                new_channel(state)
+            }
+        },
+        None if state.settings.require_preallocated => {
+            warn!(state.log.log, "Channel was not preallocated");
+            metrics.incr("conn.not_preallocated").ok();
+            state
+                .audit
+                .record(&meta, true, audit::Outcome::Rejected("not_preallocated"));
+            return Ok(HttpResponse::Forbidden().body("Channel must be preallocated"));
         }
-
+        // "/v1/ws/"
+        None if req.path().ends_with('/') => {
+            metrics
+                .incr_with_tags("conn.request")
+                .with_tag_value("new")
+                .send();
+            new_channel(state)
+        }
+        // "/v1/ws"
         None => {
+            if state.settings.require_ws_trailing_slash {
+                metrics.incr("conn.no_trailing_slash").ok();
+                state
+                    .audit
+                    .record(&meta, true, audit::Outcome::Rejected("no_trailing_slash"));
+                return Ok(HttpResponse::NotFound().finish());
+            }
             metrics
                 .incr_with_tags("conn.request")
                 .with_tag_value("none")
                 .send();
-            channelid::ChannelID::default()
+            new_channel(state)
+        }
+    };
+    // Existing channels may still be joined, so pairings in progress can finish.
+    if initial_connection && state.in_maintenance() {
+        state.metrics.incr("conn.maintenance").ok();
+        state
+            .audit
+            .record(&meta, true, audit::Outcome::Rejected("maintenance"));
+        return Ok(HttpResponse::ServiceUnavailable().json(json!({"error": "maintenance"})));
+    }
+    if let Some(denied) = screen(state, &meta, initial_connection).await {
+        return Ok(denied);
+    }
+    let audit_meta = meta.clone();
+    let integration = integration(&req, &state.settings);
+    let mut rejection = check_client_version(&req, &state.settings);
+    if rejection.is_some() {
+        state.metrics.incr("conn.old_client").ok();
+        state.audit.record(
+            &meta,
+            initial_connection,
+            audit::Outcome::Rejected("client_too_old"),
+        );
+    }
+    let limits = match channel_limits(&req, &state.settings, initial_connection) {
+        Ok(limits) => limits,
+        Err(err) => {
+            warn!(state.log.log, "Bad channel token"; "remote_ip" => &meta.remote);
+            state.metrics.incr("conn.bad_token").ok();
+            // A refused connection is audited once, for the first reason.
+            if rejection.is_none() {
+                state.audit.record(
+                    &meta,
+                    initial_connection,
+                    audit::Outcome::Rejected("bad_token"),
+                );
+                rejection = Some(err);
+            }
+            Default::default()
         }
     };
-    ws::start(
+    let secure = meta::is_secure(&req.peer_addr(), req.headers(), &state.trusted_proxy_list);
+    let result = ws::WsResponseBuilder::new(
         session::WsChannelSession {
             id: 0,
             hb: Instant::now(),
+            started: Instant::now(),
+            rtt: session::Rtt::default(),
             expiry: Duration::from_secs(state.settings.conn_lifespan),
+            heartbeat: Duration::from_secs(state.settings.heartbeat),
+            client_timeout: Duration::from_secs(state.settings.client_timeout),
             channel,
             addr: srv.get_ref().clone(),
             initial_connection,
             meta,
+            secure,
+            integration,
+            rejection,
+            limits,
+            protocol: protocol_version(&req),
+            subprotocol,
+            buffered: server::Buffered::default(),
+            client_correlation_id: client_correlation_id(&req, &state.settings),
+            resume: resume_token(&req, &state.settings, initial_connection),
+            pending: Vec::new(),
+            seq: 0,
             log,
             metrics,
+            backlog: state.backlog.clone(),
+            settings: state.settings.clone(),
+            audit: state.audit.clone(),
         },
         &req,
         stream,
     )
+    .protocols(&SUBPROTOCOLS)
+    .start();
+    // Accepted upgrades are audited once the server answers the connect.
+    if result.is_err() {
+        state.audit.record(
+            &audit_meta,
+            initial_connection,
+            audit::Outcome::Rejected("handshake"),
+        );
+    }
+    result
+}
+
+/// Who's connecting, looking up where from unless too many lookups are
+/// already in progress.
+async fn sender_data(
+    req: &HttpRequest,
+    state: &session::WsChannelSessionState,
+) -> meta::SenderData {
+    match state.geo_limiter.acquire().await {
+        Some(_permit) => meta::SenderData::new(req, state),
+        None => {
+            state.metrics.incr("geo.skipped").ok();
+            meta::SenderData::without_geo(req, state)
+        }
+    }
+}
+
+/// Refuse addresses with a bad iprepd reputation, and any the auth hook
+/// denies, returning the response to refuse them with.
+async fn screen(
+    state: &session::WsChannelSessionState,
+    meta: &meta::SenderData,
+    initial_connection: bool,
+) -> Option<HttpResponse> {
+    if let Some(iprep) = &state.iprep {
+        let remote = meta.remote.as_deref().and_then(meta::strip_port);
+        if let Some(remote) = remote {
+            if iprep.check(&remote.to_string()).await {
+                info!(state.log.log, "Refusing abusive address"; "remote_ip" => &meta.remote);
+                state.metrics.incr("conn.reject.reputation").ok();
+                state.audit.record(
+                    meta,
+                    initial_connection,
+                    audit::Outcome::Rejected("reputation"),
+                );
+                return Some(HttpResponse::Forbidden().json(json!({"error": "denied"})));
+            }
+        }
+    }
+    if let Some(hook) = &state.auth_hook {
+        if !hook.allows(meta, initial_connection).await {
+            state.metrics.incr("conn.authhook.deny").ok();
+            state.audit.record(
+                meta,
+                initial_connection,
+                audit::Outcome::Rejected("auth_hook"),
+            );
+            return Some(HttpResponse::Forbidden().json(json!({"error": "denied"})));
+        }
+    }
+    None
+}
+
+/// Serve `static_dir`, if set, so a demo client can be loaded from a browser.
+fn static_files(cfg: &mut web::ServiceConfig, settings: &settings::Settings) {
+    if !settings.static_dir.is_empty() {
+        cfg.service(
+            actix_files::Files::new(&settings.static_path, &settings.static_dir)
+                .index_file("index.html"),
+        );
+    }
+}
+
+/// Serve channel preallocation, if `require_preallocated` is set.
+fn preallocate_services(cfg: &mut web::ServiceConfig, settings: &settings::Settings) {
+    if settings.require_preallocated {
+        cfg.service(
+            web::resource(["/v1/channels", "/v1/channel"]).route(web::post().to(preallocate_route)),
+        );
+    }
+}
+
+/// Allocate a channel for clients to connect to later.
+async fn preallocate_route(
+    req: HttpRequest,
+    srv: web::Data<Addr<server::ChannelServer>>,
+) -> Result<HttpResponse, Error> {
+    let state = match req.app_data::<web::Data<session::WsChannelSessionState>>() {
+        Some(state) => state,
+        None => {
+            return Ok(HttpResponse::InternalServerError().body("Invalid or missing state"));
+        }
+    };
+    // A preallocated channel is a new one, so it's refused as `channel_route`
+    // refuses them.
+    let _upgrade = match state.start_upgrade() {
+        Some(permit) => permit,
+        None => {
+            state.metrics.incr("conn.max.upgrades").ok();
+            let meta = meta::SenderData::without_geo(&req, state);
+            state
+                .audit
+                .record(&meta, true, audit::Outcome::Rejected("upgrades"));
+            return Ok(HttpResponse::ServiceUnavailable().body("Too many connections"));
+        }
+    };
+    let meta = sender_data(&req, state).await;
+    if state.in_maintenance() {
+        state.metrics.incr("conn.maintenance").ok();
+        state
+            .audit
+            .record(&meta, true, audit::Outcome::Rejected("maintenance"));
+        return Ok(HttpResponse::ServiceUnavailable().json(json!({"error": "maintenance"})));
+    }
+    if let Some(denied) = screen(state, &meta, true).await {
+        return Ok(denied);
+    }
+    loop {
+        let result = srv
+            .send(server::Preallocate {
+                channel: new_channel(state),
+                remote: meta.remote.clone(),
+            })
+            .await
+            .map_err(actix_web::error::ErrorServiceUnavailable)?;
+        match result {
+            server::PreallocateResult::Ok(welcome) => {
+                return Ok(HttpResponse::Ok()
+                    .content_type("application/json")
+                    .body(welcome));
+            }
+            server::PreallocateResult::Taken => {}
+            server::PreallocateResult::AtCapacity => {
+                state
+                    .audit
+                    .record(&meta, true, audit::Outcome::Rejected("at_capacity"));
+                return Ok(HttpResponse::ServiceUnavailable().body("Too many connections"));
+            }
+        }
+    }
+}
+
+/// Whether a channel exists and could be joined, so a client can check a
+/// channel id before connecting to it.
+async fn channel_status_route(
+    req: HttpRequest,
+    srv: web::Data<Addr<server::ChannelServer>>,
+) -> Result<HttpResponse, Error> {
+    let state = match req.app_data::<web::Data<session::WsChannelSessionState>>() {
+        Some(state) => state,
+        None => {
+            return Ok(HttpResponse::InternalServerError().body("Invalid or missing state"));
+        }
+    };
+    let id = req.match_info().get("channel").unwrap_or_default();
+    let channel = match channelid::ChannelID::parse(id, state.settings.channelid_len) {
+        Ok(channel) => channel,
+        Err(_) => return Ok(HttpResponse::BadRequest().body("Invalid channel")),
+    };
+    let status = srv
+        .send(server::ChannelStatus(channel))
+        .await
+        .map_err(actix_web::error::ErrorServiceUnavailable)?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+#[derive(Debug, Deserialize)]
+struct ChannelsQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// Does the request carry `admin_token` as its bearer token?
+fn is_admin(req: &HttpRequest, settings: &settings::Settings) -> bool {
+    if settings.admin_token.is_empty() {
+        return false;
+    }
+    let token = match req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(token) => token.trim().as_bytes(),
+        None => return false,
+    };
+    let expected = settings.admin_token.as_bytes();
+    // Compare in constant time, so the token can't be guessed a byte at a time.
+    token.len() == expected.len()
+        && token
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The response for a request to an admin endpoint that isn't allowed, if it
+/// isn't.
+fn admin_refusal(
+    req: &HttpRequest,
+    state: &session::WsChannelSessionState,
+) -> Option<HttpResponse> {
+    // Don't reveal admin endpoints exist unless they're enabled.
+    if state.settings.admin_token.is_empty() {
+        return Some(HttpResponse::NotFound().finish());
+    }
+    if !is_admin(req, &state.settings) {
+        state.metrics.incr("admin.unauthorized").ok();
+        return Some(
+            HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .finish(),
+        );
+    }
+    None
+}
+
+/// Refuse admin requests over `admin_rate_limit` a minute with a 429, so the
+/// admin endpoints can't be scraped hard enough to affect relaying. Only
+/// requests carrying `admin_token` count, so others can't use up the
+/// operators' budget; the handlers refuse those anyway.
+async fn admin_rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if let Some(state) = req.app_data::<web::Data<session::WsChannelSessionState>>() {
+        if is_admin(req.request(), &state.settings) && !state.admit_admin() {
+            state.metrics.incr("admin.rate_limited").ok();
+            let resp = HttpResponse::TooManyRequests().finish();
+            return Ok(req.into_response(resp).map_into_right_body());
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// The admin endpoints, limited by `admin_rate_limit`.
+fn admin_services(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/__channels__")
+            .wrap(from_fn(admin_rate_limit))
+            .route(web::get().to(channels_route)),
+    )
+    .service(
+        web::scope("/v1/admin")
+            .wrap(from_fn(admin_rate_limit))
+            .service(web::resource("/proxies").route(web::get().to(proxies_route)))
+            .service(
+                web::resource("/maintenance")
+                    .route(web::get().to(maintenance_route))
+                    .route(web::post().to(maintenance_route)),
+            )
+            .service(web::resource("/channels/{channel}/drain").route(web::post().to(drain_route))),
+    );
+}
+
+#[derive(Debug, Deserialize)]
+struct DrainQuery {
+    #[serde(default)]
+    grace: u64,
+}
+
+/// Gracefully close a channel, refusing new joins for `?grace=` seconds first.
+async fn drain_route(
+    req: HttpRequest,
+    query: web::Query<DrainQuery>,
+    srv: web::Data<Addr<server::ChannelServer>>,
+) -> Result<HttpResponse, Error> {
+    let state = match req.app_data::<web::Data<session::WsChannelSessionState>>() {
+        Some(state) => state,
+        None => {
+            return Ok(HttpResponse::InternalServerError().body("Invalid or missing state"));
+        }
+    };
+    if let Some(refused) = admin_refusal(&req, state) {
+        return Ok(refused);
+    }
+    let id = req.match_info().get("channel").unwrap_or_default();
+    let channel = match channelid::ChannelID::parse(id, state.settings.channelid_len) {
+        Ok(channel) => channel,
+        Err(_) => return Ok(HttpResponse::BadRequest().body("Invalid channel")),
+    };
+    let drained = srv
+        .send(server::DrainChannel(
+            channel,
+            Duration::from_secs(query.grace),
+        ))
+        .await
+        .map_err(actix_web::error::ErrorServiceUnavailable)?;
+    if !drained {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    Ok(HttpResponse::Ok().json(json!({ "drained": true })))
+}
+
+#[derive(Debug, Deserialize)]
+struct MaintenanceQuery {
+    enabled: Option<bool>,
+}
+
+/// Show, or with `?enabled=`, set whether the server is refusing new channels.
+async fn maintenance_route(req: HttpRequest, query: web::Query<MaintenanceQuery>) -> HttpResponse {
+    let state = match req.app_data::<web::Data<session::WsChannelSessionState>>() {
+        Some(state) => state,
+        None => {
+            return HttpResponse::InternalServerError().body("Invalid or missing state");
+        }
+    };
+    if let Some(refused) = admin_refusal(&req, state) {
+        return refused;
+    }
+    if let Some(enabled) = query.enabled {
+        info!(state.log.log, "Maintenance mode set to {}", enabled);
+        state.set_maintenance(enabled);
+    }
+    HttpResponse::Ok().json(json!({ "maintenance": state.in_maintenance() }))
+}
+
+/// Wait for a signal to shut down, returning whether it asks for a graceful
+/// stop, as SIGTERM does.
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<bool> {
+    use futures::future::{select, Either};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut quit = signal(SignalKind::quit())?;
+    let stop = select(Box::pin(interrupt.recv()), Box::pin(quit.recv()));
+    let signal = select(Box::pin(terminate.recv()), stop).await;
+    Ok(matches!(signal, Either::Left(_)))
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<bool> {
+    tokio::signal::ctrl_c().await?;
+    Ok(false)
+}
+
+/// Call `handler` each time the process is sent the signal.
+#[cfg(unix)]
+fn on_signal(
+    kind: tokio::signal::unix::SignalKind,
+    state: web::Data<session::WsChannelSessionState>,
+    handler: fn(&session::WsChannelSessionState),
+) {
+    let mut signals = match tokio::signal::unix::signal(kind) {
+        Ok(signals) => signals,
+        Err(err) => {
+            warn!(
+                state.log.log,
+                "Could not listen for signal {:?}: {:?}", kind, err
+            );
+            return;
+        }
+    };
+    actix_rt::spawn(async move {
+        while signals.recv().await.is_some() {
+            handler(&state);
+        }
+    });
+}
+
+#[cfg(unix)]
+fn toggle_maintenance(state: &session::WsChannelSessionState) {
+    let maintenance = !state.in_maintenance();
+    info!(state.log.log, "Maintenance mode set to {}", maintenance);
+    state.set_maintenance(maintenance);
+}
+
+/// Show operators which addresses are trusted as proxies.
+async fn proxies_route(req: HttpRequest) -> HttpResponse {
+    let state = match req.app_data::<web::Data<session::WsChannelSessionState>>() {
+        Some(state) => state,
+        None => {
+            return HttpResponse::InternalServerError().body("Invalid or missing state");
+        }
+    };
+    if let Some(refused) = admin_refusal(&req, state) {
+        return refused;
+    }
+    let proxies: Vec<String> = state
+        .trusted_proxy_list
+        .iter()
+        .map(|net| net.to_string())
+        .collect();
+    HttpResponse::Ok().json(json!({ "trusted_proxies": proxies }))
+}
+
+/// List open channels for operators.
+async fn channels_route(
+    req: HttpRequest,
+    query: web::Query<ChannelsQuery>,
+    srv: web::Data<Addr<server::ChannelServer>>,
+) -> Result<HttpResponse, Error> {
+    let state = match req.app_data::<web::Data<session::WsChannelSessionState>>() {
+        Some(state) => state,
+        None => {
+            return Ok(HttpResponse::InternalServerError().body("Invalid or missing state"));
+        }
+    };
+    if let Some(refused) = admin_refusal(&req, state) {
+        return Ok(refused);
+    }
+    let channels = srv
+        .send(server::ListChannels {
+            offset: query.offset,
+            limit: query.limit,
+        })
+        .await
+        .map_err(actix_web::error::ErrorServiceUnavailable)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "channels": channels })))
 }
 
 pub async fn heartbeat(_req: HttpRequest) -> HttpResponse {
@@ -116,9 +813,41 @@ pub async fn heartbeat(_req: HttpRequest) -> HttpResponse {
         .json(checklist)
 }
 
-pub async fn lbheartbeat(_req: HttpRequest) -> HttpResponse {
+pub async fn lbheartbeat(req: HttpRequest) -> HttpResponse {
     // load balance heartbeat. Doesn't matter what's returned, aside from a 200
-    HttpResponse::Ok().finish()
+    // when we're ready for connections.
+    match req.app_data::<web::Data<server::Readiness>>() {
+        Some(readiness) if readiness.is_ready() => {}
+        _ => return HttpResponse::ServiceUnavailable().finish(),
+    }
+    let state = req.app_data::<web::Data<session::WsChannelSessionState>>();
+    let srv = req.app_data::<web::Data<Addr<server::ChannelServer>>>();
+    match (state, srv) {
+        (Some(state), Some(srv)) if state.settings.report_load => {
+            match srv.send(server::GetLoad).await {
+                Ok(load) => HttpResponse::Ok().json(load_report(state, load)),
+                Err(_) => HttpResponse::Ok().finish(),
+            }
+        }
+        _ => HttpResponse::Ok().finish(),
+    }
+}
+
+/// What `report_load` tells load balancers. `load` is the fraction of
+/// `max_sessions` in use, or null if sessions aren't limited.
+fn load_report(state: &session::WsChannelSessionState, load: server::Load) -> Value {
+    let max_sessions = state.settings.max_sessions;
+    json!({
+        "load": if max_sessions > 0 {
+            Some(load.sessions as f64 / max_sessions as f64)
+        } else {
+            None
+        },
+        "sessions": load.sessions,
+        "max_sessions": max_sessions,
+        "channels": load.channels,
+        "upgrades": state.upgrades_in_progress(),
+    })
 }
 
 pub async fn show_version(_req: HttpRequest) -> HttpResponse {
@@ -128,6 +857,19 @@ pub async fn show_version(_req: HttpRequest) -> HttpResponse {
         .body(include_str!("../version.json"))
 }
 
+/// Metrics for Prometheus to scrape, served on `prometheus_port`.
+pub async fn prometheus_route(req: HttpRequest) -> HttpResponse {
+    let registry = req
+        .app_data::<web::Data<session::WsChannelSessionState>>()
+        .and_then(|state| state.prometheus.as_ref());
+    match registry {
+        Some(registry) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(registry.render()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
 pub struct Server;
 
 #[actix_rt::main]
@@ -135,7 +877,7 @@ async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     let raw_settings = settings::Settings::new();
-    let settings = match raw_settings {
+    let mut settings = match raw_settings {
         Ok(settings) => settings,
         Err(e) => {
             return Err(std::io::Error::new(
@@ -146,15 +888,35 @@ async fn main() -> std::io::Result<()> {
     };
 
     let addr = format!("{}:{}", settings.hostname, settings.port);
-    let log = if settings.human_logs {
-        logging::MozLogger::new_human()
-    } else {
-        logging::MozLogger::new_json()
+    let log = match settings.log_format.as_str() {
+        _ if settings.human_logs => logging::MozLogger::new_human(),
+        "human" => logging::MozLogger::new_human(),
+        "logfmt" => logging::MozLogger::new_logfmt(),
+        _ => logging::MozLogger::new_json(),
     };
+    if settings.memory_budget_mb > 0 {
+        settings.apply_memory_budget();
+        info!(
+            &log.log,
+            "Limits for memory budget";
+            "memory_budget_mb" => settings.memory_budget_mb,
+            "max_channels" => settings.max_channels,
+            "max_sessions" => settings.max_sessions,
+        );
+    }
 
-    let metrics =
-        Arc::new(metrics::metrics_from_opts(&settings, &log).expect("Could not create metrics"));
-    let server = server::ChannelServer::new(&settings, &log, metrics.clone()).start();
+    let prometheus = (settings.prometheus_port > 0).then(metrics::PrometheusRegistry::default);
+    let metrics = Arc::new(
+        metrics::metrics_from_opts(&settings, &log, prometheus.as_ref())
+            .expect("Could not create metrics"),
+    );
+    let channel_server = server::ChannelServer::new(&settings, &log, metrics.clone());
+    let backlog = channel_server.backlog.clone();
+    let iprep = channel_server.iprep.clone();
+    let readiness = channel_server.readiness.clone();
+    let readiness_data = web::Data::new(readiness.clone());
+    let server = channel_server.start();
+    let drain = server.clone();
 
     if !Path::new(&settings.mmdb_loc).exists() {
         error!(
@@ -166,23 +928,969 @@ async fn main() -> std::io::Result<()> {
             "missing geoip database".to_owned(),
         ));
     };
+    if settings.log_features {
+        info!(&log.log, "Enabled features: {}", settings.feature_summary());
+    }
     // Create Http server with websocket support
     info!(&log.log, "Starting server: {:?}", &addr);
-    HttpServer::new(move || {
-        let state = session::WsChannelSessionState::new(&settings, &log, &metrics);
+    // Shared by all workers.
+    let mut state = session::WsChannelSessionState::new(&settings, &log, &metrics, &backlog);
+    state.prometheus = prometheus;
+    state.iprep = iprep;
+    let state = web::Data::new(state);
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::SignalKind;
+
+        on_signal(
+            SignalKind::user_defined1(),
+            state.clone(),
+            toggle_maintenance,
+        );
+        on_signal(SignalKind::hangup(), state.clone(), |state| {
+            state.reload_iploc();
+            state.reload_asnloc();
+        });
+    }
+    let prometheus_state = state.clone();
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(server.clone()))
-            .app_data(web::Data::new(state))
+            .app_data(state.clone())
+            .app_data(readiness_data.clone())
             .service(web::resource("/").route(web::get().to(HttpResponse::NotFound)))
             // websocket
             .service(web::resource("/v1/ws/{channel}").to(channel_route))
             .service(web::resource("/v1/ws/").route(web::get().to(channel_route)))
+            .service(web::resource("/v1/ws").route(web::get().to(channel_route)))
+            .configure(|cfg| preallocate_services(cfg, &state.settings))
+            .service(
+                web::resource("/v1/channel/{channel}/status")
+                    .route(web::get().to(channel_status_route)),
+            )
             // static resources
             .service(web::resource("/__heartbeat__").route(web::get().to(heartbeat)))
             .service(web::resource("/__lbheartbeat__").route(web::get().to(lbheartbeat)))
             .service(web::resource("/__version__").route(web::get().to(show_version)))
+            .configure(admin_services)
+            .configure(|cfg| static_files(cfg, &state.settings))
     })
+    .disable_signals()
     .bind(addr)?
-    .run()
-    .await
+    .run();
+    let handle = server.handle();
+    let stopping = readiness.clone();
+    actix_rt::spawn(async move {
+        let graceful = match shutdown_signal().await {
+            Ok(graceful) => graceful,
+            Err(err) => {
+                error!(&log.log, "Could not listen for shutdown signals: {:?}", err);
+                return;
+            }
+        };
+        // Turn load balancers away before the workers stop taking connections.
+        stopping.set(false);
+        // Close the channels while their sessions are still connected, so the
+        // final counts reflect what was terminated.
+        drain.send(server::DrainAll(Duration::ZERO)).await.ok();
+        handle.stop(graceful).await;
+    });
+    if settings.prometheus_port > 0 {
+        // Kept off the public port, for the scraper alone.
+        let prometheus = HttpServer::new(move || {
+            App::new()
+                .app_data(prometheus_state.clone())
+                .service(web::resource("/metrics").route(web::get().to(prometheus_route)))
+        })
+        .workers(1)
+        .bind((settings.hostname.as_str(), settings.prometheus_port))?
+        .run();
+        actix_rt::spawn(prometheus);
+    }
+    readiness.set(true);
+    server.await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use actix_web::{http::StatusCode, test};
+
+    fn test_state(settings: &settings::Settings) -> session::WsChannelSessionState {
+        let log = logging::MozLogger::new_human();
+        let metrics = Arc::new(metrics::metrics_from_opts(settings, &log, None).unwrap());
+        session::WsChannelSessionState::new(settings, &log, &metrics, &server::Backlog::default())
+    }
+
+    /// A websocket upgrade request for the given path.
+    fn ws_request(path: &str) -> test::TestRequest {
+        test::TestRequest::get()
+            .uri(path)
+            .insert_header(("upgrade", "websocket"))
+            .insert_header(("connection", "upgrade"))
+            .insert_header(("sec-websocket-version", "13"))
+            .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .peer_addr("1.2.3.4:5678".parse().unwrap())
+    }
+
+    macro_rules! ws_app {
+        ($settings:expr) => {{
+            let settings = $settings;
+            let state = test_state(&settings);
+            ws_app!(settings, state)
+        }};
+        ($settings:expr, $state:expr) => {{
+            let settings = $settings;
+            let mut state = $state;
+            let log = state.log.clone();
+            let channel_server = server::ChannelServer::new(&settings, &log, state.metrics.clone());
+            state.iprep = channel_server.iprep.clone();
+            let readiness = channel_server.readiness.clone();
+            readiness.set(true);
+            let server = channel_server.start();
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(server))
+                    .app_data(web::Data::new(state))
+                    .app_data(web::Data::new(readiness))
+                    .service(web::resource("/__lbheartbeat__").route(web::get().to(lbheartbeat)))
+                    .service(web::resource("/v1/ws/{channel}").to(channel_route))
+                    .service(web::resource("/v1/ws/").route(web::get().to(channel_route)))
+                    .service(web::resource("/v1/ws").route(web::get().to(channel_route)))
+                    .configure(|cfg| preallocate_services(cfg, &settings))
+                    .service(
+                        web::resource("/v1/channel/{channel}/status")
+                            .route(web::get().to(channel_status_route)),
+                    )
+                    .configure(admin_services),
+            )
+            .await
+        }};
+    }
+
+    #[actix_rt::test]
+    async fn test_reserved_channel() {
+        let settings = settings::Settings {
+            reserved_channels: "j6jLPVPeQR6diyrkQinRAQ, adm*".to_owned(),
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let app = ws_app!(settings);
+
+        for reserved in [
+            "/v1/ws/j6jLPVPeQR6diyrkQinRAQ",
+            "/v1/ws/j6jLPVPeQR6diyrkQinRAQ==",
+            "/v1/ws/admAAAAAAAAAAAAAAAAAAA",
+        ] {
+            let resp = test::call_service(&app, ws_request(reserved).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", reserved);
+        }
+        let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let resp = test::call_service(
+            &app,
+            ws_request("/v1/ws/IZ5B8Wj2qR1NlsNbSXQ2Fg").to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[actix_rt::test]
+    async fn test_inflight_upgrades() {
+        let settings = settings::Settings {
+            max_inflight_upgrades: 1,
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let mut state = test_state(&settings);
+        let (audit, records) = audit::test::capture();
+        state.audit = audit;
+        let upgrades = state.upgrades.clone().unwrap();
+        let app = ws_app!(settings, state);
+
+        // Another upgrade is in progress.
+        let permit = upgrades.try_acquire().unwrap();
+        let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        {
+            let records = records.lock().unwrap();
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0]["reason"], "upgrades");
+        }
+
+        drop(permit);
+        let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+        // and the slot is given back once the upgrade is done
+        assert_eq!(upgrades.available_permits(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_report_load() {
+        let settings = settings::Settings {
+            report_load: true,
+            max_sessions: 4,
+            max_inflight_upgrades: 2,
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let state = test_state(&settings);
+        let upgrades = state.upgrades.clone().unwrap();
+        let app = ws_app!(settings.clone(), state);
+        let load = || async {
+            let req = test::TestRequest::get().uri("/__lbheartbeat__");
+            let resp = test::call_service(&app, req.to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            test::read_body_json::<Value, _>(resp).await
+        };
+        assert_eq!(
+            load().await,
+            json!({
+                "load": 0.0,
+                "sessions": 0,
+                "max_sessions": 4,
+                "channels": 0,
+                "upgrades": 0
+            })
+        );
+
+        let _permit = upgrades.try_acquire().unwrap();
+        assert_eq!(load().await["upgrades"], 1);
+
+        let state = test_state(&settings);
+        let report = load_report(
+            &state,
+            server::Load {
+                sessions: 3,
+                channels: 2,
+            },
+        );
+        assert_eq!(report["load"], 0.75);
+        assert_eq!(report["sessions"], 3);
+        assert_eq!(report["channels"], 2);
+
+        // off by default
+        let app = ws_app!(settings::Settings {
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        });
+        let req = test::TestRequest::get().uri("/__lbheartbeat__");
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(test::read_body(resp).await.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_maintenance() {
+        let settings = settings::Settings {
+            admin_token: "s3cret".to_owned(),
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let app = ws_app!(settings);
+        let toggle = |query: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/v1/admin/maintenance{}", query))
+                .insert_header((header::AUTHORIZATION, "Bearer s3cret"))
+                .to_request()
+        };
+        let existing = format!("/v1/ws/{}", channelid::ChannelID::default().as_string());
+
+        let resp = test::call_service(&app, toggle("?enabled=true")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["maintenance"], true);
+        // new channels are refused
+        let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "maintenance");
+        // but existing ones may still be joined
+        let resp = test::call_service(&app, ws_request(&existing).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+        let resp = test::call_service(&app, toggle("?enabled=false")).await;
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["maintenance"], false);
+        let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+        // only for admins
+        let req = test::TestRequest::post().uri("/v1/admin/maintenance?enabled=true");
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_drain_route() {
+        let settings = settings::Settings {
+            admin_token: "s3cret".to_owned(),
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let app = ws_app!(settings);
+        let drain = |channel: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/v1/admin/channels/{}/drain?grace=60", channel))
+                .insert_header((header::AUTHORIZATION, "Bearer s3cret"))
+                .to_request()
+        };
+        let channel = channelid::ChannelID::default().as_string();
+
+        let resp = test::call_service(&app, drain(&channel)).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = test::call_service(&app, drain("nonsense")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // only for admins
+        let req = test::TestRequest::post().uri(&format!("/v1/admin/channels/{}/drain", channel));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_channels() {
+        let settings = settings::Settings {
+            admin_token: "s3cret".to_owned(),
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let app = ws_app!(settings);
+        let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+        let list = |auth: Option<&str>| {
+            let req = test::TestRequest::get().uri("/__channels__?limit=10");
+            match auth {
+                Some(auth) => req.insert_header((header::AUTHORIZATION, auth)),
+                None => req,
+            }
+            .to_request()
+        };
+        let resp = test::call_service(&app, list(None)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, list(Some("Bearer s3cre"))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, list(Some("s3cret"))).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = test::call_service(&app, list(Some("Bearer s3cret"))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        let channels = body["channels"].as_array().unwrap();
+        // The upgrade's session may not have joined yet.
+        for channel in channels {
+            assert!(channel["channelid"].is_string());
+            assert!(channel["participants"].is_u64());
+            assert!(channel["age"].is_u64());
+            assert!(channel["bytes"].is_u64());
+        }
+
+        // Disabled without a token.
+        let app = ws_app!(settings::Settings {
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        });
+        let resp = test::call_service(&app, list(Some("Bearer "))).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_client_correlation_id() {
+        let settings = settings::Settings {
+            accept_correlation_id: true,
+            ..Default::default()
+        };
+        let check = |settings: &settings::Settings, id: &str| {
+            let req = test::TestRequest::get()
+                .insert_header(("X-Correlation-Id", id))
+                .to_http_request();
+            client_correlation_id(&req, settings)
+        };
+        assert_eq!(check(&settings, "abc-123"), Some("abc-123".to_owned()));
+        assert_eq!(
+            check(&settings, "abc 123\"=\\x"),
+            Some("abc123x".to_owned())
+        );
+        assert_eq!(check(&settings, &"a".repeat(100)), Some("a".repeat(64)));
+        assert_eq!(check(&settings, "  "), None);
+        assert_eq!(check(&settings::Settings::default(), "abc-123"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_client_version() {
+        let settings = settings::Settings {
+            min_client_version: "2.1.0".to_owned(),
+            ..Default::default()
+        };
+        let check = |settings: &settings::Settings, req: test::TestRequest| {
+            check_client_version(&req.to_http_request(), settings)
+        };
+        let too_old = check(&settings, test::TestRequest::get().uri("/v1/ws/?cv=2.0.9")).unwrap();
+        let too_old: Value = serde_json::from_str(&too_old).unwrap();
+        assert_eq!(too_old["error"], "client_too_old");
+        assert_eq!(too_old["min"], "2.1.0");
+        let req = test::TestRequest::get()
+            .uri("/v1/ws/")
+            .insert_header(("x-client-version", "1.9.0"));
+        assert!(check(&settings, req).is_some());
+
+        // new enough
+        assert!(check(&settings, test::TestRequest::get().uri("/v1/ws/?cv=2.1.0")).is_none());
+        assert!(check(&settings, test::TestRequest::get().uri("/v1/ws/?cv=10.0.0")).is_none());
+        let req = test::TestRequest::get()
+            .uri("/v1/ws/")
+            .insert_header(("x-client-version", "3.0.0-beta.1"));
+        assert!(check(&settings, req).is_none());
+
+        // missing, or not a version
+        assert!(check(&settings, test::TestRequest::get().uri("/v1/ws/")).is_none());
+        assert!(check(&settings, test::TestRequest::get().uri("/v1/ws/?cv=old")).is_none());
+        let strict = settings::Settings {
+            allow_unknown_client_version: false,
+            ..settings
+        };
+        assert!(check(&strict, test::TestRequest::get().uri("/v1/ws/")).is_some());
+        assert!(check(&strict, test::TestRequest::get().uri("/v1/ws/?cv=old")).is_some());
+
+        // not enforced by default
+        let req = test::TestRequest::get().uri("/v1/ws/?cv=0.0.1");
+        assert!(check(&settings::Settings::default(), req).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_channel_limits() {
+        let settings = settings::Settings {
+            channel_token_secret: "s3cret".to_owned(),
+            max_channel_connections: 3,
+            ..Default::default()
+        };
+        let token = channel_token::test::token(&json!({"max_connections": 2}), "s3cret");
+        let limits = |uri: &str, initial_connection: bool| {
+            let req = test::TestRequest::get().uri(uri).to_http_request();
+            channel_limits(&req, &settings, initial_connection)
+        };
+        let valid = format!("/v1/ws/?token={}", token);
+        assert_eq!(limits(&valid, true).unwrap().max_connections, Some(2));
+        // only whoever creates the channel sets its limits
+        assert_eq!(limits(&valid, false).unwrap(), Default::default());
+        assert_eq!(limits("/v1/ws/", true).unwrap(), Default::default());
+
+        let tampered = format!("/v1/ws/?token={}", token.replacen('e', "f", 1));
+        let err: Value = serde_json::from_str(&limits(&tampered, true).unwrap_err()).unwrap();
+        assert_eq!(err["error"], "bad_token");
+        let forged = channel_token::test::token(&json!({"max_connections": 2}), "guess");
+        assert!(limits(&format!("/v1/ws/?token={}", forged), true).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_resume_token() {
+        let settings = settings::Settings {
+            resume_token_secret: "s3cret".to_owned(),
+            ..Default::default()
+        };
+        let resume = |settings: &settings::Settings, uri: &str, initial_connection: bool| {
+            let req = test::TestRequest::get().uri(uri).to_http_request();
+            resume_token(&req, settings, initial_connection)
+        };
+        let uri = "/v1/ws/Ck5ZMEotWCcmmXbJYkiGyw?resume=abc.def";
+        assert_eq!(resume(&settings, uri, false), Some("abc.def".to_owned()));
+        // there's nothing to resume when creating a channel
+        assert_eq!(resume(&settings, uri, true), None);
+        assert_eq!(
+            resume(&settings, "/v1/ws/Ck5ZMEotWCcmmXbJYkiGyw", false),
+            None
+        );
+        // disabled by default
+        assert_eq!(resume(&Default::default(), uri, false), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_proxies() {
+        let settings = settings::Settings {
+            admin_token: "s3cret".to_owned(),
+            trusted_proxy_list: "1.2.3.4, 5.6.0.0/16".to_owned(),
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let app = ws_app!(settings);
+        let req = test::TestRequest::get().uri("/v1/admin/proxies");
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get()
+            .uri("/v1/admin/proxies")
+            .insert_header((header::AUTHORIZATION, "Bearer s3cret"));
+        let resp = test::call_service(&app, req.to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body["trusted_proxies"],
+            json!([
+                "10.0.0.0/8",
+                "172.16.0.0/12",
+                "192.168.0.0/16",
+                "1.2.3.4/32",
+                "5.6.0.0/16"
+            ])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_rate_limit() {
+        let settings = settings::Settings {
+            admin_token: "s3cret".to_owned(),
+            admin_rate_limit: 2,
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let app = ws_app!(settings);
+        let admin = |uri| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header((header::AUTHORIZATION, "Bearer s3cret"))
+                .to_request()
+        };
+
+        // Requests without the token don't use up the operators' budget.
+        for uri in [
+            "/__channels__",
+            "/v1/admin/maintenance",
+            "/v1/admin/proxies",
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let resp = test::call_service(&app, admin("/__channels__")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, admin("/v1/admin/proxies")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // The limit is shared across the admin endpoints.
+        let resp = test::call_service(&app, admin("/__channels__")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        let resp = test::call_service(&app, admin("/v1/admin/maintenance")).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Relaying is unaffected.
+        for _ in 0..3 {
+            let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+            assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_audit_reserved() {
+        let settings = settings::Settings {
+            reserved_channels: "adm*".to_owned(),
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let mut state = test_state(&settings);
+        let (audit, records) = audit::test::capture();
+        state.audit = audit;
+        let app = ws_app!(settings, state);
+
+        let resp = test::call_service(
+            &app,
+            ws_request("/v1/ws/admAAAAAAAAAAAAAAAAAAA").to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        // An id that isn't a channel id would have created a channel.
+        let resp = test::call_service(&app, ws_request("/v1/ws/admin").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["remote_ip"], "1.2.3.4");
+        assert_eq!(records[0]["outcome"], "rejected");
+        assert_eq!(records[0]["reason"], "reserved");
+        assert_eq!(records[0]["action"], "join");
+        assert_eq!(records[1]["reason"], "reserved");
+        assert_eq!(records[1]["action"], "create");
+    }
+
+    #[actix_rt::test]
+    async fn test_audit_first_rejection() {
+        let settings = settings::Settings {
+            min_client_version: "2.1.0".to_owned(),
+            channel_token_secret: "s3cret".to_owned(),
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let mut state = test_state(&settings);
+        let (audit, records) = audit::test::capture();
+        state.audit = audit;
+        let app = ws_app!(settings, state);
+
+        // Both too old and carrying a bad token.
+        let req = ws_request("/v1/ws/?cv=2.0.0&token=forged").to_request();
+        test::call_service(&app, req).await;
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["reason"], "client_too_old");
+    }
+
+    #[actix_rt::test]
+    async fn test_auth_hook() {
+        for (answer, status) in [
+            (r#"{"allow": true}"#, StatusCode::SWITCHING_PROTOCOLS),
+            (r#"{"allow": false}"#, StatusCode::FORBIDDEN),
+        ] {
+            let (url, requests) = auth_hook::test::mock_hook(answer);
+            let settings = settings::Settings {
+                auth_hook_url: url,
+                statsd_host: "".to_owned(),
+                ..Default::default()
+            };
+            let app = ws_app!(settings);
+
+            let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+            assert_eq!(resp.status(), status, "{}", answer);
+            assert_eq!(requests.lock().unwrap().len(), 1);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_ip_reputation() {
+        for (reputation, status) in [
+            (10, StatusCode::FORBIDDEN),
+            (90, StatusCode::SWITCHING_PROTOCOLS),
+        ] {
+            let settings = settings::Settings {
+                ip_reputation_server: ip_rate_limit::test::reputation_iprepd(reputation).0,
+                iprep_min: 50,
+                statsd_host: "".to_owned(),
+                ..Default::default()
+            };
+            let app = ws_app!(settings);
+
+            let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+            assert_eq!(resp.status(), status, "{}", reputation);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_bad_ws_version() {
+        let settings = settings::Settings {
+            strict_ws_version: true,
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let mut state = test_state(&settings);
+        let (audit, records) = audit::test::capture();
+        state.audit = audit;
+        let app = ws_app!(settings, state);
+
+        let req = ws_request("/v1/ws/")
+            .insert_header(("sec-websocket-version", "8"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(
+            resp.headers().get(header::SEC_WEBSOCKET_VERSION).unwrap(),
+            "13"
+        );
+        let existing = format!("/v1/ws/{}", channelid::ChannelID::default().as_string());
+        let req = ws_request(&existing)
+            .insert_header(("sec-websocket-version", "8"))
+            .to_request();
+        test::call_service(&app, req).await;
+        {
+            let records = records.lock().unwrap();
+            assert_eq!(records[0]["action"], "create");
+            assert_eq!(records[1]["action"], "join");
+        }
+
+        let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[actix_rt::test]
+    async fn test_prometheus_route() {
+        let settings = settings::Settings::default();
+        let registry = metrics::PrometheusRegistry::default();
+        let log = logging::MozLogger::new_human();
+        let metrics = metrics::metrics_from_opts(&settings, &log, Some(&registry)).unwrap();
+        metrics.incr("conn.create").ok();
+        let mut state = test_state(&settings);
+        state.prometheus = Some(registry);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(web::resource("/metrics").route(web::get().to(prometheus_route))),
+        )
+        .await;
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("channelserver_conn_create_total 1\n"));
+
+        // not enabled
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_state(&settings)))
+                .service(web::resource("/metrics").route(web::get().to(prometheus_route))),
+        )
+        .await;
+        let resp =
+            test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_subprotocol() {
+        let app = ws_app!(settings::Settings::default());
+        let negotiate = |requested: &str| {
+            ws_request("/v1/ws/")
+                .insert_header((header::SEC_WEBSOCKET_PROTOCOL, requested.to_owned()))
+                .to_request()
+        };
+
+        for requested in ["fxa-pairing.v1", "chat, fxa-pairing.v1"] {
+            let resp = test::call_service(&app, negotiate(requested)).await;
+            assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+            assert_eq!(
+                resp.headers().get(header::SEC_WEBSOCKET_PROTOCOL).unwrap(),
+                "fxa-pairing.v1"
+            );
+        }
+
+        let resp = test::call_service(&app, negotiate("chat")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        // Clients that don't ask for one are still let in.
+        let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert!(resp.headers().get(header::SEC_WEBSOCKET_PROTOCOL).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_static_dir() {
+        let dir = std::env::temp_dir().join(format!("channelserver-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "<html></html>").unwrap();
+
+        let settings = settings::Settings {
+            static_dir: dir.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let app =
+            test::init_service(App::new().configure(|cfg| static_files(cfg, &settings))).await;
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/static/index.html")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, "<html></html>");
+
+        let settings = settings::Settings::default();
+        let app =
+            test::init_service(App::new().configure(|cfg| static_files(cfg, &settings))).await;
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/static/index.html")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_routes() {
+        let settings = settings::Settings {
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        for (path, status) in [
+            ("/v1/ws/", StatusCode::SWITCHING_PROTOCOLS),
+            ("/v1/ws", StatusCode::SWITCHING_PROTOCOLS),
+            (
+                "/v1/ws/IZ5B8Wj2qR1NlsNbSXQ2Fg",
+                StatusCode::SWITCHING_PROTOCOLS,
+            ),
+            ("/v1/ws//IZ5B8Wj2qR1NlsNbSXQ2Fg", StatusCode::NOT_FOUND),
+            ("/v1/ws/IZ5B8Wj2qR1NlsNbSXQ2Fg/", StatusCode::NOT_FOUND),
+            ("/v1/ws/IZ5B8Wj2qR1NlsNbSXQ2Fg/extra", StatusCode::NOT_FOUND),
+        ] {
+            let app = ws_app!(settings.clone());
+            let resp = test::call_service(&app, ws_request(path).to_request()).await;
+            assert_eq!(resp.status(), status, "{}", path);
+        }
+
+        let settings = settings::Settings {
+            require_ws_trailing_slash: true,
+            ..settings
+        };
+        let mut state = test_state(&settings);
+        let (audit, records) = audit::test::capture();
+        state.audit = audit;
+        let app = ws_app!(settings, state);
+        let resp = test::call_service(&app, ws_request("/v1/ws").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(records.lock().unwrap()[0]["reason"], "no_trailing_slash");
+        let resp = test::call_service(&app, ws_request("/v1/ws/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[actix_rt::test]
+    async fn test_preallocated() {
+        let settings = settings::Settings {
+            require_preallocated: true,
+            max_channels: 3,
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let mut state = test_state(&settings);
+        let (audit, records) = audit::test::capture();
+        state.audit = audit;
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        state.metrics = Arc::new(cadence::StatsdClient::from_sink("test", sink));
+        let maintenance = state.maintenance.clone();
+        let app = ws_app!(settings, state);
+
+        let req = test::TestRequest::post().uri("/v1/channels").to_request();
+        let welcome: Value = test::call_and_read_body_json(&app, req).await;
+        let link = welcome["link"].as_str().unwrap();
+        assert_eq!(
+            link,
+            format!("/v1/ws/{}", welcome["channelid"].as_str().unwrap())
+        );
+
+        let resp = test::call_service(&app, ws_request(link).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+        for path in ["/v1/ws/", "/v1/ws"] {
+            let resp = test::call_service(&app, ws_request(path).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+        let resp = test::call_service(&app, ws_request("/v1/ws/nonsense").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        {
+            let records = records.lock().unwrap();
+            let reasons: Vec<_> = records.iter().map(|record| &record["reason"]).collect();
+            assert_eq!(
+                reasons,
+                ["not_preallocated", "not_preallocated", "invalid_channel"]
+            );
+        }
+
+        // Also at its singular name, counted the same.
+        let req = test::TestRequest::post().uri("/v1/channel").to_request();
+        let welcome: Value = test::call_and_read_body_json(&app, req).await;
+        let link = welcome["link"].as_str().unwrap();
+        let resp = test::call_service(&app, ws_request(link).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let sent: Vec<_> = rx
+            .try_iter()
+            .map(|metric| String::from_utf8(metric).unwrap())
+            .collect();
+        let count = |name: &str| sent.iter().filter(|metric| *metric == name).count();
+        assert_eq!(count("test.channel.preallocated:1|c"), 2);
+
+        // Reservations count against `max_channels`, as nothing connected.
+        let req = || test::TestRequest::post().uri("/v1/channels").to_request();
+        let resp = test::call_service(&app, req()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, req()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        maintenance.store(true, std::sync::atomic::Ordering::Relaxed);
+        let resp = test::call_service(&app, req()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "maintenance");
+        {
+            let records = records.lock().unwrap();
+            let reasons: Vec<_> = records.iter().map(|record| &record["reason"]).collect();
+            assert_eq!(reasons[3..], ["at_capacity", "maintenance"]);
+        }
+
+        // Not served unless channels must be preallocated.
+        let app = ws_app!(settings::Settings {
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        });
+        let resp = test::call_service(&app, req()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_channel_status() {
+        let settings = settings::Settings {
+            require_preallocated: true,
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let app = ws_app!(settings);
+        let status = |path: String| test::TestRequest::get().uri(&path).to_request();
+
+        let req = test::TestRequest::post().uri("/v1/channel").to_request();
+        let welcome: Value = test::call_and_read_body_json(&app, req).await;
+        let channelid = welcome["channelid"].as_str().unwrap();
+        let path = format!("/v1/channel/{}/status", channelid);
+        let body: Value = test::call_and_read_body_json(&app, status(path)).await;
+        assert_eq!(
+            body,
+            json!({"exists": true, "participants": 0, "full": false})
+        );
+
+        let path = format!("/v1/channel/{}/status", channelid::ChannelID::default());
+        let body: Value = test::call_and_read_body_json(&app, status(path)).await;
+        assert_eq!(body["exists"], false);
+
+        let resp = test::call_service(&app, status("/v1/channel/nope/status".to_owned())).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_readiness() {
+        let settings = settings::Settings {
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let state = test_state(&settings);
+        let channel_server =
+            server::ChannelServer::new(&settings, &state.log, state.metrics.clone());
+        let readiness = channel_server.readiness.clone();
+        let server = channel_server.start();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(readiness.clone()))
+                .service(web::resource("/__lbheartbeat__").route(web::get().to(lbheartbeat)))
+                .service(web::resource("/__heartbeat__").route(web::get().to(heartbeat))),
+        )
+        .await;
+        let status = |path: &'static str| {
+            let app = &app;
+            async move {
+                let req = test::TestRequest::get().uri(path).to_request();
+                test::call_service(app, req).await.status()
+            }
+        };
+
+        // still starting up
+        assert_eq!(
+            status("/__lbheartbeat__").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        readiness.set(true);
+        assert_eq!(status("/__lbheartbeat__").await, StatusCode::OK);
+        server.send(server::DrainAll(Duration::ZERO)).await.unwrap();
+        assert_eq!(
+            status("/__lbheartbeat__").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        // but still alive
+        assert_eq!(status("/__heartbeat__").await, StatusCode::OK);
+    }
 }
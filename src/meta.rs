@@ -1,18 +1,26 @@
 use std::collections::{BTreeMap, HashMap};
 use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 
 use actix_web::{
     dev::Payload,
     http::{self, header::HeaderMap, header::HeaderName},
     web, Error, FromRequest, HttpRequest,
 };
+use cadence::{CountedExt, Histogrammed};
 use futures::future::{ok, Ready};
 use ipnet::IpNet;
-use maxminddb::{self, geoip2::City, MaxMindDBError};
+use maxminddb::{
+    self,
+    geoip2::{Asn, City},
+    MaxMindDBError,
+};
 use serde::{self, Serialize};
 use slog::{debug, error, info, warn};
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use crate::error::{HandlerError, HandlerErrorKind};
+use crate::geo_cache;
 use crate::logging;
 use crate::session::WsChannelSessionState;
 
@@ -29,33 +37,90 @@ pub struct SenderData {
     pub region: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lon: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    // The sender's network is logged, but not shown to other participants.
+    #[serde(skip_serializing)]
+    pub asn: Option<u32>,
+    #[serde(skip_serializing)]
+    pub org: Option<String>,
+}
+
+/// Bounds the number of geo lookups in progress at once.
+#[derive(Debug)]
+pub struct GeoLimiter {
+    // `None` if lookups are unlimited
+    permits: Option<Semaphore>,
+    // how long to wait for a free slot before giving up
+    wait: Duration,
+}
+
+/// Held while a geo lookup is in progress.
+pub struct GeoPermit<'a>(#[allow(dead_code)] Option<SemaphorePermit<'a>>);
+
+impl GeoLimiter {
+    pub fn new(max_concurrent: usize, wait: Duration) -> Self {
+        Self {
+            permits: if max_concurrent > 0 {
+                Some(Semaphore::new(max_concurrent))
+            } else {
+                None
+            },
+            wait,
+        }
+    }
+
+    /// Wait for a free lookup slot. Returns `None` if none became available
+    /// in time, in which case the lookup should be skipped.
+    pub async fn acquire(&self) -> Option<GeoPermit<'_>> {
+        let permits = match &self.permits {
+            Some(permits) => permits,
+            None => return Some(GeoPermit(None)),
+        };
+        if let Ok(permit) = permits.try_acquire() {
+            return Some(GeoPermit(Some(permit)));
+        }
+        if self.wait.is_zero() {
+            return None;
+        }
+        match actix_rt::time::timeout(self.wait, permits.acquire()).await {
+            Ok(Ok(permit)) => Some(GeoPermit(Some(permit))),
+            _ => None,
+        }
+    }
 }
 
 // Parse the Accept-Language header to get the list of preferred languages.
 // We default to "en" because of well-established Anglo-biases.
 fn preferred_languages(alheader: String, default: &str) -> Vec<String> {
-    let default_lang = String::from(default);
-    let mut lang_tree: BTreeMap<String, String> = BTreeMap::new();
-    let mut i = 0;
-    alheader.split(',').for_each(|l| {
-        if l != "-" {
-            if l.contains(';') {
-                let weight: Vec<&str> = l.split(';').collect();
-                let lang = weight[0].to_ascii_lowercase();
-                let pref = weight[1].to_ascii_lowercase();
-                lang_tree.insert(String::from(pref.trim()), String::from(lang.trim()));
-            } else {
-                lang_tree.insert(format!("q=1.{:02}", i), l.to_ascii_lowercase());
-                i += 1;
+    let mut weighted: Vec<(f32, String)> = alheader
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let lang = parts.next()?.trim().to_ascii_lowercase();
+            if lang.is_empty() || lang == "-" {
+                return None;
             }
-        }
-    });
-    let mut langs: Vec<String> = lang_tree
-        .values()
-        .map(std::borrow::ToOwned::to_owned)
+            // Unweighted, or unreadably weighted, languages are preferred.
+            let q = parts
+                .filter_map(|param| {
+                    let (name, value) = param.split_once('=')?;
+                    name.trim().eq_ignore_ascii_case("q").then_some(value)
+                })
+                .find_map(|value| value.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            // "q=0" means the language is not acceptable.
+            (q > 0.0).then(|| (q.min(1.0), lang))
+        })
         .collect();
-    langs.reverse();
-    langs.push(default_lang);
+    // A stable sort keeps the header's order between equal weights.
+    weighted.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut langs: Vec<String> = weighted.into_iter().map(|(_, lang)| lang).collect();
+    langs.push(default.to_owned());
     langs
 }
 
@@ -90,9 +155,10 @@ fn get_preferred_language_element(
 #[allow(unreachable_patterns)]
 fn handle_city_err(log: &logging::MozLogger, err: &MaxMindDBError) {
     match err {
+        // Checked when the database is loaded, so only part of it is bad.
+        // Carry on without a location rather than take every channel down.
         maxminddb::MaxMindDBError::InvalidDatabaseError(s) => {
-            error!(log.log, "Invalid GeoIP database! {:?}", s);
-            ::std::process::exit(-1);
+            error!(log.log, "Invalid GeoIP database! {:?}", s)
         }
         maxminddb::MaxMindDBError::IoError(s) => error!(log.log, "Could not read database {:?}", s),
         maxminddb::MaxMindDBError::MapError(s) => warn!(log.log, "Mapping error: {:?}", s),
@@ -107,6 +173,17 @@ fn handle_city_err(log: &logging::MozLogger, err: &MaxMindDBError) {
     };
 }
 
+/// The ASN data only adds detail to the logs, so a failed lookup never rates
+/// more than a warning.
+fn handle_asn_err(log: &logging::MozLogger, err: &MaxMindDBError) {
+    match err {
+        maxminddb::MaxMindDBError::AddressNotFoundError(s) => {
+            debug!(log.log, "Could not find network for IP: {:?}", s)
+        }
+        _ => warn!(log.log, "Could not look up network: {:?}", err),
+    }
+}
+
 fn get_ua(headers: &HeaderMap, log: &logging::MozLogger, meta: &SenderData) -> Option<String> {
     if let Some(ua) = headers
         .get(http::header::USER_AGENT)
@@ -137,19 +214,27 @@ fn is_trusted_proxy(proxy_list: &[IpNet], host: &IpAddr) -> bool {
     proxy_list.iter().any(|range| range.contains(host))
 }
 
+/// Where `get_remote` looks for the remote address, unless `remote_ip_sources`
+/// says otherwise.
+pub const DEFAULT_REMOTE_IP_SOURCES: &str = "x-forwarded-for,peer";
+
 fn get_remote(
     peer: &Option<SocketAddr>,
     headers: &HeaderMap,
     proxy_list: &[IpNet],
+    trust_loopback: bool,
+    sources: &[String],
     log: &logging::MozLogger,
 ) -> Result<String, HandlerError> {
     // Actix determines the connection_info.remote() from the first entry in the
     // Forwarded then X-Fowarded-For, Forwarded-For, then peer name. The problem is that any
     // of those could be multiple entries or may point to a known proxy, or be injected by the
-    // user. We strictly only check the one header we know the proxy will be sending, working
+    // user. We strictly only check the headers we know the proxy will be sending, working
     // our way back up the proxy chain until we find the first unexpected address.
     // This may be an intermediary proxy, or it may be the original requesting system.
     //
+    // `sources` are consulted in order. "peer" is the connecting address, the rest
+    // are header names, which are only believed if the peer is a known proxy.
     let peer_ip = match peer {
         None => {
             return Err(
@@ -159,57 +244,110 @@ fn get_remote(
         Some(v) => v,
     }
     .ip();
-    // if the peer is not a known proxy, ignore the X-Forwarded-For headers
-    if !is_trusted_proxy(proxy_list, &peer_ip) {
-        return Ok(peer_ip.to_string());
-    }
-
-    // The peer is a known proxy, so take rightmost X-Forwarded-For that is not a trusted proxy.
-    match headers.get(HeaderName::from_lowercase(b"x-forwarded-for").unwrap()) {
-        Some(header) => {
-            match header.to_str() {
-                Ok(hstr) => {
-                    info!(log.log, "Remote IP List: {:?}", hstr);
-                    // successive proxies are appeneded to this header.
-                    let mut host_list: Vec<&str> = hstr.split(',').collect();
-                    host_list.reverse();
-                    for host_str in host_list {
-                        match host_str.trim().parse::<IpAddr>() {
-                            Ok(addr) => {
-                                if !addr.is_loopback() && !is_trusted_proxy(proxy_list, &addr) {
-                                    return Ok(addr.to_string());
-                                }
-                            }
-                            Err(err) => {
-                                info!(log.log,
-                                    "Bad IP Specified";
-                                    "remote_ip" => host_str.trim(),
-                                    "err" => format!("{:?}", err),
-                                );
-                                return Err(HandlerErrorKind::BadRemoteAddrError(
-                                    "Bad IP Specified".to_owned(),
-                                )
-                                .into());
+    let trusted_peer = is_trusted_proxy(proxy_list, &peer_ip);
+    for source in sources {
+        if source == "peer" {
+            // A proxy is never the remote we're looking for.
+            if !trusted_peer {
+                return Ok(peer_ip.to_string());
+            }
+            continue;
+        }
+        // if the peer is not a known proxy, ignore the forwarding headers
+        if !trusted_peer {
+            continue;
+        }
+        let header = match HeaderName::from_bytes(source.as_bytes())
+            .ok()
+            .and_then(|name| headers.get(name))
+        {
+            Some(header) => header,
+            None => continue,
+        };
+        // The peer is a known proxy, so take the rightmost address that is not a trusted proxy.
+        return match header.to_str() {
+            Ok(hstr) => {
+                info!(log.log, "Remote IP List: {:?}", hstr; "source" => source);
+                // successive proxies are appeneded to this header.
+                let mut host_list: Vec<&str> = hstr.split(',').collect();
+                host_list.reverse();
+                for host_str in host_list {
+                    match host_str.trim().parse::<IpAddr>() {
+                        Ok(addr) => {
+                            if (trust_loopback || !addr.is_loopback())
+                                && !is_trusted_proxy(proxy_list, &addr)
+                            {
+                                return Ok(addr.to_string());
                             }
                         }
+                        Err(err) => {
+                            info!(log.log,
+                                "Bad IP Specified";
+                                "remote_ip" => host_str.trim(),
+                                "err" => format!("{:?}", err),
+                            );
+                            return Err(HandlerErrorKind::BadRemoteAddrError(
+                                "Bad IP Specified".to_owned(),
+                            )
+                            .into());
+                        }
                     }
-                    Err(
-                        HandlerErrorKind::BadRemoteAddrError("Only proxies specified".to_owned())
-                            .into(),
-                    )
                 }
-                Err(err) => Err(HandlerErrorKind::BadRemoteAddrError(format!(
-                    "Unknown address in X-Forwarded-For: {:?}",
-                    err
-                ))
-                .into()),
+                Err(
+                    HandlerErrorKind::BadRemoteAddrError("Only proxies specified".to_owned())
+                        .into(),
+                )
             }
-        }
-        None => Err(HandlerErrorKind::BadRemoteAddrError(
-            "No X-Forwarded-For found for proxied connection".to_owned(),
-        )
-        .into()),
+            Err(err) => Err(HandlerErrorKind::BadRemoteAddrError(format!(
+                "Unknown address in {}: {:?}",
+                source, err
+            ))
+            .into()),
+        };
     }
+    Err(HandlerErrorKind::BadRemoteAddrError(format!(
+        "No {} found for proxied connection",
+        sources.join(" or ")
+    ))
+    .into())
+}
+
+/// Did the client connect over TLS?
+///
+/// TLS is terminated before it reaches us, so this relies on the
+/// `X-Forwarded-Proto` header, which is only believed from a trusted proxy.
+pub fn is_secure(peer: &Option<SocketAddr>, headers: &HeaderMap, proxy_list: &[IpNet]) -> bool {
+    let proxied = peer.is_some_and(|peer| is_trusted_proxy(proxy_list, &peer.ip()));
+    if !proxied {
+        return false;
+    }
+    headers
+        .get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok())
+        // A chain of proxies appends, so the first is what the client used.
+        .and_then(|proto| proto.split(',').next())
+        .is_some_and(|proto| {
+            let proto = proto.trim();
+            proto.eq_ignore_ascii_case("https") || proto.eq_ignore_ascii_case("wss")
+        })
+}
+
+/// The address of a remote, with the port stripped (if present).
+///
+/// Handles bare IPv4 and IPv6 addresses as well as `1.2.3.4:port` and
+/// `[2001:db8::1]:port`.
+pub fn strip_port(remote: &str) -> Option<IpAddr> {
+    let remote = remote.trim();
+    remote
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| remote.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            remote
+                .strip_prefix('[')
+                .and_then(|r| r.strip_suffix(']'))
+                .and_then(|r| r.parse().ok())
+        })
 }
 
 fn get_location(
@@ -225,16 +363,8 @@ fn get_location(
             "Looking up IP";
             "remote_ip" => &sender.remote
         );
-        // Strip the port from the remote (if present)
-        let remote = sender
-            .remote
-            .clone()
-            .map(|mut r| {
-                let end = r.find(':').unwrap_or(r.len());
-                r.drain(..end).collect()
-            })
-            .unwrap_or_else(|| default_lang.to_owned());
-        if let Ok(loc) = remote.parse() {
+        let remote = sender.remote.as_deref().unwrap_or(default_lang);
+        if let Some(loc) = strip_port(remote) {
             if let Ok(city) = iploc.lookup::<City>(loc).inspect_err(|err| {
                 handle_city_err(log, err);
             }) {
@@ -282,6 +412,11 @@ fn get_location(
                 if let Some(names) = city.country.and_then(|c| c.names) {
                     sender.country = get_preferred_language_element(langs, names);
                 }
+                if let Some(location) = city.location {
+                    sender.lat = location.latitude;
+                    sender.lon = location.longitude;
+                    sender.timezone = location.time_zone.map(str::to_owned);
+                }
                 // because consistency is overrated.
                 if let Some(subdivisions) = city.subdivisions {
                     if let Some(subdivision) = subdivisions.first() {
@@ -302,6 +437,19 @@ fn get_location(
     }
 }
 
+/// Look up the network the sender is connecting from in the ASN database.
+fn get_asn(sender: &mut SenderData, log: &logging::MozLogger, asnloc: &maxminddb::Reader<Vec<u8>>) {
+    let Some(ip) = sender.remote.as_deref().and_then(strip_port) else {
+        return;
+    };
+    if let Ok(asn) = asnloc.lookup::<Asn>(ip).inspect_err(|err| {
+        handle_asn_err(log, err);
+    }) {
+        sender.asn = asn.autonomous_system_number;
+        sender.org = asn.autonomous_system_organization.map(str::to_owned);
+    }
+}
+
 impl FromRequest for SenderData {
     type Error = Error;
     type Future = Ready<Result<Self, Self::Error>>;
@@ -317,6 +465,82 @@ impl FromRequest for SenderData {
 
 impl SenderData {
     pub fn new(req: &HttpRequest, data: &WsChannelSessionState) -> Self {
+        Self::build(req, data, true)
+    }
+
+    /// Gather the sender data without resolving their location.
+    pub fn without_geo(req: &HttpRequest, data: &WsChannelSessionState) -> Self {
+        Self::build(req, data, false)
+    }
+
+    fn build(req: &HttpRequest, data: &WsChannelSessionState, lookup_geo: bool) -> Self {
+        Self::build_with(req, data, |sender, langs| {
+            if lookup_geo {
+                sender.locate(langs, data);
+                if let Some(asnloc) = &data.asnloc {
+                    get_asn(sender, &data.log, &asnloc.load());
+                }
+            }
+        })
+    }
+
+    /// Resolve the sender's location, from `geo_cache` if it was looked up
+    /// recently.
+    fn locate(&mut self, langs: &[String], data: &WsChannelSessionState) {
+        let lookup = |sender: &mut SenderData| {
+            get_location(
+                sender,
+                langs,
+                &data.log,
+                &data.iploc.load(),
+                &data.settings.default_lang,
+            )
+        };
+        let key = self
+            .remote
+            .as_deref()
+            .and_then(strip_port)
+            .map(|ip| (ip, langs.join(",")));
+        let (Some(cache), Some(key)) = (&data.geo_cache, key) else {
+            return lookup(self);
+        };
+        let cached = cache.lock().ok().and_then(|mut cache| cache.get(&key));
+        if let Some(location) = cached {
+            data.metrics.incr("geoip.cache.hit").ok();
+            self.city = location.city;
+            self.region = location.region;
+            self.country = location.country;
+            self.lat = location.lat;
+            self.lon = location.lon;
+            self.timezone = location.timezone;
+            return;
+        }
+        data.metrics.incr("geoip.cache.miss").ok();
+        lookup(self);
+        let location = geo_cache::Location {
+            city: self.city.clone(),
+            region: self.region.clone(),
+            country: self.country.clone(),
+            lat: self.lat,
+            lon: self.lon,
+            timezone: self.timezone.clone(),
+        };
+        if let Ok(mut cache) = cache.lock() {
+            cache.insert(key, location);
+        }
+    }
+
+    /// Gather the sender data, using `locate` to resolve their location.
+    ///
+    /// Enrichment gives up, leaving the location unset, once it has taken
+    /// longer than `max_meta_processing_ms`.
+    fn build_with<F>(req: &HttpRequest, data: &WsChannelSessionState, locate: F) -> Self
+    where
+        F: FnOnce(&mut SenderData, &[String]),
+    {
+        let started = Instant::now();
+        let budget = data.settings.max_meta_processing_ms;
+        let over_budget = || budget > 0 && started.elapsed() > Duration::from_millis(budget);
         let mut sender = SenderData::default();
         let headers = req.headers();
         let default_lang = &data.settings.default_lang;
@@ -325,6 +549,8 @@ impl SenderData {
             &req.peer_addr(),
             req.headers(),
             &data.trusted_proxy_list,
+            data.settings.trust_loopback_in_xff,
+            &data.remote_ip_sources,
             &data.log,
         ) {
             Ok(addr) => Some(addr),
@@ -347,22 +573,51 @@ impl SenderData {
                             "Bad Accept-Language string: {:?}", err;
                             "remote_ip" => &sender.remote
                         );
-                        &data.settings.default_lang
+                        data.settings.default_lang.clone()
+                    }
+                    Ok(ls) => {
+                        let entries = ls.split(',').count();
+                        data.metrics
+                            .histogram("meta.lang_count", entries as u64)
+                            .ok();
+                        // Don't spend time sorting an abusively long list.
+                        let max = data.settings.max_accept_language_entries;
+                        if max > 0 && entries > max {
+                            ls.split(',').take(max).collect::<Vec<&str>>().join(",")
+                        } else {
+                            ls.to_owned()
+                        }
                     }
-                    Ok(ls) => ls,
                 };
-                preferred_languages(lang.to_owned(), default_lang)
+                preferred_languages(lang, default_lang)
             }
         };
         // parse user-header for platform info
         sender.ua = get_ua(headers, &data.log, &sender);
-        get_location(
-            &mut sender,
-            &langs,
-            &data.log,
-            &data.iploc,
-            &data.settings.default_lang,
-        );
+        if !over_budget() {
+            locate(&mut sender, &langs);
+            if !data.settings.geo_include_timezone {
+                sender.timezone = None;
+            }
+        }
+        if over_budget() {
+            warn!(
+                data.log.log,
+                "Sender data took too long, skipping location";
+                "remote_ip" => &sender.remote,
+                "elapsed_ms" => started.elapsed().as_millis() as u64,
+            );
+            data.metrics.incr("meta.slow").ok();
+            sender.city = None;
+            sender.region = None;
+            sender.country = None;
+            sender.lat = None;
+            sender.lon = None;
+            sender.timezone = None;
+            sender.asn = None;
+            sender.org = None;
+            return sender;
+        }
         // If there's no sender, try pulling the GCP header.
         // NOTE: This is US/EN only, so localization should come later.
         if sender.city.is_none() {
@@ -379,6 +634,70 @@ impl SenderData {
     }
 }
 
+/// Log fields for a `SenderData`.
+///
+/// These are either separate `remote_*` keys, or with `compact` a single
+/// `meta` key holding the same fields as a JSON object.
+pub struct MetaLog<'a> {
+    meta: &'a SenderData,
+    compact: bool,
+}
+
+impl SenderData {
+    pub fn log_kv(&self, compact: bool) -> MetaLog<'_> {
+        MetaLog {
+            meta: self,
+            compact,
+        }
+    }
+}
+
+impl slog::KV for MetaLog<'_> {
+    fn serialize(
+        &self,
+        _record: &slog::Record,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        if self.compact {
+            let fields: BTreeMap<String, String> =
+                Option::<HashMap<String, String>>::from(self.meta.clone())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect();
+            return serializer
+                .emit_str("meta", &serde_json::to_string(&fields).unwrap_or_default());
+        }
+        for (key, val) in [
+            ("remote_ip", &self.meta.remote),
+            ("remote_city", &self.meta.city),
+            ("remote_region", &self.meta.region),
+            ("remote_country", &self.meta.country),
+        ] {
+            if let Some(val) = val {
+                serializer.emit_str(key, val)?;
+            }
+        }
+        for (key, val) in [
+            ("remote_lat", &self.meta.lat),
+            ("remote_lon", &self.meta.lon),
+        ] {
+            if let Some(val) = val {
+                serializer.emit_f64(key, *val)?;
+            }
+        }
+        if let Some(val) = &self.meta.timezone {
+            serializer.emit_str("remote_timezone", val)?;
+        }
+        if let Some(val) = self.meta.asn {
+            serializer.emit_u32("remote_asn", val)?;
+        }
+        if let Some(val) = &self.meta.org {
+            serializer.emit_str("remote_org", val)?;
+        }
+        Ok(())
+    }
+}
+
 /// Convert the Sender Metadata into a optional hash of data. Only include things that are set.
 /// This is used mostly by the logger.
 impl From<SenderData> for Option<HashMap<String, String>> {
@@ -397,6 +716,21 @@ impl From<SenderData> for Option<HashMap<String, String>> {
         if let Some(val) = senderdata.country {
             map.insert("remote_country".to_owned(), val);
         }
+        if let Some(val) = senderdata.lat {
+            map.insert("remote_lat".to_owned(), val.to_string());
+        }
+        if let Some(val) = senderdata.lon {
+            map.insert("remote_lon".to_owned(), val.to_string());
+        }
+        if let Some(val) = senderdata.timezone {
+            map.insert("remote_timezone".to_owned(), val);
+        }
+        if let Some(val) = senderdata.asn {
+            map.insert("remote_asn".to_owned(), val.to_string());
+        }
+        if let Some(val) = senderdata.org {
+            map.insert("remote_org".to_owned(), val);
+        }
         if !map.is_empty() {
             return Some(map);
         }
@@ -426,10 +760,25 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_preferred_language_order() {
+        // Equal weights keep the order they were given in.
+        let langs = preferred_languages("fr, de;q=0.9, it, da;Q=1".to_owned(), "en");
+        assert_eq!(vec!["fr", "it", "da", "de", "en"], langs);
+        let langs = preferred_languages("da, en-gb;q=0.8, en;q=0.7".to_owned(), "en");
+        assert_eq!(vec!["da", "en-gb", "en", "en"], langs);
+    }
+
     #[test]
     fn test_bad_preferred_language() {
         let langs = preferred_languages("-".to_owned(), "en");
         assert_eq!(vec!["en".to_owned()], langs);
+        // Malformed entries don't panic, and unacceptable ones are dropped.
+        let langs = preferred_languages(
+            "fr;q=0.5,;q=0.9,de;,it;q=bogus,es;q=0,pt;level=1;q=0.2,,ja;q=NaN".to_owned(),
+            "en",
+        );
+        assert_eq!(vec!["de", "it", "fr", "pt", "en"], langs);
     }
 
     #[test]
@@ -512,6 +861,150 @@ mod test {
         assert_eq!(sender.city, Some("Sacramento".to_owned()));
         assert_eq!(sender.region, Some("California".to_owned()));
         assert_eq!(sender.country, Some("United States".to_owned()));
+        assert!(sender.lat.is_some_and(|lat| (38.0..39.0).contains(&lat)));
+        assert!(sender
+            .lon
+            .is_some_and(|lon| (-122.0..-121.0).contains(&lon)));
+        assert_eq!(sender.timezone, Some("America/Los_Angeles".to_owned()));
+        let logged: Option<HashMap<String, String>> = sender.into();
+        let logged = logged.unwrap();
+        assert!(logged.contains_key("remote_lat"));
+        assert!(logged.contains_key("remote_lon"));
+        assert_eq!(logged["remote_timezone"], "America/Los_Angeles");
+    }
+
+    #[test]
+    fn test_location_ipv6() {
+        let log = logging::MozLogger::new_human();
+        let langs = vec!["en".to_owned()];
+        let iploc = maxminddb::Reader::open_readfile("mmdb/latest/GeoLite2-City.mmdb").unwrap();
+        for remote in ["2620:101:8000::1", "[2620:101:8000::1]:443"] {
+            let mut sender = SenderData {
+                remote: Some(remote.to_owned()),
+                ..Default::default()
+            };
+            get_location(&mut sender, &langs, &log, &iploc, "en");
+            assert_eq!(sender.country, Some("United States".to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_strip_port() {
+        let v4: IpAddr = "63.245.208.195".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(strip_port("63.245.208.195"), Some(v4));
+        assert_eq!(strip_port("63.245.208.195:8000"), Some(v4));
+        assert_eq!(strip_port("2001:db8::1"), Some(v6));
+        assert_eq!(strip_port("[2001:db8::1]"), Some(v6));
+        assert_eq!(strip_port("[2001:db8::1]:8000"), Some(v6));
+        assert_eq!(strip_port("::1"), Some("::1".parse().unwrap()));
+        assert_eq!(strip_port("en"), None);
+        assert_eq!(strip_port("2001:db8::1:8000:bogus"), None);
+    }
+
+    /// A minimal ASN database, mapping every IPv4 address to one network.
+    fn asn_db(asn: u32, org: &str) -> maxminddb::Reader<Vec<u8>> {
+        maxminddb::Reader::from_source(asn_db_bytes(asn, org)).unwrap()
+    }
+
+    fn asn_db_bytes(asn: u32, org: &str) -> Vec<u8> {
+        // MaxMind DB control bytes hold the type in the top 3 bits and the
+        // size in the rest.
+        fn string(buf: &mut Vec<u8>, s: &str) {
+            buf.push(0x40 | s.len() as u8);
+            buf.extend_from_slice(s.as_bytes());
+        }
+        fn uint16(buf: &mut Vec<u8>, v: u16) {
+            buf.push(0xa2);
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+        // One node, both records pointing at the start of the data section.
+        let mut buf = vec![0, 0, 17, 0, 0, 17];
+        buf.extend_from_slice(&[0; 16]);
+        buf.push(0xe2);
+        string(&mut buf, "autonomous_system_number");
+        buf.push(0xc4);
+        buf.extend_from_slice(&asn.to_be_bytes());
+        // too long to fit in the control byte
+        buf.extend_from_slice(&[0x5d, 1]);
+        buf.extend_from_slice(b"autonomous_system_organization");
+        string(&mut buf, org);
+
+        buf.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+        buf.push(0xe9);
+        string(&mut buf, "node_count");
+        buf.extend_from_slice(&[0xc1, 1]);
+        string(&mut buf, "record_size");
+        uint16(&mut buf, 24);
+        string(&mut buf, "ip_version");
+        uint16(&mut buf, 4);
+        string(&mut buf, "database_type");
+        string(&mut buf, "GeoLite2-ASN");
+        string(&mut buf, "languages");
+        buf.extend_from_slice(&[0, 4]);
+        string(&mut buf, "binary_format_major_version");
+        uint16(&mut buf, 2);
+        string(&mut buf, "binary_format_minor_version");
+        uint16(&mut buf, 0);
+        string(&mut buf, "build_epoch");
+        buf.extend_from_slice(&[1, 2, 1]);
+        string(&mut buf, "description");
+        buf.push(0xe0);
+        buf
+    }
+
+    #[test]
+    fn test_asn() {
+        let log = logging::MozLogger::new_human();
+        let asnloc = asn_db(15169, "GOOGLE");
+        let mut sender = SenderData {
+            remote: Some("8.8.8.8:443".to_owned()),
+            ..Default::default()
+        };
+        get_asn(&mut sender, &log, &asnloc);
+        assert_eq!(sender.asn, Some(15169));
+        assert_eq!(sender.org, Some("GOOGLE".to_owned()));
+        let logged: Option<HashMap<String, String>> = sender.into();
+        let logged = logged.unwrap();
+        assert_eq!(logged["remote_asn"], "15169");
+        assert_eq!(logged["remote_org"], "GOOGLE");
+        // but not relayed
+        let sender = SenderData {
+            asn: Some(15169),
+            org: Some("GOOGLE".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(&sender).unwrap(),
+            serde_json::json!({})
+        );
+
+        // Nothing to look up.
+        let mut sender = SenderData {
+            remote: Some("unknown".to_owned()),
+            ..Default::default()
+        };
+        get_asn(&mut sender, &log, &asnloc);
+        assert_eq!(sender.asn, None);
+        assert_eq!(sender.org, None);
+    }
+
+    #[test]
+    fn test_invalid_database() {
+        let log = logging::MozLogger::new_human();
+        let langs = vec!["en".to_owned()];
+        // Clobber the search tree.
+        let mut buf = std::fs::read("mmdb/latest/GeoLite2-City.mmdb").unwrap();
+        buf.iter_mut().take(4096).for_each(|b| *b = 0xff);
+        let iploc = maxminddb::Reader::from_source(buf).unwrap();
+        let mut sender = SenderData {
+            remote: Some("63.245.208.195".to_owned()),
+            ..Default::default()
+        };
+        get_location(&mut sender, &langs, &log, &iploc, "en");
+        // Still here, just without a location.
+        assert_eq!(sender.city, None);
+        assert_eq!(sender.country, None);
     }
 
     #[test]
@@ -537,6 +1030,297 @@ mod test {
         assert_eq!(sender.country, None);
     }
 
+    #[actix_rt::test]
+    async fn test_geo_limiter() {
+        let limiter = GeoLimiter::new(1, Duration::from_secs(0));
+        let permit = limiter.acquire().await;
+        assert!(permit.is_some());
+        assert!(limiter.acquire().await.is_none());
+        drop(permit);
+        assert!(limiter.acquire().await.is_some());
+
+        // Wait for a slot to free up.
+        let limiter = std::sync::Arc::new(GeoLimiter::new(1, Duration::from_millis(500)));
+        let permit = limiter.acquire().await;
+        let waiting = {
+            let limiter = limiter.clone();
+            actix_rt::spawn(async move { limiter.acquire().await.is_some() })
+        };
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        drop(permit);
+        assert!(waiting.await.unwrap());
+
+        // Unlimited
+        let limiter = GeoLimiter::new(0, Duration::from_secs(0));
+        let _first = limiter.acquire().await;
+        assert!(limiter.acquire().await.is_some());
+    }
+
+    #[test]
+    fn test_without_geo() {
+        let settings = crate::settings::Settings {
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let log = logging::MozLogger::new_human();
+        let metrics =
+            std::sync::Arc::new(crate::metrics::metrics_from_opts(&settings, &log, None).unwrap());
+        let state = WsChannelSessionState::new(
+            &settings,
+            &log,
+            &metrics,
+            &crate::server::Backlog::default(),
+        );
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("63.245.208.195:80".parse().unwrap())
+            .to_http_request();
+
+        let sender = SenderData::new(&req, &state);
+        assert_eq!(sender.city, Some("Sacramento".to_owned()));
+        let sender = SenderData::without_geo(&req, &state);
+        assert_eq!(sender.remote, Some("63.245.208.195".to_owned()));
+        assert_eq!(sender.city, None);
+        assert_eq!(sender.country, None);
+    }
+
+    #[test]
+    fn test_reload_asnloc() {
+        let path = std::env::temp_dir().join(format!("asn-{}.mmdb", std::process::id()));
+        std::fs::write(&path, asn_db_bytes(15169, "GOOGLE")).unwrap();
+        let settings = crate::settings::Settings {
+            asn_mmdb_loc: path.to_str().unwrap().to_owned(),
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let log = logging::MozLogger::new_human();
+        let metrics =
+            std::sync::Arc::new(crate::metrics::metrics_from_opts(&settings, &log, None).unwrap());
+        let state = WsChannelSessionState::new(
+            &settings,
+            &log,
+            &metrics,
+            &crate::server::Backlog::default(),
+        );
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("8.8.8.8:80".parse().unwrap())
+            .to_http_request();
+        assert_eq!(SenderData::new(&req, &state).asn, Some(15169));
+
+        std::fs::write(&path, asn_db_bytes(13335, "CLOUDFLARENET")).unwrap();
+        assert!(state.reload_asnloc());
+        std::fs::remove_file(&path).unwrap();
+        let sender = SenderData::new(&req, &state);
+        assert_eq!(sender.asn, Some(13335));
+        assert_eq!(sender.org, Some("CLOUDFLARENET".to_owned()));
+        // the old data is kept when the new can't be read
+        assert!(!state.reload_asnloc());
+        assert_eq!(SenderData::new(&req, &state).asn, Some(13335));
+    }
+
+    #[test]
+    fn test_reload_iploc() {
+        let settings = crate::settings::Settings {
+            statsd_host: "".to_owned(),
+            ..Default::default()
+        };
+        let log = logging::MozLogger::new_human();
+        let metrics =
+            std::sync::Arc::new(crate::metrics::metrics_from_opts(&settings, &log, None).unwrap());
+        let state = WsChannelSessionState::new(
+            &settings,
+            &log,
+            &metrics,
+            &crate::server::Backlog::default(),
+        );
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("63.245.208.195:80".parse().unwrap())
+            .to_http_request();
+
+        // a lookup in progress during the reload
+        let before = state.iploc.load_full();
+        assert!(state.reload_iploc());
+        assert!(!std::sync::Arc::ptr_eq(&before, &state.iploc.load_full()));
+        let addr: std::net::IpAddr = "63.245.208.195".parse().unwrap();
+        assert!(before.lookup::<City>(addr).is_ok());
+
+        let sender = SenderData::new(&req, &state);
+        assert_eq!(sender.city, Some("Sacramento".to_owned()));
+    }
+
+    #[test]
+    fn test_lang_count() {
+        let settings = crate::settings::Settings {
+            max_accept_language_entries: 2,
+            ..Default::default()
+        };
+        let log = logging::MozLogger::new_human();
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let metrics = std::sync::Arc::new(cadence::StatsdClient::from_sink("test", sink));
+        let state = WsChannelSessionState::new(
+            &settings,
+            &log,
+            &metrics,
+            &crate::server::Backlog::default(),
+        );
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("63.245.208.195:80".parse().unwrap())
+            .insert_header((http::header::ACCEPT_LANGUAGE, "fr,de;q=0.9,es;q=0.8"))
+            .to_http_request();
+        SenderData::without_geo(&req, &state);
+        let sent: Vec<String> = rx
+            .try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect();
+        assert_eq!(sent, vec!["test.meta.lang_count:3|h"]);
+    }
+
+    #[test]
+    fn test_include_timezone() {
+        let log = logging::MozLogger::new_human();
+        let metrics = std::sync::Arc::new(cadence::StatsdClient::from_sink(
+            "test",
+            cadence::NopMetricSink,
+        ));
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("63.245.208.195:80".parse().unwrap())
+            .to_http_request();
+        for (include, timezone) in [(true, Some("America/Los_Angeles")), (false, None)] {
+            let settings = crate::settings::Settings {
+                geo_include_timezone: include,
+                ..Default::default()
+            };
+            let state = WsChannelSessionState::new(
+                &settings,
+                &log,
+                &metrics,
+                &crate::server::Backlog::default(),
+            );
+            let sender = SenderData::new(&req, &state);
+            assert_eq!(sender.city, Some("Sacramento".to_owned()));
+            assert_eq!(sender.timezone.as_deref(), timezone);
+        }
+    }
+
+    #[test]
+    fn test_geo_cache() {
+        let settings = crate::settings::Settings {
+            geo_cache_size: 10,
+            ..Default::default()
+        };
+        let log = logging::MozLogger::new_human();
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let metrics = std::sync::Arc::new(cadence::StatsdClient::from_sink("test", sink));
+        let state = WsChannelSessionState::new(
+            &settings,
+            &log,
+            &metrics,
+            &crate::server::Backlog::default(),
+        );
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("63.245.208.195:80".parse().unwrap())
+            .to_http_request();
+        let cached = |rx: &crossbeam_channel::Receiver<Vec<u8>>| -> Vec<String> {
+            rx.try_iter()
+                .map(|metric| String::from_utf8(metric).unwrap())
+                .filter(|metric| metric.starts_with("test.geoip.cache"))
+                .collect()
+        };
+
+        let first = SenderData::new(&req, &state);
+        assert_eq!(first.city, Some("Sacramento".to_owned()));
+        assert_eq!(cached(&rx), vec!["test.geoip.cache.miss:1|c"]);
+        let second = SenderData::new(&req, &state);
+        assert_eq!(cached(&rx), vec!["test.geoip.cache.hit:1|c"]);
+        assert_eq!(second.city, first.city);
+        assert_eq!(second.region, first.region);
+        assert_eq!(second.timezone, first.timezone);
+        assert_eq!(second.lat, first.lat);
+
+        // Other languages are looked up afresh.
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("63.245.208.195:80".parse().unwrap())
+            .insert_header(("Accept-Language", "fr"))
+            .to_http_request();
+        SenderData::new(&req, &state);
+        assert_eq!(cached(&rx), vec!["test.geoip.cache.miss:1|c"]);
+
+        // and reloading the database starts over.
+        assert!(state.reload_iploc());
+        SenderData::new(&req, &state);
+        assert_eq!(cached(&rx), vec!["test.geoip.cache.miss:1|c"]);
+    }
+
+    #[test]
+    fn test_meta_budget() {
+        let settings = crate::settings::Settings {
+            max_meta_processing_ms: 10,
+            ..Default::default()
+        };
+        let log = logging::MozLogger::new_human();
+        let (rx, sink) = cadence::SpyMetricSink::new();
+        let metrics = std::sync::Arc::new(cadence::StatsdClient::from_sink("test", sink));
+        let state = WsChannelSessionState::new(
+            &settings,
+            &log,
+            &metrics,
+            &crate::server::Backlog::default(),
+        );
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("63.245.208.195:80".parse().unwrap())
+            .insert_header(("X-Client-Geo-Location", "California,Mountain View"))
+            .to_http_request();
+        let locate = |delay: u64| {
+            move |sender: &mut SenderData, _: &[String]| {
+                std::thread::sleep(Duration::from_millis(delay));
+                sender.city = Some("Sacramento".to_owned());
+                sender.country = Some("USA".to_owned());
+            }
+        };
+
+        let sender = SenderData::build_with(&req, &state, locate(0));
+        assert_eq!(sender.city, Some("Sacramento".to_owned()));
+        assert!(rx.try_recv().is_err());
+
+        let sender = SenderData::build_with(&req, &state, locate(50));
+        assert_eq!(sender.remote, Some("63.245.208.195".to_owned()));
+        assert_eq!(sender.city, None);
+        assert_eq!(sender.region, None);
+        assert_eq!(sender.country, None);
+        assert_eq!(rx.try_recv().unwrap(), b"test.meta.slow:1|c");
+    }
+
+    #[test]
+    fn test_meta_log() {
+        use slog::Drain;
+
+        let meta = SenderData {
+            remote: Some("63.245.208.195".to_owned()),
+            city: Some("Sacramento".to_owned()),
+            country: Some("USA".to_owned()),
+            ..Default::default()
+        };
+        let buffer = logging::test::Buffer::default();
+        let log = slog::Logger::root(logging::Logfmt::new(buffer.clone()).fuse(), slog::o!());
+
+        info!(log, "Starting new session"; meta.log_kv(false));
+        let line = buffer.take();
+        assert!(line.contains(" remote_ip=63.245.208.195 "), "{}", line);
+        assert!(line.contains(" remote_city=Sacramento "), "{}", line);
+        assert!(line.contains(" remote_country=USA"), "{}", line);
+        assert!(!line.contains(" meta="), "{}", line);
+
+        info!(log, "Starting new session"; meta.log_kv(true));
+        let line = buffer.take();
+        assert!(
+            line.contains(
+                r#" meta="{\"remote_city\":\"Sacramento\",\"remote_country\":\"USA\",\"remote_ip\":\"63.245.208.195\"}""#
+            ),
+            "{}",
+            line
+        );
+        assert!(!line.contains(" remote_ip="), "{}", line);
+    }
+
     #[test]
     fn test_get_remote() {
         let mut headers = actix_web::http::header::HeaderMap::new();
@@ -549,6 +1333,7 @@ mod test {
         let true_remote: SocketAddr = "1.2.3.4:0".parse().unwrap();
         let proxy_server: SocketAddr = "192.168.0.4:0".parse().unwrap();
         let log = logging::MozLogger::new_human();
+        let sources = vec!["x-forwarded-for".to_owned(), "peer".to_owned()];
 
         bad_headers.insert(
             http::header::HeaderName::from_lowercase("x-forwarded-for".as_bytes()).unwrap(),
@@ -556,11 +1341,25 @@ mod test {
         );
 
         // Proxy only, no XFF header
-        let remote = get_remote(&Some(proxy_server), &empty_headers, &proxy_list, &log);
+        let remote = get_remote(
+            &Some(proxy_server),
+            &empty_headers,
+            &proxy_list,
+            false,
+            &sources,
+            &log,
+        );
         assert!(remote.is_err());
 
         // Proxy only, bad XFF header
-        let remote = get_remote(&Some(proxy_server), &bad_headers, &proxy_list, &log);
+        let remote = get_remote(
+            &Some(proxy_server),
+            &bad_headers,
+            &proxy_list,
+            false,
+            &sources,
+            &log,
+        );
         assert!(remote.is_err());
 
         // Proxy only, crap XFF header
@@ -568,11 +1367,25 @@ mod test {
             http::header::HeaderName::from_lowercase("x-forwarded-for".as_bytes()).unwrap(),
             "invalid".parse().unwrap(),
         );
-        let remote = get_remote(&Some(proxy_server), &bad_headers, &proxy_list, &log);
+        let remote = get_remote(
+            &Some(proxy_server),
+            &bad_headers,
+            &proxy_list,
+            false,
+            &sources,
+            &log,
+        );
         assert!(remote.is_err());
 
         // Peer only, no header
-        let remote = get_remote(&Some(true_remote), &empty_headers, &proxy_list, &log);
+        let remote = get_remote(
+            &Some(true_remote),
+            &empty_headers,
+            &proxy_list,
+            false,
+            &sources,
+            &log,
+        );
         assert_eq!(remote.unwrap(), "1.2.3.4".to_owned());
 
         headers.insert(
@@ -581,7 +1394,14 @@ mod test {
         );
 
         // Peer proxy, fetch from XFF header
-        let remote = get_remote(&Some(proxy_server), &headers, &proxy_list, &log);
+        let remote = get_remote(
+            &Some(proxy_server),
+            &headers,
+            &proxy_list,
+            false,
+            &sources,
+            &log,
+        );
         assert_eq!(remote.unwrap(), "1.2.3.4".to_owned());
 
         // Peer proxy, ensure right most XFF client fetched
@@ -590,7 +1410,14 @@ mod test {
             "1.2.3.4, 2.3.4.5".parse().unwrap(),
         );
 
-        let remote = get_remote(&Some(proxy_server), &headers, &proxy_list, &log);
+        let remote = get_remote(
+            &Some(proxy_server),
+            &headers,
+            &proxy_list,
+            false,
+            &sources,
+            &log,
+        );
         assert_eq!(remote.unwrap(), "2.3.4.5".to_owned());
 
         // Peer proxy, ensure right most non-proxy XFF client fetched
@@ -599,7 +1426,157 @@ mod test {
             "1.2.3.4, 2.3.4.5, 192.168.0.10".parse().unwrap(),
         );
 
-        let remote = get_remote(&Some(proxy_server), &headers, &proxy_list, &log);
+        let remote = get_remote(
+            &Some(proxy_server),
+            &headers,
+            &proxy_list,
+            false,
+            &sources,
+            &log,
+        );
         assert_eq!(remote.unwrap(), "2.3.4.5".to_owned());
     }
+
+    #[test]
+    fn test_trust_loopback() {
+        let proxy_list: Vec<IpNet> = vec!["192.168.0.0/24".parse().unwrap()];
+        let proxy_server: SocketAddr = "192.168.0.4:0".parse().unwrap();
+        let log = logging::MozLogger::new_human();
+        let sources = vec!["x-forwarded-for".to_owned(), "peer".to_owned()];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-forwarded-for"),
+            "1.2.3.4, 127.0.0.1".parse().unwrap(),
+        );
+
+        // Skipped by default
+        let remote = get_remote(
+            &Some(proxy_server),
+            &headers,
+            &proxy_list,
+            false,
+            &sources,
+            &log,
+        );
+        assert_eq!(remote.unwrap(), "1.2.3.4");
+        let remote = get_remote(
+            &Some(proxy_server),
+            &headers,
+            &proxy_list,
+            true,
+            &sources,
+            &log,
+        );
+        assert_eq!(remote.unwrap(), "127.0.0.1");
+
+        headers.insert(
+            HeaderName::from_static("x-forwarded-for"),
+            "::1".parse().unwrap(),
+        );
+        let remote = get_remote(
+            &Some(proxy_server),
+            &headers,
+            &proxy_list,
+            false,
+            &sources,
+            &log,
+        );
+        assert!(remote.is_err());
+        let remote = get_remote(
+            &Some(proxy_server),
+            &headers,
+            &proxy_list,
+            true,
+            &sources,
+            &log,
+        );
+        assert_eq!(remote.unwrap(), "::1");
+    }
+
+    #[test]
+    fn test_is_secure() {
+        let proxy_list: Vec<IpNet> = vec!["192.168.0.0/24".parse().unwrap()];
+        let proxy: Option<SocketAddr> = Some("192.168.0.4:0".parse().unwrap());
+        let client: Option<SocketAddr> = Some("1.2.3.4:0".parse().unwrap());
+        let mut headers = HeaderMap::new();
+
+        assert!(!is_secure(&proxy, &headers, &proxy_list));
+        headers.insert(
+            HeaderName::from_static("x-forwarded-proto"),
+            "https, http".parse().unwrap(),
+        );
+        assert!(is_secure(&proxy, &headers, &proxy_list));
+        // Only a trusted proxy can say so.
+        assert!(!is_secure(&client, &headers, &proxy_list));
+        assert!(!is_secure(&None, &headers, &proxy_list));
+        headers.insert(
+            HeaderName::from_static("x-forwarded-proto"),
+            "http".parse().unwrap(),
+        );
+        assert!(!is_secure(&proxy, &headers, &proxy_list));
+    }
+
+    #[test]
+    fn test_remote_ip_sources() {
+        let proxy_list: Vec<IpNet> = vec!["192.168.0.0/24".parse().unwrap()];
+        let proxy_server: SocketAddr = "192.168.0.4:0".parse().unwrap();
+        let true_remote: SocketAddr = "1.2.3.4:0".parse().unwrap();
+        let log = logging::MozLogger::new_human();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-forwarded-for"),
+            "2.3.4.5, 192.168.0.10".parse().unwrap(),
+        );
+        headers.insert(
+            HeaderName::from_static("cf-connecting-ip"),
+            "3.4.5.6".parse().unwrap(),
+        );
+        let sources = |list: &str| -> Vec<String> { list.split(',').map(str::to_owned).collect() };
+
+        for (order, expected) in [
+            ("cf-connecting-ip,x-forwarded-for,peer", "3.4.5.6"),
+            ("x-forwarded-for,cf-connecting-ip,peer", "2.3.4.5"),
+            // missing headers are skipped
+            ("x-client-ip,cf-connecting-ip", "3.4.5.6"),
+        ] {
+            let remote = get_remote(
+                &Some(proxy_server),
+                &headers,
+                &proxy_list,
+                false,
+                &sources(order),
+                &log,
+            );
+            assert_eq!(remote.unwrap(), expected, "{}", order);
+        }
+        // A proxy is never the remote.
+        let remote = get_remote(
+            &Some(proxy_server),
+            &headers,
+            &proxy_list,
+            false,
+            &sources("x-client-ip,peer"),
+            &log,
+        );
+        assert!(remote.is_err());
+        // Headers from a peer that isn't a known proxy are ignored.
+        let remote = get_remote(
+            &Some(true_remote),
+            &headers,
+            &proxy_list,
+            false,
+            &sources("cf-connecting-ip,peer"),
+            &log,
+        );
+        assert_eq!(remote.unwrap(), "1.2.3.4");
+        let remote = get_remote(
+            &Some(true_remote),
+            &headers,
+            &proxy_list,
+            false,
+            &sources("cf-connecting-ip"),
+            &log,
+        );
+        assert!(remote.is_err());
+    }
 }
@@ -1,43 +1,361 @@
 //! Metrics tie-ins
 //! This is a WIP.
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
 use std::net::UdpSocket;
+use std::panic::RefUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use cadence::{BufferedUdpMetricSink, NopMetricSink, QueuingMetricSink, StatsdClient};
+use cadence::{
+    BufferedUdpMetricSink, MetricSink, NopMetricSink, QueuingMetricSink, StatsdClient,
+    StatsdClientBuilder,
+};
 use slog::{error, info};
 
 use crate::error as c_error;
 use crate::logging;
 use crate::settings::Settings;
 
-/// Create a cadence StatsdClient from the given options
+const STATSD_PORT: u16 = 8125;
+
+/// Create a cadence StatsdClient from the given options, also keeping what
+/// it sends in `prometheus`, if given.
 pub fn metrics_from_opts(
     settings: &Settings,
     log: &logging::MozLogger,
+    prometheus: Option<&PrometheusRegistry>,
 ) -> Result<StatsdClient, c_error::HandlerError> {
     let builder = if !settings.statsd_host.is_empty() {
         let socket = UdpSocket::bind("0.0.0.0:0")?;
         socket.set_nonblocking(true)?;
-        let elements: Vec<&str> = settings.statsd_host.splitn(2, ':').collect();
-        let host: (&str, &str) = if elements.len() == 2 {
-            (elements[0], elements[1])
-        } else {
-            (settings.statsd_host.as_str(), "8529")
-        };
-        let port = host.1.parse::<u16>().unwrap_or(8529);
-        let udp_sink = BufferedUdpMetricSink::from((host.0, port), socket)?;
+        let (host, port) = statsd_addr(&settings.statsd_host);
+        let udp_sink = BufferedUdpMetricSink::from((host, port), socket)?;
         let sink = QueuingMetricSink::from(udp_sink);
         info!(log.log,
             "Establishing connection to Stat Server";
-            "server"=>host.0,
-            "port"=>host.1);
-        StatsdClient::builder(&settings.metric_name, sink)
+            "server"=>host,
+            "port"=>port);
+        let names = aggregated_names(settings);
+        if names.is_empty() {
+            builder(settings, sink, prometheus)
+        } else {
+            let sink = AggregatingMetricSink::new(sink, names);
+            let flusher = sink.clone();
+            let interval = Duration::from_secs(settings.aggregate_interval.max(1));
+            std::thread::spawn(move || loop {
+                std::thread::sleep(interval);
+                flusher.flush().ok();
+            });
+            builder(settings, sink, prometheus)
+        }
     } else {
         info!(log.log, "No Stat Server");
-        StatsdClient::builder(&settings.metric_name, NopMetricSink)
+        builder(settings, NopMetricSink, prometheus)
     };
     let mlog = log.log.clone();
     Ok(builder
         .with_error_handler(move |err| error!(mlog, "Could not start metrics: {:?}", err))
         .build())
 }
+
+/// The host and port in `statsd_host`, on the standard statsd port unless
+/// it names another.
+fn statsd_addr(statsd_host: &str) -> (&str, u16) {
+    match statsd_host.split_once(':') {
+        Some((host, port)) => (host, port.parse().unwrap_or(STATSD_PORT)),
+        None => (statsd_host, STATSD_PORT),
+    }
+}
+
+/// A client builder sending to `sink`, by way of `prometheus` if given.
+fn builder<T>(
+    settings: &Settings,
+    sink: T,
+    prometheus: Option<&PrometheusRegistry>,
+) -> StatsdClientBuilder
+where
+    T: MetricSink + Send + Sync + RefUnwindSafe + 'static,
+{
+    match prometheus {
+        Some(registry) => StatsdClient::builder(
+            &settings.metric_name,
+            PrometheusMetricSink {
+                inner: sink,
+                registry: registry.clone(),
+            },
+        ),
+        None => StatsdClient::builder(&settings.metric_name, sink),
+    }
+}
+
+/// The full names of the counters in `aggregate_metrics`.
+fn aggregated_names(settings: &Settings) -> HashSet<String> {
+    let prefix = settings.metric_name.trim_end_matches('.');
+    settings
+        .aggregate_metrics
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            if prefix.is_empty() {
+                name.to_owned()
+            } else {
+                format!("{}.{}", prefix, name)
+            }
+        })
+        .collect()
+}
+
+/// Sums the named counters, sending each total once per `flush` rather than
+/// a packet per increment. Everything else is sent straight through.
+pub struct AggregatingMetricSink<T> {
+    inner: Arc<T>,
+    names: Arc<HashSet<String>>,
+    // totals by name and the rest of the line after the value
+    counters: Arc<Mutex<HashMap<(String, String), i64>>>,
+}
+
+impl<T> Clone for AggregatingMetricSink<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            names: self.names.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+impl<T: MetricSink> AggregatingMetricSink<T> {
+    pub fn new(inner: T, names: HashSet<String>) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            names: Arc::new(names),
+            counters: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<T: MetricSink> MetricSink for AggregatingMetricSink<T> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        // e.g. "channelserver.conn.create:1|c|#secure:true"
+        if let Some((name, rest)) = metric.split_once(':') {
+            if let Some((value, kind)) = rest.split_once('|') {
+                let counter = kind == "c" || kind.starts_with("c|");
+                if counter && self.names.contains(name) {
+                    if let Ok(value) = value.parse::<i64>() {
+                        let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+                        *counters
+                            .entry((name.to_owned(), kind.to_owned()))
+                            .or_insert(0) += value;
+                        return Ok(0);
+                    }
+                }
+            }
+        }
+        self.inner.emit(metric)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let counters =
+            std::mem::take(&mut *self.counters.lock().unwrap_or_else(|e| e.into_inner()));
+        for ((name, kind), total) in counters {
+            self.inner.emit(&format!("{}:{}|{}", name, total, kind))?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// The latest counter totals and gauge values, for Prometheus to scrape.
+#[derive(Clone, Debug, Default)]
+pub struct PrometheusRegistry {
+    // by name and labels, e.g. `{secure="true"}`
+    series: Arc<Mutex<BTreeMap<(String, String), Series>>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Series {
+    Counter(i64),
+    Gauge(f64),
+}
+
+impl PrometheusRegistry {
+    /// Keep a metric sent to statsd, e.g. "channelserver.conn.create:1|c|#secure:true".
+    /// Only counters and gauges are kept.
+    fn record(&self, metric: &str) {
+        let Some((name, rest)) = metric.split_once(':') else {
+            return;
+        };
+        let mut fields = rest.split('|');
+        let (Some(value), Some(kind)) = (fields.next(), fields.next()) else {
+            return;
+        };
+        let tags = fields.find_map(|field| field.strip_prefix('#'));
+        let key = (prometheus_name(name), prometheus_labels(tags));
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        match (kind, series.get(&key)) {
+            ("c", Some(Series::Counter(total))) => {
+                let total = total + value.parse::<i64>().unwrap_or(0);
+                series.insert(key, Series::Counter(total));
+            }
+            ("c", _) => {
+                series.insert(key, Series::Counter(value.parse().unwrap_or(0)));
+            }
+            ("g", _) => {
+                if let Ok(value) = value.parse() {
+                    series.insert(key, Series::Gauge(value));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Everything kept so far, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        let mut text = String::new();
+        let mut last = String::new();
+        for ((name, labels), value) in series.iter() {
+            let (name, kind, value) = match value {
+                Series::Counter(total) => (format!("{}_total", name), "counter", total.to_string()),
+                Series::Gauge(value) => (name.clone(), "gauge", value.to_string()),
+            };
+            if name != last {
+                text.push_str(&format!("# TYPE {} {}\n", name, kind));
+            }
+            text.push_str(&format!("{}{} {}\n", name, labels, value));
+            last = name;
+        }
+        text
+    }
+}
+
+/// `name` with anything Prometheus doesn't allow in a name replaced by `_`.
+fn prometheus_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// statsd tags, e.g. "secure:true,existing", as Prometheus labels, e.g.
+/// `{secure="true",tag="existing"}`.
+fn prometheus_labels(tags: Option<&str>) -> String {
+    let labels: Vec<String> = tags
+        .unwrap_or_default()
+        .split(',')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| {
+            let (name, value) = tag.split_once(':').unwrap_or(("tag", tag));
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("{}=\"{}\"", prometheus_name(name), value)
+        })
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// Keeps everything sent in a `PrometheusRegistry` on its way to `inner`.
+pub struct PrometheusMetricSink<T> {
+    inner: T,
+    registry: PrometheusRegistry,
+}
+
+impl<T: MetricSink> MetricSink for PrometheusMetricSink<T> {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        self.registry.record(metric);
+        self.inner.emit(metric)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use cadence::{CountedExt, Gauged, SpyMetricSink, Timed};
+
+    #[test]
+    fn test_aggregate() {
+        let (rx, spy) = SpyMetricSink::new();
+        let names = ["test.conn.create".to_owned()].into_iter().collect();
+        let sink = AggregatingMetricSink::new(spy, names);
+        let metrics = StatsdClient::from_sink("test", sink.clone());
+        for _ in 0..5 {
+            metrics.incr("conn.create").ok();
+        }
+        metrics
+            .incr_with_tags("conn.create")
+            .with_tag("secure", "true")
+            .send();
+        metrics.incr("conn.expired").ok();
+        let sent = |rx: &crossbeam_channel::Receiver<Vec<u8>>| -> Vec<String> {
+            let mut sent: Vec<String> = rx
+                .try_iter()
+                .map(|m| String::from_utf8(m).unwrap())
+                .collect();
+            sent.sort();
+            sent
+        };
+        // only what isn't aggregated is sent right away
+        assert_eq!(sent(&rx), vec!["test.conn.expired:1|c"]);
+
+        sink.flush().unwrap();
+        assert_eq!(
+            sent(&rx),
+            vec!["test.conn.create:1|c|#secure:true", "test.conn.create:5|c"]
+        );
+        // and the totals start over
+        sink.flush().unwrap();
+        assert!(sent(&rx).is_empty());
+    }
+
+    #[test]
+    fn test_statsd_addr() {
+        assert_eq!(statsd_addr("statsd"), ("statsd", 8125));
+        assert_eq!(statsd_addr("statsd:9999"), ("statsd", 9999));
+        assert_eq!(statsd_addr("statsd:bogus"), ("statsd", 8125));
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let (rx, spy) = SpyMetricSink::new();
+        let registry = PrometheusRegistry::default();
+        let settings = Settings {
+            metric_name: "pair".to_owned(),
+            ..Default::default()
+        };
+        let metrics = builder(&settings, spy, Some(&registry)).build();
+        metrics.incr("conn.create").ok();
+        metrics.incr("conn.create").ok();
+        metrics
+            .incr_with_tags("conn.request")
+            .with_tag_value("existing")
+            .send();
+        metrics.gauge("channels.active", 3).ok();
+        metrics.gauge("channels.active", 2).ok();
+        metrics.time("conn.rtt_ms", 12).ok();
+        // still sent to statsd
+        assert_eq!(rx.try_iter().count(), 6);
+
+        assert_eq!(
+            registry.render(),
+            "# TYPE pair_channels_active gauge\n\
+             pair_channels_active 2\n\
+             # TYPE pair_conn_create_total counter\n\
+             pair_conn_create_total 2\n\
+             # TYPE pair_conn_request_total counter\n\
+             pair_conn_request_total{tag=\"existing\"} 1\n"
+        );
+        assert_eq!(
+            prometheus_labels(Some(r#"secure:true,type:"new""#)),
+            r#"{secure="true",type="\"new\""}"#
+        );
+    }
+}
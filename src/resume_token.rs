@@ -0,0 +1,74 @@
+//! Let a participant that dropped reclaim its place in a channel.
+//!
+//! A token is `<slot>.<signature>`, where the slot identifies the participant
+//! within the channel, and the signature is the URL safe base64 HMAC-SHA256 of
+//! the channel id, the channel's correlation id and the slot, keyed with
+//! `resume_token_secret`. A channel gets a fresh correlation id each time it's
+//! created, so a token is only good for as long as its channel lasts.
+use base64::Engine;
+use uuid::Uuid;
+
+use crate::channel_token::{sign, verify_signature};
+
+/// A token for the participant `slot` in the channel, or `None` if tokens
+/// aren't enabled.
+pub fn issue(channel: &str, correlation_id: &Uuid, slot: &Uuid, secret: &str) -> Option<String> {
+    if secret.is_empty() {
+        return None;
+    }
+    let signature = sign(&payload(channel, correlation_id, slot), secret)?;
+    Some(format!(
+        "{}.{}",
+        slot.simple(),
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(signature)
+    ))
+}
+
+/// The participant slot a token was issued for, returning `None` if it wasn't
+/// issued for this channel with `secret`.
+pub fn verify(token: &str, channel: &str, correlation_id: &Uuid, secret: &str) -> Option<Uuid> {
+    if secret.is_empty() {
+        return None;
+    }
+    let (slot, signature) = token.split_once('.')?;
+    let slot = Uuid::try_parse(slot).ok()?;
+    if !verify_signature(&payload(channel, correlation_id, &slot), signature, secret) {
+        return None;
+    }
+    Some(slot)
+}
+
+fn payload(channel: &str, correlation_id: &Uuid, slot: &Uuid) -> String {
+    format!("{}.{}.{}", channel, correlation_id.simple(), slot.simple())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let channel = "Ck5ZMEotWCcmmXbJYkiGyw";
+        let correlation_id = Uuid::new_v4();
+        let slot = Uuid::new_v4();
+        let token = issue(channel, &correlation_id, &slot, "s3cret").unwrap();
+        assert_eq!(
+            verify(&token, channel, &correlation_id, "s3cret"),
+            Some(slot)
+        );
+        assert_eq!(verify(&token, channel, &correlation_id, "guess"), None);
+        assert_eq!(verify(&token, "elsewhere", &correlation_id, "s3cret"), None);
+        // the channel was closed and opened again
+        assert_eq!(verify(&token, channel, &Uuid::new_v4(), "s3cret"), None);
+
+        // tampered with
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", Uuid::new_v4().simple(), signature);
+        assert_eq!(verify(&forged, channel, &correlation_id, "s3cret"), None);
+        assert_eq!(verify("garbage", channel, &correlation_id, "s3cret"), None);
+
+        // disabled
+        assert_eq!(issue(channel, &correlation_id, &slot, ""), None);
+        assert_eq!(verify(&token, channel, &correlation_id, ""), None);
+    }
+}
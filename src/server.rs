@@ -1,41 +1,102 @@
 //! `ChannelServer` is an actor. It maintains list of connection client session.
 //! And manages available channels. Peers send messages to other peers in same
 //! channels through `ChannelServer`.
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, BTreeSet, HashMap, HashSet};
 use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use actix::prelude::{Actor, Context, Handler, Message, MessageResult, Recipient};
-use cadence::{CountedExt, StatsdClient};
-use rand::{self, rngs::ThreadRng, Rng};
+use actix::prelude::{
+    Actor, AsyncContext, Context, Handler, Message, MessageResponse, MessageResult, Recipient,
+    SendError,
+};
+use cadence::{CountedExt, Gauged, StatsdClient, Timed};
+use ipnet::IpNet;
 use serde::Serialize;
 use serde_json::json;
-use slog::{debug, error, trace, warn};
+use slog::{debug, error, info, trace, warn};
+use uuid::Uuid;
 
+use crate::channel_token::ChannelLimits;
 use crate::channelid::ChannelID;
+use crate::closed::ClosedChannels;
 use crate::error as perror;
+use crate::ip_rate_limit::IPReputation;
 use crate::logging;
 use crate::logging::MozLogger;
 use crate::meta;
+use crate::resume_token;
 use crate::settings::Settings;
 
-pub const EOL: &str = "\x04";
-
 #[derive(Serialize, Debug, Eq, PartialEq)]
 pub enum MessageType {
     Text,
     Terminate,
+    /// Terminate, telling the client to reconnect elsewhere
+    Drain,
+    /// Terminate, the channel was evicted to make room for a new one
+    Evict,
+    /// Sent by the server about the channel, rather than relayed from a peer
+    Control,
 }
 
 /// New session is created
 #[derive(Message)]
-#[rtype(usize)]
+#[rtype(result = "ConnectResult")]
 pub struct Connect {
     pub addr: Recipient<TextMessage>,
     pub channel: ChannelID,
     pub remote: Option<String>,
+    pub country: Option<String>,
     pub initial_connect: bool,
+    /// who is creating the channel, per `integration_header`
+    pub integration: Option<String>,
+    /// limits for the channel, if this connection creates it
+    pub limits: ChannelLimits,
+    /// the major version of the client, if it gave one
+    pub protocol: Option<u64>,
+    /// octets queued for the session
+    pub buffered: Buffered,
+    /// the creating client's own `X-Correlation-Id`, if accepted
+    pub client_correlation_id: Option<String>,
+    /// a token to reclaim a dropped participant's place, per `resume_token_secret`
+    pub resume: Option<String>,
+}
+
+/// Whether a session was let into its channel, and if not, why.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, MessageResponse)]
+pub enum ConnectResult {
+    /// Joined, with the new session's id
+    Ok(SessionId),
+    /// The channel already has `max_channel_connections`
+    ChannelFull,
+    /// The channel doesn't exist, and this connection may not create it
+    UnknownChannel,
+    /// The channel's principals are connected, and this isn't from one of them
+    Untrusted,
+    /// A server wide limit, such as `max_sessions`, was reached
+    AtCapacity,
+    /// The client's version doesn't match the channel's, per `match_versions`
+    VersionMismatch,
+    /// The channel is being drained, and takes no one new
+    Draining,
+}
+
+impl ConnectResult {
+    /// Why the session was refused, as recorded in the audit log.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ConnectResult::Ok(_) => "accepted",
+            ConnectResult::ChannelFull => "channel_full",
+            ConnectResult::UnknownChannel => "unknown_channel",
+            ConnectResult::Untrusted => "untrusted",
+            ConnectResult::AtCapacity => "at_capacity",
+            ConnectResult::VersionMismatch => "version_mismatch",
+            ConnectResult::Draining => "draining",
+        }
+    }
 }
 
 /// Session is disconnected
@@ -47,11 +108,18 @@ pub struct Disconnect {
     pub reason: DisconnectReason,
 }
 
-#[derive(Serialize, Debug, Eq, PartialEq, PartialOrd)]
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq, PartialOrd)]
 pub enum DisconnectReason {
     None,
     _ConnectionError,
     Timeout,
+    MaxAge,
+    ExcessData,
+    ExcessMessages,
+    Drained,
+    MailboxOverflow,
+    Evicted,
+    Swept,
 }
 
 impl fmt::Display for DisconnectReason {
@@ -63,6 +131,13 @@ impl fmt::Display for DisconnectReason {
                 DisconnectReason::None => "Client Disconnect",
                 DisconnectReason::_ConnectionError => "Connection Error",
                 DisconnectReason::Timeout => "Connection Timeout",
+                DisconnectReason::MaxAge => "Channel Max Age",
+                DisconnectReason::ExcessData => "Excess Data",
+                DisconnectReason::ExcessMessages => "Excess Messages",
+                DisconnectReason::Drained => "Channel Drained",
+                DisconnectReason::MailboxOverflow => "Mailbox Overflow",
+                DisconnectReason::Evicted => "Channel Evicted",
+                DisconnectReason::Swept => "Channel Swept",
             }
         )
     }
@@ -75,6 +150,93 @@ type SessionId = usize;
 #[rtype(result = "()")]
 pub struct TextMessage(pub MessageType, pub String);
 
+/// What a frame sent to a client is about, unless `legacy_envelope` is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameType {
+    /// Relayed from a peer
+    Message,
+    /// About the channel or the client's own connection
+    Control,
+    /// Why the server is refusing or closing the connection
+    Error,
+    /// Who is in the channel
+    Presence,
+}
+
+impl FrameType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FrameType::Message => "message",
+            FrameType::Control => "control",
+            FrameType::Error => "error",
+            FrameType::Presence => "presence",
+        }
+    }
+}
+
+/// `payload` wrapped in the envelope every frame to a client shares,
+/// `{"type":..,"payload":..}`, which the session numbers with `seq` as it
+/// sends it. When `legacy_envelope` is set, `payload` is sent as is.
+pub fn frame(settings: &Settings, frame_type: FrameType, payload: serde_json::Value) -> String {
+    if settings.legacy_envelope {
+        return payload.to_string();
+    }
+    json!({ "type": frame_type.as_str(), "payload": payload }).to_string()
+}
+
+impl TextMessage {
+    /// Control message telling a session to close. The message type carries
+    /// the intent, so nothing is sent in band.
+    pub fn terminate() -> Self {
+        TextMessage(MessageType::Terminate, String::new())
+    }
+
+    /// Control message telling a session to close because the server is
+    /// draining, rather than because anything went wrong.
+    pub fn drain() -> Self {
+        TextMessage(MessageType::Drain, String::new())
+    }
+
+    /// Control message telling a session to close because its channel was
+    /// evicted, per `evict_on_full`.
+    pub fn evict() -> Self {
+        TextMessage(MessageType::Evict, String::new())
+    }
+
+    /// Server event about the channel, such as a peer arriving.
+    pub fn event(event: &str, settings: &Settings) -> Self {
+        let event = frame(settings, FrameType::Presence, json!({ "event": event }));
+        TextMessage(MessageType::Control, event)
+    }
+}
+
+/// Requests a client may make of the server instead of relaying a message.
+#[derive(Debug, PartialEq)]
+pub enum ControlRequest {
+    /// Return the session's transfer stats
+    Stats,
+    /// Pass the features the client supports along to its peers
+    Caps(Vec<serde_json::Value>),
+}
+
+impl ControlRequest {
+    /// Recognize a control request in a client message.
+    pub fn parse(msg: &str) -> Option<Self> {
+        // Relayed messages are base64 blocks, so don't bother parsing those.
+        if !msg.starts_with('{') {
+            return None;
+        }
+        let value: serde_json::Value = serde_json::from_str(msg).ok()?;
+        match value.get("type")?.as_str()? {
+            "stats" => Some(ControlRequest::Stats),
+            "caps" => Some(ControlRequest::Caps(
+                value.get("features")?.as_array()?.clone(),
+            )),
+            _ => None,
+        }
+    }
+}
+
 /// Send message to specific room
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -89,6 +251,131 @@ pub struct ClientMessage {
     pub channel: ChannelID,
     /// Sender info
     pub sender: meta::SenderData,
+    /// When the session received the message
+    pub received: Instant,
+}
+
+/// A channel and the sessions participating in it.
+#[derive(Clone, Debug)]
+pub struct ChannelGroup {
+    /// Identifies the channel in events without exposing the channel id
+    pub correlation_id: Uuid,
+    /// When the channel was first created
+    pub created: Instant,
+    /// When a message was last relayed through the channel
+    pub last_active: Instant,
+    /// Sessions connected to the channel
+    pub participants: Channels,
+    /// Most participants connected at once
+    pub peak_participants: usize,
+    /// Messages relayed through the channel
+    pub msg_count: usize,
+    /// Octets relayed through the channel
+    pub data_exchanged: usize,
+    /// Countries participants connected from
+    pub countries: BTreeSet<String>,
+    /// Latest message relayed, when `snapshot_mode` is on
+    pub snapshot: Option<String>,
+    /// The integration that created the channel
+    pub integration: Option<String>,
+    /// Limits in place of the server wide ones
+    pub limits: ChannelLimits,
+    /// Has a participant come near `max_data`?
+    pub near_data_limit: bool,
+    /// Has a participant come near `max_exchanges`?
+    pub near_msg_limit: bool,
+    /// Participants that recently dropped, and when, per `reconnect_coalesce_ms`
+    pub departed: Vec<(Instant, Channel)>,
+    /// The major client version of the first participant to give one
+    pub protocol: Option<u64>,
+    /// The creating client's own `X-Correlation-Id`, per `accept_correlation_id`
+    pub client_correlation_id: Option<String>,
+    /// When a sweep first found no participant still connected, per `idle_grace`
+    pub vacant_since: Option<Instant>,
+    /// Is the channel waiting out a `DrainChannel` grace period?
+    pub draining: bool,
+}
+
+impl Default for ChannelGroup {
+    fn default() -> Self {
+        Self {
+            correlation_id: Uuid::new_v4(),
+            created: Instant::now(),
+            last_active: Instant::now(),
+            participants: HashMap::new(),
+            peak_participants: 0,
+            msg_count: 0,
+            data_exchanged: 0,
+            countries: BTreeSet::new(),
+            snapshot: None,
+            integration: None,
+            limits: ChannelLimits::default(),
+            near_data_limit: false,
+            near_msg_limit: false,
+            departed: Vec::new(),
+            protocol: None,
+            client_correlation_id: None,
+            vacant_since: None,
+            draining: false,
+        }
+    }
+}
+
+impl ChannelGroup {
+    /// Remember a participant that dropped, so a quick reconnect from the same
+    /// address can pick up where it left off.
+    fn depart(&mut self, party: Channel, window: Duration) {
+        self.departed.retain(|(left, _)| left.elapsed() < window);
+        self.departed.push((Instant::now(), party));
+    }
+
+    /// The participant from `remote` that dropped within `window`, if any.
+    fn rejoin(&mut self, remote: &Option<String>, window: Duration) -> Option<Channel> {
+        self.departed.retain(|(left, _)| left.elapsed() < window);
+        let remote = remote.as_ref()?;
+        let index = self
+            .departed
+            .iter()
+            .rposition(|(_, party)| party.remote.as_ref() == Some(remote))?;
+        Some(self.departed.remove(index).1)
+    }
+
+    /// The participant in `slot` that dropped within `window`, if any.
+    fn reclaim(&mut self, slot: &Uuid, window: Duration) -> Option<Channel> {
+        self.departed.retain(|(left, _)| left.elapsed() < window);
+        let index = self
+            .departed
+            .iter()
+            .rposition(|(_, party)| &party.correlation_id == slot)?;
+        Some(self.departed.remove(index).1)
+    }
+
+    /// `log`, noting the creating client's correlation id if it gave one.
+    pub fn log(&self, log: &slog::Logger) -> slog::Logger {
+        match &self.client_correlation_id {
+            Some(id) => log.new(slog::o!("client_correlation_id" => id.clone())),
+            None => log.clone(),
+        }
+    }
+
+    /// Describe the channel's lifetime for analytics once it closes. This
+    /// should not contain anything that identifies the channel or its participants.
+    pub fn closed_event(&self, reason: &DisconnectReason) -> serde_json::Value {
+        let mut event = json!({
+            "event": "channel_closed",
+            "correlation_id": self.correlation_id.to_string(),
+            "duration_ms": self.created.elapsed().as_millis() as u64,
+            "messages": self.msg_count,
+            "bytes": self.data_exchanged,
+            "peak_participants": self.peak_participants,
+            "countries": self.countries,
+            "reason": reason.to_string(),
+        });
+        if let Some(id) = &self.client_correlation_id {
+            event["client_correlation_id"] = id.as_str().into();
+        }
+        event
+    }
 }
 
 #[derive(Eq, PartialEq, Clone, Debug)]
@@ -97,30 +384,299 @@ pub struct Channel {
     pub started: Instant,
     pub msg_count: u8,
     pub data_exchanged: usize,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub control_count: usize,
+    /// Identifies the session in logs, kept across coalesced reconnects
+    pub correlation_id: Uuid,
+    pub remote: Option<String>,
+}
+
+/// Outcome of handing a message to a session.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Delivery {
+    Sent,
+    /// The session has gone away
+    Gone,
+    /// The session's mailbox was full, so it is falling behind
+    Backlogged,
+    /// The session's mailbox was full and the message was dropped
+    Overflowed,
+}
+
+/// Send a message to a session without waiting for it to be handled.
+///
+/// A session that has gone away is expected, since it may not have finished
+/// disconnecting, but a full mailbox means the session can't keep up. Unless
+/// `shed` is set, the message is still queued in that case, so nothing is lost.
+pub fn deliver(
+    addr: &SessionAddr,
+    msg: TextMessage,
+    metrics: &StatsdClient,
+    shed: bool,
+) -> Delivery {
+    match addr.try_send(msg) {
+        Ok(()) => Delivery::Sent,
+        Err(SendError::Closed(_)) => Delivery::Gone,
+        Err(SendError::Full(_)) if shed => {
+            metrics.incr("conn.mailbox_overflow").ok();
+            Delivery::Overflowed
+        }
+        Err(SendError::Full(msg)) => {
+            metrics.incr("relay.backpressure").ok();
+            addr.do_send(msg);
+            Delivery::Backlogged
+        }
+    }
+}
+
+/// Octets queued for a session that it has not yet handled.
+///
+/// Like `Backlog`, the server adds to it as it queues messages for the session,
+/// and the session takes away once it picks each one up.
+#[derive(Clone, Debug, Default)]
+pub struct Buffered(Arc<AtomicUsize>);
+
+impl Buffered {
+    pub fn add(&self, len: usize) {
+        self.0.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, len: usize) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                Some(v.saturating_sub(len))
+            });
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A connected session's mailbox, and how much is queued in it.
+#[derive(Clone)]
+pub struct SessionAddr {
+    pub addr: Recipient<TextMessage>,
+    pub buffered: Buffered,
+}
+
+impl SessionAddr {
+    pub fn try_send(&self, msg: TextMessage) -> Result<(), SendError<TextMessage>> {
+        let len = msg.1.len();
+        self.addr.try_send(msg)?;
+        self.buffered.add(len);
+        Ok(())
+    }
+
+    pub fn do_send(&self, msg: TextMessage) {
+        self.buffered.add(msg.1.len());
+        self.addr.do_send(msg);
+    }
+}
+
+impl From<Recipient<TextMessage>> for SessionAddr {
+    fn from(addr: Recipient<TextMessage>) -> Self {
+        Self {
+            addr,
+            buffered: Buffered::default(),
+        }
+    }
+}
+
+/// Count of messages sent to the `ChannelServer` that have not yet been handled.
+///
+/// Actix does not expose the depth of an actor's mailbox, so senders `enter()`
+/// before dispatching a message and the handler `leave()`s once it picks it up.
+#[derive(Clone, Debug, Default)]
+pub struct Backlog(Arc<AtomicUsize>);
+
+impl Backlog {
+    pub fn enter(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn leave(&self) {
+        // Messages may arrive from senders that don't track the backlog.
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1));
+    }
+
+    pub fn pending(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether the server should be sent new connections.
+///
+/// Not ready until startup has finished, and no longer ready once draining.
+#[derive(Clone, Debug, Default)]
+pub struct Readiness(Arc<AtomicBool>);
+
+impl Readiness {
+    pub fn set(&self, ready: bool) {
+        self.0.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Gracefully close a channel.
+///
+/// Participants are told the channel is draining, and how many seconds to wait
+/// before retrying on a fresh channel, then disconnected after the grace period.
+/// Returns whether the channel existed.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct DrainChannel(pub ChannelID, pub Duration);
+
+/// Gracefully close every channel, as `DrainChannel` does.
+///
+/// With no grace period the channels are closed immediately.
+#[derive(Debug)]
+pub struct DrainAll(pub Duration);
+
+impl actix::Message for DrainAll {
+    type Result = DrainReport;
+}
+
+/// What a `DrainAll` affected.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct DrainReport {
+    /// channels drained
+    pub channels: usize,
+    /// sessions in those channels
+    pub sessions: usize,
+    /// sessions that could not be sent the drain notice
+    pub failed: usize,
+}
+
+/// Set aside a channel id for clients to connect to within `preallocate_ttl`.
+#[derive(Message)]
+#[rtype(result = "PreallocateResult")]
+pub struct Preallocate {
+    pub channel: ChannelID,
+    /// the address asking for it, counted against `max_connections_per_subnet`
     pub remote: Option<String>,
 }
 
+/// Whether a channel id was set aside, and if not, why.
+#[derive(Debug, Clone, Eq, PartialEq, MessageResponse)]
+pub enum PreallocateResult {
+    /// Set aside, with the welcome message for the channel
+    Ok(String),
+    /// The id is already in use
+    Taken,
+    /// `max_channels`, or `max_connections_per_subnet`, was reached
+    AtCapacity,
+}
+
+/// A channel id set aside by `Preallocate`.
+struct Reservation {
+    /// when it's let go if no one has connected
+    expires: Instant,
+    /// the subnet it was asked for from, per `max_connections_per_subnet`
+    subnet: Option<IpNet>,
+}
+
+/// How many sessions are currently in a channel.
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct ChannelInfo(pub ChannelID);
+
+/// Whether a channel exists, and if it has room for another participant.
+#[derive(Message)]
+#[rtype(result = "ChannelStatusReport")]
+pub struct ChannelStatus(pub ChannelID);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, MessageResponse)]
+pub struct ChannelStatusReport {
+    /// The channel is open, or preallocated and waiting for its first client
+    pub exists: bool,
+    /// Sessions connected
+    pub participants: usize,
+    /// The channel already has `max_channel_connections`
+    pub full: bool,
+}
+
+/// How busy the server is, for load balancers.
+#[derive(Message)]
+#[rtype(result = "Load")]
+pub struct GetLoad;
+
+#[derive(Debug, Clone, Copy, Serialize, MessageResponse)]
+pub struct Load {
+    /// Sessions connected
+    pub sessions: usize,
+    /// Channels open
+    pub channels: usize,
+}
+
 /// List of available rooms
-pub struct ListChannels;
+///
+/// Channels are ordered by id so that callers can page through them with
+/// `offset` and `limit`. Without a `limit` all remaining channels are returned.
+#[derive(Debug, Default)]
+pub struct ListChannels {
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
 
 impl actix::Message for ListChannels {
-    type Result = Vec<ChannelID>;
+    type Result = Vec<ChannelSummary>;
+}
+
+/// A channel's occupancy, as reported by `ListChannels`.
+#[derive(Debug, Serialize)]
+pub struct ChannelSummary {
+    pub channelid: ChannelID,
+    /// Sessions connected
+    pub participants: usize,
+    /// Seconds since the channel was created
+    pub age: u64,
+    /// Octets relayed through the channel
+    pub bytes: usize,
 }
 
 /// `ChannelServer` manages channels and is responsible for coordinating
 /// sessions.
 pub struct ChannelServer {
     // collections of sessions grouped by channel
-    channels: HashMap<ChannelID, Channels>,
+    channels: HashMap<ChannelID, ChannelGroup>,
     // individual connections
-    sessions: HashMap<SessionId, Recipient<TextMessage>>,
-    // random number generator
-    rng: ThreadRng,
+    sessions: HashMap<SessionId, SessionAddr>,
+    // the session id handed out last
+    last_session_id: SessionId,
     // logging object
     pub log: MozLogger,
     // configuration options
     pub settings: Settings,
     pub metrics: Arc<StatsdClient>,
+    // messages queued for this actor
+    pub backlog: Backlog,
+    // whether to accept new connections
+    pub readiness: Readiness,
+    // analytics event logging
+    events: Option<MozLogger>,
+    // channels that have recently been shut down
+    closed: ClosedChannels,
+    // remote addresses seen in the current `distinct_ip_window`
+    distinct_ips: HashSet<String>,
+    // sessions connected from each subnet, when `max_connections_per_subnet` is set
+    subnets: HashMap<IpNet, usize>,
+    // channels allocated by `Preallocate`, until their first client connects
+    preallocated: HashMap<ChannelID, Reservation>,
+    // last `ts` added to a relayed message
+    last_ts: u64,
+    // open channels created by each integration
+    integrations: HashMap<String, usize>,
+    // checks addresses and reports quota violators, if `ip_reputation_server`
+    // is set
+    pub iprep: Option<Arc<IPReputation>>,
 }
 
 impl ChannelServer {
@@ -134,28 +690,329 @@ impl ChannelServer {
         Self {
             sessions: HashMap::new(),
             channels: HashMap::new(),
-            rng: ThreadRng::default(),
+            last_session_id: 0,
             log: log.clone(),
             settings: settings.clone(),
             metrics: metrics.clone(),
+            backlog: Backlog::default(),
+            readiness: Readiness::default(),
+            events: if settings.emit_events {
+                Some(MozLogger::new_json())
+            } else {
+                None
+            },
+            closed: ClosedChannels::new(
+                settings.closed_lru_size,
+                Duration::from_secs(settings.closed_ttl),
+                Duration::from_secs(settings.closed_abuse_ttl),
+            ),
+            distinct_ips: HashSet::new(),
+            subnets: HashMap::new(),
+            preallocated: HashMap::new(),
+            last_ts: 0,
+            integrations: HashMap::new(),
+            iprep: IPReputation::new(settings, log, &metrics).map(Arc::new),
+        }
+    }
+
+    /// Report an address that tripped a channel quota to iprepd, without
+    /// waiting for it to answer.
+    fn report_violator(&self, remote: &str) {
+        if !self.settings.iprepd_report_violators {
+            return;
+        }
+        let (Some(iprep), Some(remote)) = (&self.iprep, meta::strip_port(remote)) else {
+            return;
+        };
+        let iprep = iprep.clone();
+        actix::spawn(async move {
+            // Failures are logged by `add_abuser`.
+            iprep.add_abuser(&remote.to_string()).await.ok();
+        });
+    }
+
+    /// The subnet a remote address counts against for
+    /// `max_connections_per_subnet`.
+    fn subnet(&self, remote: &Option<String>) -> Option<IpNet> {
+        if self.settings.max_connections_per_subnet == 0 {
+            return None;
+        }
+        let addr: IpAddr = remote.as_ref()?.parse().ok()?;
+        let prefix = match addr {
+            IpAddr::V4(_) => self.settings.subnet_prefix_v4,
+            IpAddr::V6(_) => self.settings.subnet_prefix_v6,
+        };
+        IpNet::new(addr, prefix).ok().map(|net| net.trunc())
+    }
+
+    /// Reservations not yet expired, dropping the rest.
+    fn reservations(&mut self) -> usize {
+        let now = Instant::now();
+        self.preallocated
+            .retain(|_, reservation| reservation.expires > now);
+        self.preallocated.len()
+    }
+
+    /// A session from `remote` has left.
+    fn release_subnet(&mut self, remote: &Option<String>) {
+        if let Some(subnet) = self.subnet(remote) {
+            if let Entry::Occupied(mut entry) = self.subnets.entry(subnet) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    /// Build the welcome message telling the client what their channel is.
+    fn welcome(&self, channel: &ChannelID, resume: Option<String>) -> serde_json::Value {
+        let chan_id = if self.settings.pad_channel_links {
+            channel.as_padded_string()
+        } else {
+            channel.as_string()
+        };
+        let mut welcome = json!({ "link": format!("{}/v1/ws/{}", self.settings.public_path(), chan_id),
+                "channelid": chan_id });
+        if let Some(token) = resume {
+            welcome["resume"] = token.into();
+        }
+        welcome
+    }
+
+    /// Reply to a session with its own transfer stats.
+    fn send_stats(&mut self, channel: &ChannelID, id: SessionId) {
+        let party = match self.channels.get(channel) {
+            Some(group) => group.participants.get(&id),
+            None => None,
+        };
+        if let (Some(party), Some(addr)) = (party, self.sessions.get(&id)) {
+            let stats = json!({
+                "sent": party.bytes_sent,
+                "received": party.bytes_received,
+                "messages": party.msg_count,
+            });
+            let stats = frame(&self.settings, FrameType::Control, stats);
+            let msg = TextMessage(MessageType::Text, stats);
+            match deliver(addr, msg, &self.metrics, self.shed()) {
+                Delivery::Gone => self.prune(channel, id),
+                Delivery::Overflowed => {
+                    self.disconnect(channel, id, DisconnectReason::MailboxOverflow)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Pass a session's capabilities along to the rest of its channel. This
+    /// doesn't count against the channel's message or data limits.
+    fn send_caps(&mut self, channel: &ChannelID, id: SessionId, features: Vec<serde_json::Value>) {
+        let caps = json!({"type": "caps", "features": features});
+        let caps = frame(&self.settings, FrameType::Control, caps);
+        let peers: Vec<SessionId> = match self.channels.get(channel) {
+            Some(group) => group
+                .participants
+                .keys()
+                .filter(|pid| **pid != id)
+                .copied()
+                .collect(),
+            None => return,
+        };
+        for pid in peers {
+            if let Some(addr) = self.sessions.get(&pid) {
+                let msg = TextMessage(MessageType::Control, caps.clone());
+                match deliver(addr, msg, &self.metrics, self.shed()) {
+                    Delivery::Gone => self.prune(channel, pid),
+                    Delivery::Overflowed => {
+                        self.disconnect(channel, pid, DisconnectReason::MailboxOverflow)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Count a control request from a session against `max_control_messages`.
+    ///
+    /// Returns whether the request should be answered.
+    fn control_allowed(&mut self, channel: &ChannelID, id: SessionId) -> bool {
+        let max = self.settings.max_control_messages;
+        let party = match self
+            .channels
+            .get_mut(channel)
+            .and_then(|group| group.participants.get_mut(&id))
+        {
+            Some(party) => party,
+            None => return false,
+        };
+        party.control_count += 1;
+        if max > 0 && party.control_count > max {
+            if party.control_count == max + 1 {
+                warn!(
+                    self.log.log,
+                    "Too many control messages, ignoring";
+                    "session" => id,
+                    "remote_ip" => &party.remote,
+                );
+            }
+            self.metrics.incr("conn.control_flood").ok();
+            return false;
+        }
+        true
+    }
+
+    /// Milliseconds since the epoch to stamp a relayed message with.
+    ///
+    /// This never goes backwards, even if the system clock does.
+    fn timestamp(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.last_ts = self.last_ts.max(now);
+        self.last_ts
+    }
+
+    /// Tell a channel's participants it is about to close.
+    ///
+    /// Returns how many participants there are and how many of them couldn't
+    /// be told, or `None` if there's no such channel.
+    fn notify_drain(&mut self, channel: &ChannelID, grace: Duration) -> Option<(usize, usize)> {
+        let group = self.channels.get_mut(channel)?;
+        group.draining = true;
+        let group = &self.channels[channel];
+        info!(
+            self.log.log,
+            "Draining channel";
+            "channel" => &channel.as_string(),
+            "grace" => grace.as_secs(),
+        );
+        let notice = json!({"type": "draining", "retry_after": grace.as_secs()});
+        let notice = frame(&self.settings, FrameType::Control, notice);
+        let mut failed = 0;
+        for id in group.participants.keys() {
+            let delivered = self.sessions.get(id).map(|addr| {
+                let msg = TextMessage(MessageType::Text, notice.clone());
+                deliver(addr, msg, &self.metrics, false)
+            });
+            if !matches!(delivered, Some(Delivery::Sent | Delivery::Backlogged)) {
+                failed += 1;
+            }
+        }
+        self.metrics.incr("channel.drain").ok();
+        Some((group.participants.len(), failed))
+    }
+
+    /// Should messages to a session with a full mailbox be dropped?
+    fn shed(&self) -> bool {
+        self.settings.session_mailbox_cap > 0
+    }
+
+    /// Report how many distinct remote addresses connected during the last
+    /// window, and start a new one.
+    fn report_distinct_ips(&mut self) {
+        self.metrics
+            .gauge("conn.distinct_ips", self.distinct_ips.len() as u64)
+            .ok();
+        self.distinct_ips.clear();
+    }
+
+    /// Octets queued across all sessions.
+    fn total_buffered(&self) -> usize {
+        self.sessions
+            .values()
+            .map(|session| session.buffered.bytes())
+            .sum()
+    }
+
+    /// Drop the sessions with the most queued for them, until the total is
+    /// back within `max_total_buffered_bytes`.
+    fn shed_buffered(&mut self) {
+        let max = self.settings.max_total_buffered_bytes;
+        let mut total = self.total_buffered();
+        if max == 0 || total <= max {
+            return;
+        }
+        let mut slowest: Vec<(usize, ChannelID, SessionId)> = self
+            .channels
+            .iter()
+            .flat_map(|(channel, group)| {
+                group.participants.keys().filter_map(|id| {
+                    let session = self.sessions.get(id)?;
+                    Some((session.buffered.bytes(), *channel, *id))
+                })
+            })
+            .collect();
+        slowest.sort_by_key(|(bytes, _, _)| std::cmp::Reverse(*bytes));
+        for (bytes, channel, id) in slowest {
+            if total <= max {
+                break;
+            }
+            warn!(
+                self.log.log,
+                "Too much buffered, dropping session {}", id;
+                "buffered" => bytes,
+                "total_buffered" => total,
+            );
+            self.metrics.incr("buffer.shed").ok();
+            self.disconnect(&channel, id, DisconnectReason::MailboxOverflow);
+            total = total.saturating_sub(bytes);
         }
     }
 
+    /// Report the periodic health gauges.
+    fn emit_gauges(&self) {
+        self.metrics
+            .gauge("router.mailbox", self.backlog.pending() as u64)
+            .ok();
+        self.metrics
+            .gauge("channels.closed", self.closed.len() as u64)
+            .ok();
+        self.metrics
+            .gauge("channels.active", self.channels.len() as u64)
+            .ok();
+        self.metrics
+            .gauge("sessions.active", self.sessions.len() as u64)
+            .ok();
+        self.metrics
+            .gauge("buffer.total_bytes", self.total_buffered() as u64)
+            .ok();
+    }
+
     /// Send message to all users in the room
     fn send_message(
         &mut self,
         channel: &ChannelID,
         message: &str,
         skip_id: SessionId,
+        received: Instant,
     ) -> Result<(), perror::HandlerError> {
-        if let Some(participants) = self.channels.get_mut(channel) {
-            for party in participants.values_mut() {
-                let max_data: usize = self.settings.max_data as usize;
+        let mut gone = Vec::new();
+        let mut overflowed = Vec::new();
+        let shed = self.shed();
+        let near_limit_percent = self.settings.near_limit_percent;
+        let near = |used: usize, limit: usize| {
+            near_limit_percent > 0 && limit > 0 && used * 100 >= limit * near_limit_percent
+        };
+        if let Some(group) = self.channels.get_mut(channel) {
+            group.msg_count += 1;
+            group.data_exchanged += message.len();
+            group.last_active = Instant::now();
+            if self.settings.snapshot_mode {
+                group.snapshot = Some(message.to_owned());
+            }
+            let max_data = group.limits.max_data.unwrap_or(self.settings.max_data) as usize;
+            let msg_count = group
+                .limits
+                .max_exchanges
+                .unwrap_or(self.settings.max_exchanges);
+            let log = group.log(&self.log.log);
+            for party in group.participants.values_mut() {
                 let msg_len = message.len();
                 let remote_ip = party.remote.clone().unwrap_or_else(|| "Unknown".to_owned());
                 if max_data > 0 && (party.data_exchanged > max_data || msg_len > max_data) {
                     warn!(
-                        self.log.log,
+                        log,
                         "Too much data sent through {}, closing", channel;
                         "remote_ip" => &remote_ip
                     );
@@ -167,11 +1024,22 @@ impl ChannelServer {
                     return Err(perror::HandlerErrorKind::XSDataErr(remote.to_owned()).into());
                 }
                 party.data_exchanged += msg_len;
-                let msg_count = self.settings.max_exchanges;
+                if !group.near_data_limit && near(party.data_exchanged, max_data) {
+                    group.near_data_limit = true;
+                    self.metrics
+                        .incr_with_tags("conn.near_limit")
+                        .with_tag("limit", "data")
+                        .send();
+                }
+                if party.session_id == skip_id {
+                    party.bytes_sent += msg_len;
+                } else {
+                    party.bytes_received += msg_len;
+                }
                 party.msg_count += 1;
                 if msg_count > 0 && party.msg_count > msg_count {
                     warn!(
-                        self.log.log,
+                        log,
                         "Too many messages through {}, closing", channel;
                         "remote_ip" => &remote_ip
                     );
@@ -182,55 +1050,277 @@ impl ChannelServer {
                     self.metrics.incr("conn.max.msg").ok();
                     return Err(perror::HandlerErrorKind::XSMessageErr(remote.to_owned()).into());
                 }
+                if !group.near_msg_limit && near(party.msg_count as usize, msg_count as usize) {
+                    group.near_msg_limit = true;
+                    self.metrics
+                        .incr_with_tags("conn.near_limit")
+                        .with_tag("limit", "messages")
+                        .send();
+                }
                 if party.session_id != skip_id {
                     if let Some(addr) = self.sessions.get(&party.session_id) {
-                        addr.do_send(TextMessage(MessageType::Text, message.to_owned()));
+                        let msg = TextMessage(MessageType::Text, message.to_owned());
+                        match deliver(addr, msg, &self.metrics, shed) {
+                            Delivery::Gone => {
+                                gone.push(party.session_id);
+                                continue;
+                            }
+                            Delivery::Overflowed => {
+                                overflowed.push(party.session_id);
+                                continue;
+                            }
+                            _ => {}
+                        }
+                        if self.settings.relay_latency_metrics {
+                            self.metrics
+                                .time("relay.latency_ms", received.elapsed().as_millis() as u64)
+                                .ok();
+                        }
                     }
                 }
             }
         }
+        for id in gone {
+            self.prune(channel, id);
+        }
+        for id in overflowed {
+            warn!(self.log.log, "Session {} can't keep up, dropping", id);
+            self.disconnect(channel, id, DisconnectReason::MailboxOverflow);
+        }
         Ok(())
     }
 
-    fn disconnect(&mut self, channel: &ChannelID, id: usize) {
-        if let Some(participants) = self.channels.get_mut(channel) {
-            for pid in participants.keys() {
+    fn disconnect(&mut self, channel: &ChannelID, id: usize, reason: DisconnectReason) {
+        if let Some(group) = self.channels.get(channel) {
+            for pid in group.participants.keys() {
                 if id == *pid {
                     debug!(self.log.log, "Sending disconnect to {}", pid);
                     if let Some(addr) = self.sessions.get(&id) {
                         // send a control message to force close
-                        addr.do_send(TextMessage(MessageType::Terminate, EOL.to_owned()));
+                        deliver(addr, TextMessage::terminate(), &self.metrics, false);
                     }
                 }
             }
         }
+        self.sessions.remove(&id);
         let mut do_shutdown = false;
-        if let Some(participants) = self.channels.get_mut(channel) {
-            participants.remove(&id);
-            if participants.is_empty() {
+        let mut departed = None;
+        if let Some(group) = self.channels.get_mut(channel) {
+            departed = group.participants.remove(&id);
+            if group.participants.is_empty() {
                 do_shutdown = true;
             }
         }
+        if let Some(party) = departed {
+            self.release_subnet(&party.remote);
+            if !do_shutdown {
+                self.send_event(channel, id, "peer_disconnected");
+                // Only clients that dropped may pick up where they left off.
+                let dropped = matches!(
+                    reason,
+                    DisconnectReason::None
+                        | DisconnectReason::Timeout
+                        | DisconnectReason::_ConnectionError
+                );
+                let window = Duration::from_millis(self.settings.reconnect_coalesce_ms);
+                if dropped && !window.is_zero() {
+                    if let Some(group) = self.channels.get_mut(channel) {
+                        group.depart(party, window);
+                    }
+                }
+            }
+        }
         if do_shutdown {
-            self.shutdown(channel);
+            self.shutdown(channel, reason);
+        }
+    }
+
+    /// Forget a session that went away before saying so, as though it had
+    /// disconnected.
+    fn prune(&mut self, channel: &ChannelID, id: SessionId) {
+        debug!(self.log.log, "Pruning departed session {}", id);
+        self.disconnect(channel, id, DisconnectReason::None);
+    }
+
+    /// Tell the channel's other participants about a change in who's
+    /// connected, if `presence_events` is on.
+    fn send_event(&self, channel: &ChannelID, skip_id: SessionId, event: &str) {
+        if !self.settings.presence_events {
+            return;
+        }
+        if let Some(group) = self.channels.get(channel) {
+            for id in group.participants.keys().filter(|id| **id != skip_id) {
+                if let Some(addr) = self.sessions.get(id) {
+                    let msg = TextMessage::event(event, &self.settings);
+                    deliver(addr, msg, &self.metrics, false);
+                }
+            }
         }
     }
 
     /// Kill a channel and terminate all participants.
     ///
     /// This sends a Terminate to each participant, which forces the connection closed.
-    fn shutdown(&mut self, channel: &ChannelID) {
-        if let Some(participants) = self.channels.get(channel) {
-            for id in participants.keys() {
+    fn shutdown(&mut self, channel: &ChannelID, reason: DisconnectReason) {
+        // Let clients know which limit ended the channel, rather than leave
+        // them guessing whether the peer left.
+        let error = match reason {
+            DisconnectReason::ExcessData => Some("too_much_data"),
+            DisconnectReason::ExcessMessages => Some("too_many_messages"),
+            _ => None,
+        }
+        .filter(|_| self.settings.quota_errors);
+        if let Some(group) = self.channels.get(channel) {
+            for id in group.participants.keys() {
                 if let Some(addr) = self.sessions.get(id) {
+                    if let Some(error) = error {
+                        let error =
+                            frame(&self.settings, FrameType::Error, json!({ "error": error }));
+                        let msg = TextMessage(MessageType::Text, error);
+                        deliver(addr, msg, &self.metrics, false);
+                    }
                     // send a control message to force close
-                    addr.do_send(TextMessage(MessageType::Terminate, EOL.to_owned()));
+                    let msg = match reason {
+                        DisconnectReason::Drained => TextMessage::drain(),
+                        DisconnectReason::Evicted => TextMessage::evict(),
+                        _ => TextMessage::terminate(),
+                    };
+                    deliver(addr, msg, &self.metrics, false);
                 }
                 self.sessions.remove(id);
             }
         }
-        debug!(self.log.log, "Removing channel {}", channel);
-        self.channels.remove(channel);
+        if let Some(group) = self.channels.remove(channel) {
+            debug!(group.log(&self.log.log), "Removing channel {}", channel);
+            for party in group.participants.values() {
+                self.release_subnet(&party.remote);
+            }
+            if let Some(integration) = &group.integration {
+                if let Entry::Occupied(mut entry) = self.integrations.entry(integration.clone()) {
+                    *entry.get_mut() -= 1;
+                    if *entry.get() == 0 {
+                        entry.remove();
+                    }
+                }
+            }
+            self.closed.insert(*channel, reason);
+            if let Some(events) = &self.events {
+                info!(events.log, "{}", group.closed_event(&reason));
+            }
+        }
+    }
+
+    /// Make room for a new channel by tearing down the oldest one that has
+    /// been idle for `evict_idle_secs`, returning whether there was one.
+    fn evict_idle(&mut self) -> bool {
+        let idle = Duration::from_secs(self.settings.evict_idle_secs);
+        let oldest = self
+            .channels
+            .iter()
+            .filter(|(_, group)| group.last_active.elapsed() >= idle)
+            .min_by_key(|(_, group)| group.created)
+            .map(|(channel, _)| *channel);
+        let Some(channel) = oldest else {
+            return false;
+        };
+        let log = self
+            .channels
+            .get(&channel)
+            .map(|group| group.log(&self.log.log));
+        info!(
+            log.as_ref().unwrap_or(&self.log.log),
+            "Evicting idle channel to make room";
+            "channel" => &channel.as_string(),
+        );
+        self.metrics.incr("channel.evicted").ok();
+        self.shutdown(&channel, DisconnectReason::Evicted);
+        true
+    }
+
+    /// An id for a new session. Ids are handed out in turn, skipping 0 and
+    /// any still in use, so one session can never take another's place.
+    fn next_session_id(&mut self) -> SessionId {
+        loop {
+            self.last_session_id = self.last_session_id.wrapping_add(1);
+            if self.last_session_id != 0 && !self.sessions.contains_key(&self.last_session_id) {
+                return self.last_session_id;
+            }
+        }
+    }
+
+    /// Tear down channels that have outlived `conn_lifespan`, or that have
+    /// had no participant still connected for `idle_grace`, such as when a
+    /// client vanished without closing its socket before its peer joined.
+    fn sweep(&mut self) {
+        let lifespan = Duration::from_secs(self.settings.conn_lifespan);
+        let grace = Duration::from_secs(self.settings.idle_grace);
+        let mut swept = Vec::new();
+        for (channel, group) in self.channels.iter_mut() {
+            let vacant = !group
+                .participants
+                .keys()
+                .any(|id| self.sessions.contains_key(id));
+            let idle = if vacant {
+                group
+                    .vacant_since
+                    .get_or_insert_with(Instant::now)
+                    .elapsed()
+                    >= grace
+            } else {
+                group.vacant_since = None;
+                false
+            };
+            if idle || group.created.elapsed() >= lifespan {
+                swept.push(*channel);
+            }
+        }
+        for channel in swept {
+            let log = self
+                .channels
+                .get(&channel)
+                .map(|group| group.log(&self.log.log));
+            info!(
+                log.as_ref().unwrap_or(&self.log.log),
+                "Sweeping abandoned channel";
+                "channel" => &channel.as_string(),
+            );
+            self.metrics.incr("channel.swept").ok();
+            self.shutdown(&channel, DisconnectReason::Swept);
+        }
+    }
+
+    /// Has the channel outlived `max_channel_age`?
+    fn channel_expired(&self, channel: &ChannelID) -> bool {
+        self.channels
+            .get(channel)
+            .map(|group| {
+                let max_age = group
+                    .limits
+                    .max_age
+                    .unwrap_or(self.settings.max_channel_age);
+                max_age > 0 && group.created.elapsed() >= Duration::from_secs(max_age)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Tear down the channel if it has outlived `max_channel_age`, regardless
+    /// of how active its participants are.
+    fn expire_channel(&mut self, channel: &ChannelID) -> bool {
+        if !self.channel_expired(channel) {
+            return false;
+        }
+        let log = self
+            .channels
+            .get(channel)
+            .map(|group| group.log(&self.log.log));
+        info!(
+            log.as_ref().unwrap_or(&self.log.log),
+            "Channel exceeded max age, closing";
+            "channel" => &channel.as_string(),
+        );
+        self.metrics.incr("channel.max_age").ok();
+        self.shutdown(channel, DisconnectReason::MaxAge);
+        true
     }
 }
 
@@ -260,14 +1350,19 @@ impl Handler<Disconnect> for ChannelServer {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _ctx: &mut Context<Self>) {
+        self.backlog.leave();
+        let log = self
+            .channels
+            .get(&msg.channel)
+            .map(|group| group.log(&self.log.log));
         debug!(
-            self.log.log,
+            log.as_ref().unwrap_or(&self.log.log),
             "Connection dropped";
             "channel" => &msg.channel.as_string(),
             "session" => &msg.id,
             "reason" => format!("{}", &msg.reason),
         );
-        self.disconnect(&msg.channel, msg.id);
+        self.disconnect(&msg.channel, msg.id, msg.reason);
     }
 }
 
@@ -276,22 +1371,65 @@ impl Handler<ClientMessage> for ChannelServer {
     type Result = ();
 
     fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
+        self.backlog.leave();
         if msg.message_type == MessageType::Terminate {
-            return self.disconnect(&msg.channel, msg.id);
-        }
-        if self
-            .send_message(
-                &msg.channel,
-                &json!({
-                    "message": &msg.msg,
-                    "sender": &msg.sender,
-                })
-                .to_string(),
-                msg.id,
-            )
-            .is_err()
+            return self.disconnect(&msg.channel, msg.id, DisconnectReason::None);
+        }
+        if self.expire_channel(&msg.channel) {
+            return;
+        }
+        if let Some(request) = ControlRequest::parse(&msg.msg) {
+            match request {
+                ControlRequest::Stats if self.settings.expose_stats => {
+                    if self.control_allowed(&msg.channel, msg.id) {
+                        self.send_stats(&msg.channel, msg.id);
+                    }
+                    return;
+                }
+                ControlRequest::Caps(features) if self.settings.relay_caps => {
+                    if self.control_allowed(&msg.channel, msg.id) {
+                        self.send_caps(&msg.channel, msg.id, features);
+                    }
+                    return;
+                }
+                // Not enabled, so relay it like any other message.
+                _ => {}
+            }
+        }
+        if !self.settings.relay_enabled {
+            self.metrics.incr("conn.relay_disabled").ok();
+            return;
+        }
+        let mut envelope = if self.settings.legacy_envelope {
+            json!({
+                "message": &msg.msg,
+                "sender": &msg.sender,
+            })
+        } else {
+            json!({
+                "type": FrameType::Message.as_str(),
+                "payload": &msg.msg,
+                "sender": &msg.sender,
+            })
+        };
+        if self.settings.include_timestamp {
+            envelope["ts"] = self.timestamp().into();
+        }
+        if let Err(err) =
+            self.send_message(&msg.channel, &envelope.to_string(), msg.id, msg.received)
         {
-            self.shutdown(&msg.channel)
+            let reason = match err.kind {
+                perror::HandlerErrorKind::XSDataErr(remote) => {
+                    self.report_violator(&remote);
+                    DisconnectReason::ExcessData
+                }
+                perror::HandlerErrorKind::XSMessageErr(remote) => {
+                    self.report_violator(&remote);
+                    DisconnectReason::ExcessMessages
+                }
+                _ => DisconnectReason::_ConnectionError,
+            };
+            self.shutdown(&msg.channel, reason)
         }
     }
 }
@@ -301,27 +1439,90 @@ impl Actor for ChannelServer {
     /// We are going to use simple Context, we just need ability to communicate
     /// with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.settings.gauge_interval > 0 {
+            ctx.run_interval(
+                Duration::from_secs(self.settings.gauge_interval),
+                |act, _ctx| act.emit_gauges(),
+            );
+        }
+        if self.settings.max_total_buffered_bytes > 0 {
+            ctx.run_interval(Duration::from_secs(1), |act, _ctx| act.shed_buffered());
+        }
+        if self.settings.sweep_interval > 0 {
+            ctx.run_interval(
+                Duration::from_secs(self.settings.sweep_interval),
+                |act, _ctx| act.sweep(),
+            );
+        }
+        if self.settings.distinct_ip_window > 0 {
+            ctx.run_interval(
+                Duration::from_secs(self.settings.distinct_ip_window),
+                |act, _ctx| act.report_distinct_ips(),
+            );
+        }
+    }
 }
 
 /// Handler for Connect message.
 ///
 /// Register new session and assign unique id to this session
 impl Handler<Connect> for ChannelServer {
-    type Result = usize;
+    type Result = ConnectResult;
 
-    fn handle(&mut self, msg: Connect, _ctx: &mut Context<Self>) -> Self::Result {
-        let session_id = self.rng.gen::<usize>();
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> Self::Result {
+        self.backlog.leave();
+        // Don't let reconnects extend the life of an aged out channel.
+        self.expire_channel(&msg.channel);
+        let session_id = self.next_session_id();
         let remote = &msg.remote.clone().unwrap_or_else(|| "Unkown".to_owned());
         let chan_id = &msg.channel.as_string();
-        let new_session = Channel {
+        if self.settings.distinct_ip_window > 0 {
+            if let Some(remote) = &msg.remote {
+                self.distinct_ips.insert(remote.clone());
+            }
+        }
+        let subnet = self.subnet(&msg.remote);
+        if let Some(subnet) = subnet {
+            if self.subnets.get(&subnet).copied().unwrap_or(0)
+                >= self.settings.max_connections_per_subnet
+            {
+                warn!(
+                    self.log.log,
+                    "Too many connections from subnet";
+                    "subnet" => subnet.to_string(),
+                    "remote_ip" => remote,
+                );
+                self.metrics.incr("conn.max.subnet").ok();
+                return ConnectResult::AtCapacity;
+            }
+        }
+        let max_sessions = self.settings.max_sessions;
+        if max_sessions > 0 && self.sessions.len() >= max_sessions {
+            warn!(
+                self.log.log,
+                "Too many sessions";
+                "remote_ip" => remote,
+            );
+            self.metrics.incr("conn.max.sessions").ok();
+            return ConnectResult::AtCapacity;
+        }
+        let mut new_session = Channel {
             session_id,
             started: Instant::now(),
             msg_count: 0,
             data_exchanged: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            control_count: 0,
+            correlation_id: Uuid::new_v4(),
             remote: msg.remote.clone(),
         };
-        self.sessions
-            .insert(new_session.session_id, msg.addr.clone());
+        let addr = SessionAddr {
+            addr: msg.addr.clone(),
+            buffered: msg.buffered.clone(),
+        };
         debug!(
             self.log.log,
             "New connection";
@@ -329,19 +1530,90 @@ impl Handler<Connect> for ChannelServer {
             "session" => &new_session.session_id,
             "remote_ip" => remote,
         );
+        let preallocated = self
+            .preallocated
+            .get(&msg.channel)
+            .is_some_and(|reservation| reservation.expires > Instant::now());
+        let max_channels = self.settings.max_channels;
+        // A reserved channel was already counted when it was set aside.
+        let mut channels_full = max_channels > 0
+            && !preallocated
+            && self.channels.len() + self.reservations() >= max_channels;
+        if channels_full
+            && self.settings.evict_on_full
+            && (msg.initial_connect || preallocated)
+            && !self.channels.contains_key(&msg.channel)
+        {
+            channels_full = !self.evict_idle();
+        }
+        let mut created = false;
         // Is this a new channel request?
         if let Entry::Vacant(entry) = self.channels.entry(msg.channel) {
             // Is this the first time we're requesting this channel?
-            if !&msg.initial_connect {
+            if !msg.initial_connect && !preallocated {
+                if let Some(reason) = self.closed.get(&msg.channel) {
+                    warn!(
+                        self.log.log,
+                        "Attempt to connect to closed channel";
+                        "channel" => chan_id,
+                        "remote_ip" => remote,
+                        "reason" => reason.to_string(),
+                    );
+                    self.metrics.incr("conn.closed_channel").ok();
+                } else {
+                    warn!(
+                        self.log.log,
+                        "Attempt to connect to unknown channel";
+                        "channel" => chan_id,
+                        "remote_ip" => remote,
+                    );
+                }
+                return ConnectResult::UnknownChannel;
+            }
+            if let Some(integration) = &msg.integration {
+                let max = self.settings.max_channels_per_integration;
+                if max > 0 && self.integrations.get(integration).copied().unwrap_or(0) >= max {
+                    warn!(
+                        self.log.log,
+                        "Too many channels for integration";
+                        "integration" => integration,
+                        "remote_ip" => remote,
+                    );
+                    self.metrics.incr("conn.max.integration").ok();
+                    return ConnectResult::AtCapacity;
+                }
+            }
+            if channels_full {
                 warn!(
                     self.log.log,
-                    "Attempt to connect to unknown channel";
+                    "Too many channels";
                     "channel" => chan_id,
                     "remote_ip" => remote,
                 );
-                return 0;
+                self.metrics.incr("conn.max.channels").ok();
+                return ConnectResult::AtCapacity;
+            }
+            let limits = msg.limits.capped(&self.settings);
+            entry.insert(ChannelGroup {
+                integration: msg.integration.clone(),
+                client_correlation_id: msg.client_correlation_id.clone(),
+                limits,
+                ..Default::default()
+            });
+            // Only used up once the channel is open, so a refused client
+            // doesn't take it from the one it was meant for.
+            self.preallocated.remove(&msg.channel);
+            if let Some(integration) = &msg.integration {
+                *self.integrations.entry(integration.clone()).or_insert(0) += 1;
+            }
+            created = true;
+            let max_age = limits.max_age.unwrap_or(self.settings.max_channel_age);
+            if max_age > 0 {
+                let channel = msg.channel;
+                ctx.run_later(Duration::from_secs(max_age), move |act, _ctx| {
+                    act.expire_channel(&channel);
+                });
             }
-            entry.insert(HashMap::new());
         };
         let group = match self.channels.get_mut(&msg.channel) {
             None => {
@@ -349,54 +1621,142 @@ impl Handler<Connect> for ChannelServer {
                 "No group information found for channel";
                 "channel" => chan_id,
                 "remote_ip" => remote);
-                return 0;
+                return ConnectResult::UnknownChannel;
             }
             Some(v) => v,
         };
-        if group.len() >= self.settings.max_channel_connections as usize {
+        if group.draining {
+            info!(
+                self.log.log,
+                "Refusing to join a draining channel";
+                "channel" => chan_id,
+                "remote_ip" => remote,
+            );
+            return ConnectResult::Draining;
+        }
+        let max_connections = group
+            .limits
+            .max_connections
+            .unwrap_or(self.settings.max_channel_connections);
+        if group.participants.len() >= max_connections as usize {
             warn!(
                 self.log.log,
                 "Too many connections requested for channel";
                 "channel" => chan_id,
                 "remote_ip" => remote,
             );
-            self.sessions.remove(&new_session.session_id);
-            self.metrics.incr("conn.max.conn").ok();
             // It doesn't make sense to impose a high penalty for this
             // behavior, but we may want to flag and log the origin
             // IP for later analytics.
             // We could also impose a tiny penalty on the IP (if possible)
             // which would minimally impact accidental occurances, but
             // add up for major infractors.
-            return 0;
+            return ConnectResult::ChannelFull;
         }
-        // The group should have two principle parties, the auth and supplicant
+        if self.settings.match_versions {
+            match (group.protocol, msg.protocol) {
+                (Some(expected), Some(protocol)) if expected != protocol => {
+                    warn!(
+                        self.log.log,
+                        "Client version does not match channel";
+                        "channel" => chan_id,
+                        "remote_ip" => remote,
+                        "expected" => expected,
+                        "protocol" => protocol,
+                    );
+                    self.metrics.incr("conn.version_mismatch").ok();
+                    return ConnectResult::VersionMismatch;
+                }
+                _ => {}
+            }
+        }
+        // The group should have `principal_count` principle parties, for
+        // pairing that's the auth and supplicant.
         // Any connection beyond that group should be checked to ensure it's
         // from a known IP. If a principle that only has one connection and it
         // drops, it is possible that it can't reconnect, but that's not a bad
         // thing. We should just let the connection expire as invalid so that
         // it's not stolen.
-        if group.len() > 2 && !reconnect_check(group, &new_session.remote, Some(&self.log)) {
+        // A client with a resume token for a participant that just dropped is
+        // reclaiming its own place, wherever it's connecting from now. The
+        // token alone isn't enough while its participant is still connected.
+        let window = Duration::from_millis(self.settings.reconnect_coalesce_ms);
+        let resumed = msg
+            .resume
+            .as_deref()
+            .and_then(|token| {
+                resume_token::verify(
+                    token,
+                    chan_id,
+                    &group.correlation_id,
+                    &self.settings.resume_token_secret,
+                )
+            })
+            .and_then(|slot| group.reclaim(&slot, window));
+        if group.participants.len() > self.settings.principal_count
+            && resumed.is_none()
+            && !reconnect_check(&group.participants, &new_session.remote, Some(&self.log))
+        {
             error!(
                 self.log.log,
                 "Unexpected remote connection";
                 "remote_ip" => remote,
             );
-            return 0;
+            return ConnectResult::Untrusted;
         };
-        debug!(self.log.log,
+        if let Some(prior) = resumed {
+            new_session = Channel {
+                session_id,
+                remote: new_session.remote,
+                ..prior
+            };
+            self.metrics.incr("conn.resumed").ok();
+        } else if !window.is_zero() {
+            if let Some(prior) = group.rejoin(&new_session.remote, window) {
+                // Carry the dropped session's accounting over, so reconnecting
+                // doesn't reset its limits.
+                new_session = Channel {
+                    session_id,
+                    remote: new_session.remote,
+                    ..prior
+                };
+                self.metrics.incr("conn.coalesced").ok();
+            }
+        }
+        debug!(group.log(&self.log.log),
             "Adding session to channel";
             "channel" => chan_id,
             "session" => &new_session.session_id,
+            "correlation_id" => new_session.correlation_id.to_string(),
             "remote_ip" => remote,
         );
-        group.insert(session_id, new_session);
+        let resume = resume_token::issue(
+            chan_id,
+            &group.correlation_id,
+            &new_session.correlation_id,
+            &self.settings.resume_token_secret,
+        );
+        group.participants.insert(session_id, new_session);
+        self.sessions.insert(session_id, addr.clone());
+        if self.settings.match_versions && group.protocol.is_none() {
+            group.protocol = msg.protocol;
+        }
+        if let Some(subnet) = subnet {
+            *self.subnets.entry(subnet).or_insert(0) += 1;
+        }
+        group.peak_participants = group.peak_participants.max(group.participants.len());
+        if let Some(country) = &msg.country {
+            group.countries.insert(country.clone());
+        }
+        self.send_event(&msg.channel, session_id, "peer_connected");
         // tell the client what their channel is.
-        let jpath = json!({ "link": format!("/v1/ws/{}", chan_id),
-                            "channelid": chan_id });
-        if msg
-            .addr
-            .try_send(TextMessage(MessageType::Text, jpath.to_string()))
+        let welcome = frame(
+            &self.settings,
+            FrameType::Control,
+            self.welcome(&msg.channel, resume),
+        );
+        if addr
+            .try_send(TextMessage(MessageType::Text, welcome))
             .is_err()
         {
             warn!(
@@ -405,8 +1765,194 @@ impl Handler<Connect> for ChannelServer {
                 "channel" => chan_id,
                 "remote_ip" => remote
             )
+        } else {
+            self.metrics
+                .incr_with_tags("channel.welcome")
+                .with_tag("type", if created { "new" } else { "existing" })
+                .send();
         };
-        session_id
+        // bring a late joiner up to date.
+        if let Some(snapshot) = self
+            .channels
+            .get(&msg.channel)
+            .and_then(|group| group.snapshot.clone())
+        {
+            if addr
+                .try_send(TextMessage(MessageType::Text, snapshot))
+                .is_err()
+            {
+                warn!(
+                    self.log.log,
+                    "Could not send snapshot to channel";
+                    "channel" => chan_id,
+                    "remote_ip" => remote
+                )
+            }
+        }
+        ConnectResult::Ok(session_id)
+    }
+}
+
+/// Handler for `DrainChannel` message.
+impl Handler<DrainChannel> for ChannelServer {
+    type Result = bool;
+
+    fn handle(&mut self, msg: DrainChannel, ctx: &mut Context<Self>) -> Self::Result {
+        let DrainChannel(channel, grace) = msg;
+        if self.notify_drain(&channel, grace).is_none() {
+            return false;
+        }
+        ctx.run_later(grace, move |act, _ctx| {
+            act.shutdown(&channel, DisconnectReason::Drained)
+        });
+        true
+    }
+}
+
+/// Handler for `DrainAll` message.
+impl Handler<DrainAll> for ChannelServer {
+    type Result = MessageResult<DrainAll>;
+
+    fn handle(&mut self, msg: DrainAll, ctx: &mut Context<Self>) -> Self::Result {
+        let DrainAll(grace) = msg;
+        self.readiness.set(false);
+        let mut report = DrainReport::default();
+        let channels: Vec<ChannelID> = self.channels.keys().copied().collect();
+        for channel in &channels {
+            if let Some((sessions, failed)) = self.notify_drain(channel, grace) {
+                report.channels += 1;
+                report.sessions += sessions;
+                report.failed += failed;
+            }
+        }
+        if grace.is_zero() {
+            for channel in &channels {
+                self.shutdown(channel, DisconnectReason::Drained);
+            }
+        } else {
+            ctx.run_later(grace, move |act, _ctx| {
+                for channel in &channels {
+                    act.shutdown(channel, DisconnectReason::Drained);
+                }
+            });
+        }
+        info!(
+            self.log.log,
+            "Drained all channels";
+            "channels" => report.channels,
+            "sessions" => report.sessions,
+            "failed" => report.failed,
+        );
+        MessageResult(report)
+    }
+}
+
+/// Handler for `Preallocate` message.
+impl Handler<Preallocate> for ChannelServer {
+    type Result = PreallocateResult;
+
+    fn handle(&mut self, msg: Preallocate, _: &mut Context<Self>) -> Self::Result {
+        let reservations = self.reservations();
+        let channel = msg.channel;
+        if self.channels.contains_key(&channel) || self.preallocated.contains_key(&channel) {
+            return PreallocateResult::Taken;
+        }
+        let remote = msg.remote.as_deref().unwrap_or("Unknown");
+        let max_channels = self.settings.max_channels;
+        if max_channels > 0 && self.channels.len() + reservations >= max_channels {
+            warn!(
+                self.log.log,
+                "Too many channels to preallocate another";
+                "remote_ip" => remote,
+            );
+            self.metrics.incr("conn.max.channels").ok();
+            return PreallocateResult::AtCapacity;
+        }
+        // A reservation holds a place for its subnet until it's used.
+        let subnet = self.subnet(&msg.remote);
+        if let Some(subnet) = subnet {
+            let reserved = self
+                .preallocated
+                .values()
+                .filter(|reservation| reservation.subnet == Some(subnet))
+                .count();
+            if self.subnets.get(&subnet).copied().unwrap_or(0) + reserved
+                >= self.settings.max_connections_per_subnet
+            {
+                warn!(
+                    self.log.log,
+                    "Too many connections from subnet to preallocate";
+                    "subnet" => subnet.to_string(),
+                    "remote_ip" => remote,
+                );
+                self.metrics.incr("conn.max.subnet").ok();
+                return PreallocateResult::AtCapacity;
+            }
+        }
+        let ttl = Duration::from_secs(self.settings.preallocate_ttl);
+        self.preallocated.insert(
+            channel,
+            Reservation {
+                expires: Instant::now() + ttl,
+                subnet,
+            },
+        );
+        self.metrics.incr("channel.preallocated").ok();
+        PreallocateResult::Ok(self.welcome(&channel, None).to_string())
+    }
+}
+
+/// Handler for `ChannelInfo` message.
+impl Handler<ChannelInfo> for ChannelServer {
+    type Result = usize;
+
+    fn handle(&mut self, msg: ChannelInfo, _: &mut Context<Self>) -> Self::Result {
+        self.backlog.leave();
+        self.channels
+            .get(&msg.0)
+            .map(|group| group.participants.len())
+            .unwrap_or(0)
+    }
+}
+
+/// Handler for `ChannelStatus` message.
+impl Handler<ChannelStatus> for ChannelServer {
+    type Result = ChannelStatusReport;
+
+    fn handle(&mut self, msg: ChannelStatus, _: &mut Context<Self>) -> Self::Result {
+        match self.channels.get(&msg.0) {
+            Some(group) => {
+                let max_connections = group
+                    .limits
+                    .max_connections
+                    .unwrap_or(self.settings.max_channel_connections);
+                ChannelStatusReport {
+                    exists: true,
+                    participants: group.participants.len(),
+                    full: group.participants.len() >= max_connections as usize,
+                }
+            }
+            None => ChannelStatusReport {
+                exists: self
+                    .preallocated
+                    .get(&msg.0)
+                    .is_some_and(|reservation| reservation.expires > Instant::now()),
+                participants: 0,
+                full: false,
+            },
+        }
+    }
+}
+
+/// Handler for `GetLoad` message.
+impl Handler<GetLoad> for ChannelServer {
+    type Result = Load;
+
+    fn handle(&mut self, _: GetLoad, _: &mut Context<Self>) -> Self::Result {
+        Load {
+            sessions: self.sessions.len(),
+            channels: self.channels.len(),
+        }
     }
 }
 
@@ -414,14 +1960,23 @@ impl Handler<Connect> for ChannelServer {
 impl Handler<ListChannels> for ChannelServer {
     type Result = MessageResult<ListChannels>;
 
-    fn handle(&mut self, _: ListChannels, _: &mut Context<Self>) -> Self::Result {
-        let mut channels = Vec::new();
-
-        for key in self.channels.keys() {
-            channels.push(key.to_owned())
-        }
+    fn handle(&mut self, msg: ListChannels, _: &mut Context<Self>) -> Self::Result {
+        let mut channels: Vec<(&ChannelID, &ChannelGroup)> = self.channels.iter().collect();
+        channels.sort_by_key(|(channel, _)| channel.as_string());
 
-        MessageResult(channels)
+        MessageResult(
+            channels
+                .into_iter()
+                .skip(msg.offset)
+                .take(msg.limit.unwrap_or(usize::MAX))
+                .map(|(channel, group)| ChannelSummary {
+                    channelid: *channel,
+                    participants: group.participants.len(),
+                    age: group.created.elapsed().as_secs(),
+                    bytes: group.data_exchanged,
+                })
+                .collect(),
+        )
     }
 }
 
@@ -429,31 +1984,1960 @@ impl Handler<ListChannels> for ChannelServer {
 mod test {
     use super::*;
 
+    use std::sync::Mutex;
+
+    use actix::Addr;
+    use cadence::SpyMetricSink;
+    use crossbeam_channel::Receiver;
+    use slog::Drain;
+
+    type Received = Arc<Mutex<Vec<(MessageType, String)>>>;
+
+    /// Stand-in for a websocket session that records what it is sent.
+    #[derive(Default)]
+    struct TestClient {
+        received: Received,
+    }
+
+    impl Actor for TestClient {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<TextMessage> for TestClient {
+        type Result = ();
+
+        fn handle(&mut self, msg: TextMessage, _ctx: &mut Context<Self>) {
+            self.received.lock().unwrap().push((msg.0, msg.1));
+        }
+    }
+
+    /// Connect a client, returning its session id, or 0 if it was refused.
+    async fn connect(
+        server: &Addr<ChannelServer>,
+        channel: ChannelID,
+        remote: &str,
+        initial_connect: bool,
+    ) -> (SessionId, Received) {
+        let (result, received) = try_connect(server, channel, remote, initial_connect).await;
+        match result {
+            ConnectResult::Ok(session_id) => (session_id, received),
+            _ => (0, received),
+        }
+    }
+
+    async fn try_connect(
+        server: &Addr<ChannelServer>,
+        channel: ChannelID,
+        remote: &str,
+        initial_connect: bool,
+    ) -> (ConnectResult, Received) {
+        let client = TestClient::default();
+        let received = client.received.clone();
+        let result = server
+            .send(Connect {
+                addr: client.start().recipient(),
+                channel,
+                remote: Some(remote.to_owned()),
+                country: None,
+                initial_connect,
+                integration: None,
+                limits: ChannelLimits::default(),
+                protocol: None,
+                buffered: Buffered::default(),
+                client_correlation_id: None,
+                resume: None,
+            })
+            .await
+            .unwrap();
+        (result, received)
+    }
+
+    async fn preallocate(
+        server: &Addr<ChannelServer>,
+        channel: ChannelID,
+        remote: &str,
+    ) -> PreallocateResult {
+        server
+            .send(Preallocate {
+                channel,
+                remote: Some(remote.to_owned()),
+            })
+            .await
+            .unwrap()
+    }
+
+    fn terminated(received: &Received) -> bool {
+        received.lock().unwrap().iter().any(|(t, _)| {
+            matches!(
+                t,
+                MessageType::Terminate | MessageType::Drain | MessageType::Evict
+            )
+        })
+    }
+
+    fn test_server(settings: Settings) -> (ChannelServer, Receiver<Vec<u8>>) {
+        let (rx, sink) = SpyMetricSink::new();
+        let metrics = Arc::new(StatsdClient::from_sink("test", sink));
+        let server = ChannelServer::new(&settings, &MozLogger::new_human(), metrics);
+        (server, rx)
+    }
+
+    /// A participant that hasn't sent anything yet.
+    fn party(session_id: SessionId, remote: Option<&str>) -> Channel {
+        Channel {
+            session_id,
+            started: Instant::now(),
+            msg_count: 0,
+            data_exchanged: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            control_count: 0,
+            correlation_id: Uuid::new_v4(),
+            remote: remote.map(str::to_owned),
+        }
+    }
+
+    fn sent_metrics(rx: &Receiver<Vec<u8>>) -> Vec<String> {
+        rx.try_iter()
+            .map(|m| String::from_utf8(m).unwrap())
+            .collect()
+    }
+
+    /// Wait a few seconds at most for `done` to hold for the metrics sent so
+    /// far, returning them.
+    async fn await_metrics(
+        rx: &Receiver<Vec<u8>>,
+        done: impl Fn(&[String]) -> bool,
+    ) -> Vec<String> {
+        let mut sent = Vec::new();
+        for _ in 0..300 {
+            sent.extend(sent_metrics(rx));
+            if done(&sent) {
+                break;
+            }
+            actix_rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        sent
+    }
+
     #[test]
-    fn test_reconnect() {
-        let mut test_group: Channels = HashMap::new();
+    fn test_mailbox_gauge() {
+        let (server, rx) = test_server(Settings::default());
+        server.emit_gauges();
+        assert!(sent_metrics(&rx).contains(&"test.router.mailbox:0|g".to_owned()));
+
+        for _ in 0..3 {
+            server.backlog.enter();
+        }
+        server.emit_gauges();
+        assert!(sent_metrics(&rx).contains(&"test.router.mailbox:3|g".to_owned()));
+
+        server.backlog.leave();
+        server.emit_gauges();
+        assert!(sent_metrics(&rx).contains(&"test.router.mailbox:2|g".to_owned()));
+    }
+
+    #[actix_rt::test]
+    async fn test_active_gauges() {
+        let settings = Settings {
+            gauge_interval: 1,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        connect(&server, channel, "1.1.1.1", true).await;
+        connect(&server, channel, "2.2.2.2", false).await;
+        connect(&server, ChannelID::default(), "3.3.3.3", true).await;
+
+        let expected = [
+            "test.channels.active:2|g".to_owned(),
+            "test.sessions.active:3|g".to_owned(),
+        ];
+        let metrics = await_metrics(&rx, |sent| {
+            expected.iter().all(|gauge| sent.contains(gauge))
+        })
+        .await;
+        for gauge in &expected {
+            assert!(metrics.contains(gauge), "{}", gauge);
+        }
+    }
+
+    #[test]
+    fn test_welcome_padding() {
+        let chan = "j6jLPVPeQR6diyrkQinRAQ".parse::<ChannelID>().unwrap();
+        for (pad, expected) in [
+            (false, "j6jLPVPeQR6diyrkQinRAQ"),
+            (true, "j6jLPVPeQR6diyrkQinRAQ=="),
+        ] {
+            let settings = Settings {
+                pad_channel_links: pad,
+                ..Default::default()
+            };
+            let (server, _) = test_server(settings);
+            let welcome = server.welcome(&chan, None);
+            assert_eq!(welcome["channelid"], expected);
+            assert_eq!(welcome["link"], format!("/v1/ws/{}", expected));
+            let link = welcome["link"].as_str().unwrap();
+            let id = link.rsplit('/').next().unwrap();
+            assert_eq!(id.parse::<ChannelID>().unwrap(), chan);
+        }
+    }
+
+    #[test]
+    fn test_welcome_prefix() {
+        let chan = "j6jLPVPeQR6diyrkQinRAQ".parse::<ChannelID>().unwrap();
+        for (prefix, expected) in [
+            ("", "/v1/ws/j6jLPVPeQR6diyrkQinRAQ"),
+            ("/pair", "/pair/v1/ws/j6jLPVPeQR6diyrkQinRAQ"),
+            ("pair/", "/pair/v1/ws/j6jLPVPeQR6diyrkQinRAQ"),
+            ("/svc/pair/", "/svc/pair/v1/ws/j6jLPVPeQR6diyrkQinRAQ"),
+        ] {
+            let settings = Settings {
+                public_path_prefix: prefix.to_owned(),
+                ..Default::default()
+            };
+            let (server, _) = test_server(settings);
+            let welcome = server.welcome(&chan, None);
+            assert_eq!(welcome["link"], expected, "{}", prefix);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_max_channel_age() {
+        let settings = Settings {
+            max_channel_age: 1,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+
+        let (a, a_rcv) = connect(&server, channel, "1.1.1.1", true).await;
+        let (b, _) = connect(&server, channel, "2.2.2.2", false).await;
+        assert!(a != 0 && b != 0);
+        // The peer drops and reconnects, keeping the channel active.
+        server
+            .send(Disconnect {
+                channel,
+                id: b,
+                reason: DisconnectReason::None,
+            })
+            .await
+            .unwrap();
+        let (c, c_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        assert!(c != 0);
+
+        let max_age = "test.channel.max_age:1|c".to_owned();
+        let sent = await_metrics(&rx, |sent| {
+            sent.contains(&max_age) && terminated(&a_rcv) && terminated(&c_rcv)
+        })
+        .await;
+        assert!(sent.contains(&max_age));
+        assert!(terminated(&a_rcv));
+        assert!(terminated(&c_rcv));
+        assert!(server
+            .send(ListChannels::default())
+            .await
+            .unwrap()
+            .is_empty());
+        // and it can't be rejoined.
+        assert_eq!(connect(&server, channel, "2.2.2.2", false).await.0, 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_relay_latency() {
+        let settings = Settings {
+            relay_latency_metrics: true,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+
+        let (a, _) = connect(&server, channel, "1.1.1.1", true).await;
+        let (_, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        server
+            .send(ClientMessage {
+                id: a,
+                message_type: MessageType::Text,
+                msg: "hello".to_owned(),
+                channel,
+                sender: meta::SenderData::default(),
+                received: Instant::now(),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(b_rcv.lock().unwrap().len(), 2);
+        let timers: Vec<u64> = sent_metrics(&rx)
+            .iter()
+            .filter_map(|m| m.strip_prefix("test.relay.latency_ms:"))
+            .map(|m| m.trim_end_matches("|ms").parse().unwrap())
+            .collect();
+        // Only the peer is relayed to, and it should take well under a second.
+        assert_eq!(timers.len(), 1);
+        assert!(timers[0] < 1000);
+    }
+
+    #[actix_rt::test]
+    async fn test_terminate_has_no_sentinel() {
+        let settings = Settings {
+            max_exchanges: 1,
+            ..Default::default()
+        };
+        let (server, _) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+
+        let (a, a_rcv) = connect(&server, channel, "1.1.1.1", true).await;
+        let (_, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        for _ in 0..2 {
+            server
+                .send(ClientMessage {
+                    id: a,
+                    message_type: MessageType::Text,
+                    msg: "hello".to_owned(),
+                    channel,
+                    sender: meta::SenderData::default(),
+                    received: Instant::now(),
+                })
+                .await
+                .unwrap();
+        }
+
+        for rcv in [a_rcv, b_rcv] {
+            assert!(terminated(&rcv));
+            for (msg_type, body) in rcv.lock().unwrap().iter() {
+                assert!(!body.contains('\x04'));
+                if *msg_type == MessageType::Terminate {
+                    assert!(body.is_empty());
+                }
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_quota_errors() {
+        for quota_errors in [true, false] {
+            let settings = Settings {
+                max_exchanges: 1,
+                quota_errors,
+                ..Default::default()
+            };
+            let (server, _) = test_server(settings);
+            let server = server.start();
+            let channel = ChannelID::default();
+
+            let (a, a_rcv) = connect(&server, channel, "1.1.1.1", true).await;
+            let (_, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+            for _ in 0..2 {
+                server
+                    .send(ClientMessage {
+                        id: a,
+                        message_type: MessageType::Text,
+                        msg: "hello".to_owned(),
+                        channel,
+                        sender: meta::SenderData::default(),
+                        received: Instant::now(),
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            for rcv in [a_rcv, b_rcv] {
+                let rcv = rcv.lock().unwrap();
+                let (last, rest) = rcv.split_last().unwrap();
+                assert_eq!(last.0, MessageType::Terminate);
+                let error = rest
+                    .last()
+                    .and_then(|(_, body)| serde_json::from_str::<serde_json::Value>(body).ok())
+                    .filter(|body| body.get("error").is_some());
+                if quota_errors {
+                    assert_eq!(error, Some(json!({"error": "too_many_messages"})));
+                } else {
+                    assert_eq!(error, None);
+                }
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_presence_events() {
+        for presence_events in [true, false] {
+            let settings = Settings {
+                presence_events,
+                ..Default::default()
+            };
+            let (server, _) = test_server(settings);
+            let server = server.start();
+            let channel = ChannelID::default();
+            let (_, a_rcv) = connect(&server, channel, "1.1.1.1", true).await;
+            let (b, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+            server
+                .send(Disconnect {
+                    id: b,
+                    channel,
+                    reason: DisconnectReason::None,
+                })
+                .await
+                .unwrap();
+            actix_rt::time::sleep(Duration::from_millis(10)).await;
+
+            let events = |rcv: &Received| -> Vec<serde_json::Value> {
+                rcv.lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(t, _)| *t == MessageType::Control)
+                    .map(|(_, body)| serde_json::from_str(body).unwrap())
+                    .collect()
+            };
+            if presence_events {
+                assert_eq!(
+                    events(&a_rcv),
+                    vec![
+                        json!({"event": "peer_connected"}),
+                        json!({"event": "peer_disconnected"})
+                    ]
+                );
+            } else {
+                assert!(events(&a_rcv).is_empty());
+            }
+            // nobody else was there to tell
+            assert!(events(&b_rcv).is_empty());
+            assert_eq!(server.send(GetLoad).await.unwrap().sessions, 1);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_stats() {
+        let settings = Settings {
+            expose_stats: true,
+            ..Default::default()
+        };
+        let (server, _) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let send = |id: SessionId, msg: &str| ClientMessage {
+            id,
+            message_type: MessageType::Text,
+            msg: msg.to_owned(),
+            channel,
+            sender: meta::SenderData::default(),
+            received: Instant::now(),
+        };
+        let last_stats = |rcv: &Received| -> serde_json::Value {
+            let rcv = rcv.lock().unwrap();
+            serde_json::from_str(&rcv.last().unwrap().1).unwrap()
+        };
+
+        let (a, a_rcv) = connect(&server, channel, "1.1.1.1", true).await;
+        let (b, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        server.send(send(a, "hello")).await.unwrap();
+        let relayed = b_rcv.lock().unwrap().last().unwrap().1.len();
+
+        server.send(send(a, r#"{"type":"stats"}"#)).await.unwrap();
+        let stats = last_stats(&a_rcv);
+        assert_eq!(stats["sent"], relayed);
+        assert_eq!(stats["received"], 0);
+        assert_eq!(stats["messages"], 1);
 
-        test_group.insert(
-            1,
+        server.send(send(b, r#"{"type":"stats"}"#)).await.unwrap();
+        let stats = last_stats(&b_rcv);
+        assert_eq!(stats["sent"], 0);
+        assert_eq!(stats["received"], relayed);
+        assert_eq!(stats["messages"], 1);
+        // The stats requests were only answered to the requester.
+        assert_eq!(a_rcv.lock().unwrap().len(), 2);
+        assert_eq!(b_rcv.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_rejoin() {
+        let window = Duration::from_secs(1);
+        let mut group = ChannelGroup::default();
+        let party = Channel {
+            msg_count: 2,
+            data_exchanged: 10,
+            bytes_sent: 10,
+            ..party(1, Some("1.1.1.1"))
+        };
+        let correlation_id = party.correlation_id;
+        group.depart(party, window);
+        assert!(group.rejoin(&Some("2.2.2.2".to_owned()), window).is_none());
+        assert!(group.rejoin(&None, window).is_none());
+        let prior = group.rejoin(&Some("1.1.1.1".to_owned()), window).unwrap();
+        assert_eq!(prior.correlation_id, correlation_id);
+        assert_eq!(prior.msg_count, 2);
+        assert_eq!(prior.bytes_sent, 10);
+        // Only picked up once.
+        assert!(group.rejoin(&Some("1.1.1.1".to_owned()), window).is_none());
+
+        // Too late.
+        group.departed.push((
+            Instant::now() - Duration::from_secs(2),
             Channel {
-                session_id: 1,
-                started: Instant::now(),
-                msg_count: 0,
-                data_exchanged: 0,
-                remote: Some("127.0.0.1".to_owned()),
+                session_id: 2,
+                ..prior
             },
+        ));
+        assert!(group.rejoin(&Some("1.1.1.1".to_owned()), window).is_none());
+        assert!(group.departed.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_reconnect_coalesce() {
+        let settings = Settings {
+            expose_stats: true,
+            reconnect_coalesce_ms: 1000,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let send = |id: SessionId, msg: &str| ClientMessage {
+            id,
+            message_type: MessageType::Text,
+            msg: msg.to_owned(),
+            channel,
+            sender: meta::SenderData::default(),
+            received: Instant::now(),
+        };
+
+        let (a, _) = connect(&server, channel, "1.1.1.1", true).await;
+        let (_b, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        server.send(send(a, "hello")).await.unwrap();
+        let relayed = b_rcv.lock().unwrap().last().unwrap().1.len();
+        server
+            .send(Disconnect {
+                channel,
+                id: a,
+                reason: DisconnectReason::Timeout,
+            })
+            .await
+            .unwrap();
+
+        let (a, a_rcv) = connect(&server, channel, "1.1.1.1", false).await;
+        assert_ne!(a, 0);
+        assert!(sent_metrics(&rx).contains(&"test.conn.coalesced:1|c".to_owned()));
+        server.send(send(a, r#"{"type":"stats"}"#)).await.unwrap();
+        let stats: serde_json::Value =
+            serde_json::from_str(&a_rcv.lock().unwrap().last().unwrap().1).unwrap();
+        assert_eq!(stats["sent"], relayed);
+        assert_eq!(stats["messages"], 1);
+
+        // A different address starts afresh.
+        let (c, c_rcv) = connect(&server, channel, "3.3.3.3", false).await;
+        server.send(send(c, r#"{"type":"stats"}"#)).await.unwrap();
+        let stats: serde_json::Value =
+            serde_json::from_str(&c_rcv.lock().unwrap().last().unwrap().1).unwrap();
+        assert_eq!(stats["messages"], 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_resume_token() {
+        let settings = Settings {
+            expose_stats: true,
+            principal_count: 1,
+            max_channel_connections: 4,
+            reconnect_coalesce_ms: 1000,
+            resume_token_secret: "s3cret".to_owned(),
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let send = |id: SessionId, msg: &str| ClientMessage {
+            id,
+            message_type: MessageType::Text,
+            msg: msg.to_owned(),
+            channel,
+            sender: meta::SenderData::default(),
+            received: Instant::now(),
+        };
+        let resume = |remote: &str, token: &str| {
+            let client = TestClient::default();
+            let received = client.received.clone();
+            let connect = Connect {
+                addr: client.start().recipient(),
+                channel,
+                remote: Some(remote.to_owned()),
+                country: None,
+                initial_connect: false,
+                integration: None,
+                limits: ChannelLimits::default(),
+                protocol: None,
+                buffered: Buffered::default(),
+                client_correlation_id: None,
+                resume: Some(token.to_owned()),
+            };
+            (server.send(connect), received)
+        };
+
+        let (a, _) = connect(&server, channel, "1.1.1.1", true).await;
+        let (b, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        let (c, _) = connect(&server, channel, "1.1.1.1", false).await;
+        let welcome: serde_json::Value = serde_json::from_str(&b_rcv.lock().unwrap()[0].1).unwrap();
+        let token = welcome["resume"].as_str().unwrap().to_owned();
+        server.send(send(b, "hello")).await.unwrap();
+        // The token can't be replayed while b is still connected.
+        let (result, _) = resume("5.5.5.5", &token);
+        assert_eq!(result.await.unwrap(), ConnectResult::Untrusted);
+        server
+            .send(Disconnect {
+                channel,
+                id: b,
+                reason: DisconnectReason::Timeout,
+            })
+            .await
+            .unwrap();
+        sent_metrics(&rx);
+
+        // Not from a participant's address, and no valid token.
+        let (result, _) = resume("5.5.5.5", "bogus");
+        assert_eq!(result.await.unwrap(), ConnectResult::Untrusted);
+
+        // b comes back from a new address, and picks up where it left off.
+        let (result, b_rcv) = resume("5.5.5.5", &token);
+        let b = match result.await.unwrap() {
+            ConnectResult::Ok(id) => id,
+            other => panic!("not resumed: {:?}", other),
+        };
+        assert!(sent_metrics(&rx).contains(&"test.conn.resumed:1|c".to_owned()));
+        server.send(send(b, r#"{"type":"stats"}"#)).await.unwrap();
+        let received: Vec<String> = b_rcv
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, text)| text.clone())
+            .collect();
+        let welcome: serde_json::Value = serde_json::from_str(&received[0]).unwrap();
+        // the slot, and so the token, stay the same
+        assert_eq!(welcome["resume"], token.as_str());
+        let stats: serde_json::Value = serde_json::from_str(received.last().unwrap()).unwrap();
+        assert_eq!(stats["messages"], 1);
+
+        // The token is no good once the channel has gone.
+        for id in [a, b, c] {
+            server
+                .send(Disconnect {
+                    channel,
+                    id,
+                    reason: DisconnectReason::None,
+                })
+                .await
+                .unwrap();
+        }
+        let (result, _) = resume("2.2.2.2", &token);
+        assert_eq!(result.await.unwrap(), ConnectResult::UnknownChannel);
+    }
+
+    #[actix_rt::test]
+    async fn test_caps() {
+        let settings = Settings {
+            relay_caps: true,
+            max_exchanges: 1,
+            ..Default::default()
+        };
+        let (server, _) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let send = |id: SessionId, msg: &str| ClientMessage {
+            id,
+            message_type: MessageType::Text,
+            msg: msg.to_owned(),
+            channel,
+            sender: meta::SenderData::default(),
+            received: Instant::now(),
+        };
+
+        let (a, a_rcv) = connect(&server, channel, "1.1.1.1", true).await;
+        let (b, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        for id in [a, b] {
+            server
+                .send(send(id, r#"{"type":"caps","features":["binary"]}"#))
+                .await
+                .unwrap();
+        }
+        for rcv in [&a_rcv, &b_rcv] {
+            let rcv = rcv.lock().unwrap();
+            let (msg_type, body) = rcv.last().unwrap();
+            assert_eq!(*msg_type, MessageType::Control);
+            let caps: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(caps, json!({"type": "caps", "features": ["binary"]}));
+        }
+        // only sent to the peer
+        assert_eq!(a_rcv.lock().unwrap().len(), 2);
+        assert_eq!(b_rcv.lock().unwrap().len(), 2);
+
+        // and the channel's one message is still there to send
+        server.send(send(a, "hello")).await.unwrap();
+        assert!(!terminated(&b_rcv));
+        assert_eq!(b_rcv.lock().unwrap().last().unwrap().0, MessageType::Text);
+    }
+
+    #[actix_rt::test]
+    async fn test_include_timestamp() {
+        let settings = Settings {
+            include_timestamp: true,
+            ..Default::default()
+        };
+        let (server, _) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let (a, _) = connect(&server, channel, "1.1.1.1", true).await;
+        let (_, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        for _ in 0..5 {
+            server
+                .send(ClientMessage {
+                    id: a,
+                    message_type: MessageType::Text,
+                    msg: "aBc12e".to_owned(),
+                    channel,
+                    sender: meta::SenderData::default(),
+                    received: Instant::now(),
+                })
+                .await
+                .unwrap();
+        }
+        let stamps: Vec<u64> = b_rcv.lock().unwrap()[1..]
+            .iter()
+            .map(|(_, msg)| {
+                let envelope: serde_json::Value = serde_json::from_str(msg).unwrap();
+                assert_eq!(envelope["message"], "aBc12e");
+                envelope["ts"].as_u64().unwrap()
+            })
+            .collect();
+        assert_eq!(stamps.len(), 5);
+        assert!(stamps[0] > 0);
+        assert!(stamps.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[actix_rt::test]
+    async fn test_snapshot_mode() {
+        let settings = Settings {
+            snapshot_mode: true,
+            ..Default::default()
+        };
+        let (server, _) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let (a, _) = connect(&server, channel, "1.1.1.1", true).await;
+        let (_, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        // nothing to catch up on yet
+        assert_eq!(b_rcv.lock().unwrap().len(), 1);
+        for msg in ["first", "second"] {
+            server
+                .send(ClientMessage {
+                    id: a,
+                    message_type: MessageType::Text,
+                    msg: msg.to_owned(),
+                    channel,
+                    sender: meta::SenderData::default(),
+                    received: Instant::now(),
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(b_rcv.lock().unwrap().len(), 3);
+
+        let (_, c_rcv) = connect(&server, channel, "1.1.1.1", false).await;
+        let c_rcv = c_rcv.lock().unwrap();
+        assert_eq!(c_rcv.len(), 2);
+        let snapshot: serde_json::Value = serde_json::from_str(&c_rcv[1].1).unwrap();
+        assert_eq!(snapshot["message"], "second");
+    }
+
+    #[actix_rt::test]
+    async fn test_relay_disabled() {
+        let settings = Settings {
+            relay_enabled: false,
+            expose_stats: true,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let (a, a_rcv) = connect(&server, channel, "1.1.1.1", true).await;
+        let (_, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        let send = |msg: &str| ClientMessage {
+            id: a,
+            message_type: MessageType::Text,
+            msg: msg.to_owned(),
+            channel,
+            sender: meta::SenderData::default(),
+            received: Instant::now(),
+        };
+        server.send(send("aBc12e")).await.unwrap();
+        assert_eq!(b_rcv.lock().unwrap().len(), 1);
+        assert!(sent_metrics(&rx).contains(&"test.conn.relay_disabled:1|c".to_owned()));
+
+        // Control requests still work.
+        server.send(send(r#"{"type":"stats"}"#)).await.unwrap();
+        assert_eq!(a_rcv.lock().unwrap().len(), 2);
+        assert_eq!(server.send(ChannelInfo(channel)).await.unwrap(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_control_flood() {
+        let settings = Settings {
+            expose_stats: true,
+            max_control_messages: 3,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let (a, a_rcv) = connect(&server, channel, "1.1.1.1", true).await;
+        let (_, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        for _ in 0..10 {
+            server
+                .send(ClientMessage {
+                    id: a,
+                    message_type: MessageType::Text,
+                    msg: r#"{"type":"stats"}"#.to_owned(),
+                    channel,
+                    sender: meta::SenderData::default(),
+                    received: Instant::now(),
+                })
+                .await
+                .unwrap();
+        }
+        // welcome, then only the budgeted replies.
+        assert_eq!(a_rcv.lock().unwrap().len(), 4);
+        // and the rest weren't relayed either.
+        assert_eq!(b_rcv.lock().unwrap().len(), 1);
+        let floods = sent_metrics(&rx)
+            .iter()
+            .filter(|m| *m == "test.conn.control_flood:1|c")
+            .count();
+        assert_eq!(floods, 7);
+    }
+
+    #[test]
+    fn test_control_request_parse() {
+        assert_eq!(
+            ControlRequest::parse(r#"{"type":"stats"}"#),
+            Some(ControlRequest::Stats)
         );
-        test_group.insert(
-            2,
-            Channel {
-                session_id: 1,
-                started: Instant::now(),
-                msg_count: 0,
-                data_exchanged: 0,
-                remote: Some("127.0.0.2".to_owned()),
-            },
+        assert_eq!(
+            ControlRequest::parse(r#"{"type":"caps","features":["binary",{"v":2}]}"#),
+            Some(ControlRequest::Caps(vec![json!("binary"), json!({"v": 2})]))
+        );
+        assert_eq!(ControlRequest::parse(r#"{"type":"caps"}"#), None);
+        assert_eq!(ControlRequest::parse(r#"{"type":"bogus"}"#), None);
+        assert_eq!(ControlRequest::parse(r#"{"stats"}"#), None);
+        assert_eq!(ControlRequest::parse("aBc12e"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_list_channels() {
+        let (server, _) = test_server(Settings::default());
+        let server = server.start();
+        for _ in 0..5 {
+            connect(&server, ChannelID::default(), "1.1.1.1", true).await;
+        }
+        let ids = |list: Vec<ChannelSummary>| -> Vec<ChannelID> {
+            list.into_iter().map(|summary| summary.channelid).collect()
+        };
+
+        let all = server.send(ListChannels::default()).await.unwrap();
+        assert_eq!(all.len(), 5);
+        assert!(all.iter().all(|summary| summary.participants == 1));
+        let all = ids(all);
+        let page = server
+            .send(ListChannels {
+                offset: 1,
+                limit: Some(2),
+            })
+            .await
+            .unwrap();
+        assert_eq!(ids(page), all[1..3]);
+        let rest = server
+            .send(ListChannels {
+                offset: 3,
+                limit: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(ids(rest), all[3..]);
+        let past_end = server
+            .send(ListChannels {
+                offset: 10,
+                limit: Some(2),
+            })
+            .await
+            .unwrap();
+        assert!(past_end.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_drain_channel() {
+        let (server, _) = test_server(Settings::default());
+        let server = server.start();
+        let channel = ChannelID::default();
+
+        let (_, a_rcv) = connect(&server, channel, "1.1.1.1", true).await;
+        let (_, b_rcv) = connect(&server, channel, "2.2.2.2", false).await;
+        let grace = Duration::from_millis(100);
+        assert!(server.send(DrainChannel(channel, grace)).await.unwrap());
+        assert!(!terminated(&a_rcv));
+        let (late, _) = try_connect(&server, channel, "3.3.3.3", false).await;
+        assert_eq!(late, ConnectResult::Draining);
+
+        actix_rt::time::sleep(grace * 2).await;
+        for rcv in [a_rcv, b_rcv] {
+            let rcv = rcv.lock().unwrap();
+            let (notice_type, notice) = &rcv[rcv.len() - 2];
+            assert_eq!(*notice_type, MessageType::Text);
+            let notice: serde_json::Value = serde_json::from_str(notice).unwrap();
+            assert_eq!(notice["type"], "draining");
+            assert_eq!(notice["retry_after"], 0);
+            assert_eq!(rcv.last().unwrap().0, MessageType::Drain);
+        }
+        assert!(server
+            .send(ListChannels::default())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(!server
+            .send(DrainChannel(ChannelID::default(), grace))
+            .await
+            .unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn test_drain_all() {
+        let (mut server, _) = test_server(Settings::default());
+        let mut received = Vec::new();
+        let mut next_id = 1;
+        // Three channels of two, with one session in the last already gone.
+        for n in 0..3 {
+            let channel = ChannelID::default();
+            let mut group = ChannelGroup::default();
+            for _ in 0..2 {
+                let id = next_id;
+                next_id += 1;
+                let addr = if n == 2 && id % 2 == 0 {
+                    let ctx: Context<TestClient> = Context::new();
+                    ctx.address()
+                } else {
+                    let client = TestClient::default();
+                    received.push(client.received.clone());
+                    client.start()
+                };
+                server.sessions.insert(id, addr.recipient().into());
+                group.participants.insert(id, party(id, None));
+            }
+            server.channels.insert(channel, group);
+        }
+        let readiness = server.readiness.clone();
+        readiness.set(true);
+        let server = server.start();
+
+        let report = server.send(DrainAll(Duration::ZERO)).await.unwrap();
+        assert!(!readiness.is_ready());
+        assert_eq!(
+            report,
+            DrainReport {
+                channels: 3,
+                sessions: 6,
+                failed: 1,
+            }
+        );
+        assert!(server
+            .send(ListChannels::default())
+            .await
+            .unwrap()
+            .is_empty());
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(received.len(), 5);
+        assert!(received.iter().all(terminated));
+    }
+
+    #[test]
+    fn test_closed_event() {
+        let mut group = ChannelGroup {
+            msg_count: 3,
+            data_exchanged: 120,
+            peak_participants: 2,
+            ..Default::default()
+        };
+        group.countries.insert("United States".to_owned());
+        group.countries.insert("Canada".to_owned());
+
+        let event = group.closed_event(&DisconnectReason::ExcessMessages);
+        let fields: BTreeSet<&str> = event
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            fields,
+            BTreeSet::from([
+                "event",
+                "correlation_id",
+                "duration_ms",
+                "messages",
+                "bytes",
+                "peak_participants",
+                "countries",
+                "reason",
+            ])
+        );
+        assert_eq!(event["event"], "channel_closed");
+        assert_eq!(event["correlation_id"], group.correlation_id.to_string());
+        assert_eq!(event["messages"], 3);
+        assert_eq!(event["bytes"], 120);
+        assert_eq!(event["peak_participants"], 2);
+        assert_eq!(event["countries"], json!(["Canada", "United States"]));
+        assert_eq!(event["reason"], "Excess Messages");
+    }
+
+    #[actix_rt::test]
+    async fn test_join_closed_channel() {
+        let (server, rx) = test_server(Settings::default());
+        let server = server.start();
+        let channel = ChannelID::default();
+
+        let (a, _) = connect(&server, channel, "1.1.1.1", true).await;
+        server
+            .send(Disconnect {
+                channel,
+                id: a,
+                reason: DisconnectReason::None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(connect(&server, channel, "2.2.2.2", false).await.0, 0);
+        assert!(sent_metrics(&rx).contains(&"test.conn.closed_channel:1|c".to_owned()));
+    }
+
+    #[actix_rt::test]
+    async fn test_distinct_ips() {
+        let settings = Settings {
+            distinct_ip_window: 1,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let gauges = |sent: &[String]| -> Vec<String> {
+            sent.iter()
+                .filter(|m| m.starts_with("test.conn.distinct_ips:"))
+                .cloned()
+                .collect()
+        };
+        for remote in ["1.1.1.1", "2.2.2.2", "1.1.1.1", "3.3.3.3"] {
+            connect(&server, ChannelID::default(), remote, true).await;
+        }
+        let first = gauges(&await_metrics(&rx, |sent| !gauges(sent).is_empty()).await);
+        assert_eq!(first, ["test.conn.distinct_ips:3|g"]);
+
+        // The next window starts afresh.
+        connect(&server, ChannelID::default(), "4.4.4.4", true).await;
+        let second = gauges(&await_metrics(&rx, |sent| !gauges(sent).is_empty()).await);
+        assert_eq!(second, ["test.conn.distinct_ips:1|g"]);
+    }
+
+    #[actix_rt::test]
+    async fn test_rejections_release_sessions() {
+        let settings = Settings {
+            max_channel_connections: 3,
+            principal_count: 1,
+            ..Default::default()
+        };
+        let (server, _) = test_server(settings);
+        let server = server.start();
+        for _ in 0..3 {
+            let unknown = try_connect(&server, ChannelID::default(), "1.1.1.1", false).await;
+            assert_eq!(unknown.0, ConnectResult::UnknownChannel);
+        }
+        assert_eq!(server.send(GetLoad).await.unwrap().sessions, 0);
+
+        let channel = ChannelID::default();
+        connect(&server, channel, "1.1.1.1", true).await;
+        connect(&server, channel, "2.2.2.2", false).await;
+        let untrusted = try_connect(&server, channel, "3.3.3.3", false).await.0;
+        assert_eq!(untrusted, ConnectResult::Untrusted);
+        connect(&server, channel, "1.1.1.1", false).await;
+        let full = try_connect(&server, channel, "1.1.1.1", false).await.0;
+        assert_eq!(full, ConnectResult::ChannelFull);
+        assert_eq!(server.send(GetLoad).await.unwrap().sessions, 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_connect_result() {
+        let settings = Settings {
+            max_channel_connections: 4,
+            principal_count: 2,
+            ..Default::default()
+        };
+        let (server, _) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let unknown = try_connect(&server, channel, "1.1.1.1", false).await.0;
+        assert_eq!(unknown, ConnectResult::UnknownChannel);
+        for (n, remote) in ["1.1.1.1", "2.2.2.2", "3.3.3.3"].iter().enumerate() {
+            let joined = try_connect(&server, channel, remote, n == 0).await.0;
+            assert!(matches!(joined, ConnectResult::Ok(_)));
+        }
+        let untrusted = try_connect(&server, channel, "4.4.4.4", false).await.0;
+        assert_eq!(untrusted, ConnectResult::Untrusted);
+        assert!(matches!(
+            try_connect(&server, channel, "1.1.1.1", false).await.0,
+            ConnectResult::Ok(_)
+        ));
+        let full = try_connect(&server, channel, "1.1.1.1", false).await.0;
+        assert_eq!(full, ConnectResult::ChannelFull);
+    }
+
+    #[actix_rt::test]
+    async fn test_welcome_metric() {
+        let settings = Settings {
+            max_channel_connections: 2,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        connect(&server, channel, "1.1.1.1", true).await;
+        connect(&server, channel, "2.2.2.2", false).await;
+        // full, so no welcome
+        connect(&server, channel, "3.3.3.3", false).await;
+        let welcomes: Vec<String> = sent_metrics(&rx)
+            .into_iter()
+            .filter(|m| m.starts_with("test.channel.welcome"))
+            .collect();
+        assert_eq!(
+            welcomes,
+            vec![
+                "test.channel.welcome:1|c|#type:new",
+                "test.channel.welcome:1|c|#type:existing"
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_integration_limit() {
+        let settings = Settings {
+            max_channels_per_integration: 1,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let create = |channel, integration: &str| Connect {
+            addr: TestClient::default().start().recipient(),
+            channel,
+            remote: Some("1.1.1.1".to_owned()),
+            country: None,
+            initial_connect: true,
+            integration: Some(integration.to_owned()),
+            limits: ChannelLimits::default(),
+            protocol: None,
+            buffered: Buffered::default(),
+            client_correlation_id: None,
+            resume: None,
+        };
+        let first = ChannelID::default();
+        let result = server.send(create(first, "a")).await.unwrap();
+        assert!(matches!(result, ConnectResult::Ok(_)));
+        let result = server
+            .send(create(ChannelID::default(), "a"))
+            .await
+            .unwrap();
+        assert_eq!(result, ConnectResult::AtCapacity);
+        assert!(sent_metrics(&rx).contains(&"test.conn.max.integration:1|c".to_owned()));
+        let reserved = ChannelID::default();
+        preallocate(&server, reserved, "1.1.1.1").await;
+        let result = server
+            .send(Connect {
+                initial_connect: false,
+                ..create(reserved, "a")
+            })
+            .await
+            .unwrap();
+        assert_eq!(result, ConnectResult::AtCapacity);
+        // Other integrations, and joining, aren't affected.
+        let result = server
+            .send(create(ChannelID::default(), "b"))
+            .await
+            .unwrap();
+        assert!(matches!(result, ConnectResult::Ok(_)));
+        assert_ne!(connect(&server, first, "2.2.2.2", false).await.0, 0);
+
+        // Closing the channel frees up the slot.
+        server
+            .send(DrainChannel(first, Duration::ZERO))
+            .await
+            .unwrap();
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        // The refused connect left the reservation in place.
+        let result = server
+            .send(Connect {
+                initial_connect: false,
+                ..create(reserved, "a")
+            })
+            .await
+            .unwrap();
+        assert!(matches!(result, ConnectResult::Ok(_)));
+    }
+
+    #[actix_rt::test]
+    async fn test_match_versions() {
+        let settings = Settings {
+            match_versions: true,
+            max_channel_connections: 5,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let join_from = |channel, remote: &str, initial_connect, protocol| Connect {
+            addr: TestClient::default().start().recipient(),
+            channel,
+            remote: Some(remote.to_owned()),
+            country: None,
+            initial_connect,
+            integration: None,
+            limits: ChannelLimits::default(),
+            protocol,
+            buffered: Buffered::default(),
+            client_correlation_id: None,
+            resume: None,
+        };
+        let join =
+            |initial_connect, protocol| join_from(channel, "1.1.1.1", initial_connect, protocol);
+        let result = server.send(join(true, Some(2))).await.unwrap();
+        assert!(matches!(result, ConnectResult::Ok(_)));
+        let result = server.send(join(false, Some(2))).await.unwrap();
+        assert!(matches!(result, ConnectResult::Ok(_)));
+        let result = server.send(join(false, Some(3))).await.unwrap();
+        assert_eq!(result, ConnectResult::VersionMismatch);
+        assert!(sent_metrics(&rx).contains(&"test.conn.version_mismatch:1|c".to_owned()));
+        // Clients that don't say are let in.
+        let result = server.send(join(false, None)).await.unwrap();
+        assert!(matches!(result, ConnectResult::Ok(_)));
+
+        // A client that isn't let in doesn't decide the channel's version.
+        let other = ChannelID::default();
+        for (remote, initial_connect) in [("1.1.1.1", true), ("2.2.2.2", false), ("1.1.1.1", false)]
+        {
+            let result = server
+                .send(join_from(other, remote, initial_connect, None))
+                .await
+                .unwrap();
+            assert!(matches!(result, ConnectResult::Ok(_)));
+        }
+        let result = server
+            .send(join_from(other, "5.5.5.5", false, Some(3)))
+            .await
+            .unwrap();
+        assert_eq!(result, ConnectResult::Untrusted);
+        let result = server
+            .send(join_from(other, "1.1.1.1", false, Some(2)))
+            .await
+            .unwrap();
+        assert!(matches!(result, ConnectResult::Ok(_)));
+    }
+
+    #[actix_rt::test]
+    async fn test_channel_limits() {
+        let settings = Settings {
+            max_channel_connections: 5,
+            max_exchanges: 10,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let creator = TestClient::default();
+        let received = creator.received.clone();
+        let result = server
+            .send(Connect {
+                addr: creator.start().recipient(),
+                channel,
+                remote: Some("1.1.1.1".to_owned()),
+                country: None,
+                initial_connect: true,
+                integration: None,
+                limits: ChannelLimits {
+                    max_connections: Some(2),
+                    max_exchanges: Some(1),
+                    ..Default::default()
+                },
+                protocol: None,
+                buffered: Buffered::default(),
+                client_correlation_id: None,
+                resume: None,
+            })
+            .await
+            .unwrap();
+        let ConnectResult::Ok(id) = result else {
+            panic!("Expected to connect, got {:?}", result);
+        };
+        assert!(matches!(
+            try_connect(&server, channel, "2.2.2.2", false).await.0,
+            ConnectResult::Ok(_)
+        ));
+        assert_eq!(
+            try_connect(&server, channel, "1.1.1.1", false).await.0,
+            ConnectResult::ChannelFull
+        );
+        for msg in ["first", "second"] {
+            server
+                .send(ClientMessage {
+                    id,
+                    message_type: MessageType::Text,
+                    msg: msg.to_owned(),
+                    channel,
+                    sender: meta::SenderData::default(),
+                    received: Instant::now(),
+                })
+                .await
+                .unwrap();
+        }
+        assert!(sent_metrics(&rx).contains(&"test.conn.max.msg:1|c".to_owned()));
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        assert!(terminated(&received));
+    }
+
+    #[actix_rt::test]
+    async fn test_report_violators() {
+        for report in [true, false] {
+            let (iprepd, requests) = crate::ip_rate_limit::test::mock_iprepd();
+            let settings = Settings {
+                max_exchanges: 1,
+                ip_reputation_server: iprepd,
+                iprepd_report_violators: report,
+                ..Default::default()
+            };
+            let (server, _rx) = test_server(settings);
+            let server = server.start();
+            let channel = ChannelID::default();
+            let (id, _) = connect(&server, channel, "1.1.1.1", true).await;
+            connect(&server, channel, "2.2.2.2", false).await;
+            for msg in ["first", "second"] {
+                server
+                    .send(ClientMessage {
+                        id,
+                        message_type: MessageType::Text,
+                        msg: msg.to_owned(),
+                        channel,
+                        sender: meta::SenderData::default(),
+                        received: Instant::now(),
+                    })
+                    .await
+                    .unwrap();
+            }
+            let expected = usize::from(report);
+            // Give a report that shouldn't be made time to show up.
+            if !report {
+                actix_rt::time::sleep(Duration::from_millis(50)).await;
+            }
+            for _ in 0..100 {
+                if requests.load(Ordering::SeqCst) >= expected {
+                    break;
+                }
+                actix_rt::time::sleep(Duration::from_millis(5)).await;
+            }
+            assert_eq!(requests.load(Ordering::SeqCst), expected, "{}", report);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_client_correlation_id() {
+        let settings = Settings {
+            max_exchanges: 1,
+            ..Default::default()
+        };
+        let (mut server, _rx) = test_server(settings);
+        let buffer = logging::test::Buffer::default();
+        let log = slog::Logger::root(logging::Logfmt::new(buffer.clone()).fuse(), slog::o!());
+        server.log = MozLogger { log: log.clone() };
+        server.events = Some(MozLogger { log });
+        let server = server.start();
+        let channel = ChannelID::default();
+        let client = TestClient::default();
+        let result = server
+            .send(Connect {
+                addr: client.start().recipient(),
+                channel,
+                remote: Some("1.1.1.1".to_owned()),
+                country: None,
+                initial_connect: true,
+                integration: None,
+                limits: ChannelLimits::default(),
+                protocol: None,
+                buffered: Buffered::default(),
+                client_correlation_id: Some("trace-123".to_owned()),
+                resume: None,
+            })
+            .await
+            .unwrap();
+        let ConnectResult::Ok(id) = result else {
+            panic!("Expected to connect, got {:?}", result);
+        };
+        connect(&server, channel, "2.2.2.2", false).await;
+        for msg in ["first", "second"] {
+            server
+                .send(ClientMessage {
+                    id,
+                    message_type: MessageType::Text,
+                    msg: msg.to_owned(),
+                    channel,
+                    sender: meta::SenderData::default(),
+                    received: Instant::now(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let logged = buffer.take();
+        let lines: Vec<&str> = logged.lines().collect();
+        for msg in [
+            "Adding session to channel",
+            "Too many messages",
+            "channel_closed",
+        ] {
+            let line = lines
+                .iter()
+                .find(|line| line.contains(msg))
+                .unwrap_or_else(|| panic!("{:?} not logged in {:?}", msg, logged));
+            assert!(line.contains("trace-123"), "{:?}", line);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_max_channels() {
+        let settings = Settings {
+            max_channels: 1,
+            max_sessions: 3,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        assert_ne!(connect(&server, channel, "1.1.1.1", true).await.0, 0);
+        // a second channel is refused, but joining the first is fine.
+        let (refused, _) = connect(&server, ChannelID::default(), "2.2.2.2", true).await;
+        assert_eq!(refused, 0);
+        assert_ne!(connect(&server, channel, "2.2.2.2", false).await.0, 0);
+        assert!(sent_metrics(&rx).contains(&"test.conn.max.channels:1|c".to_owned()));
+
+        assert_ne!(connect(&server, channel, "1.1.1.1", false).await.0, 0);
+        assert_eq!(connect(&server, channel, "2.2.2.2", false).await.0, 0);
+        assert!(sent_metrics(&rx).contains(&"test.conn.max.sessions:1|c".to_owned()));
+    }
+
+    #[actix_rt::test]
+    async fn test_channel_status() {
+        let settings = Settings {
+            max_channel_connections: 2,
+            ..Default::default()
+        };
+        let (server, _rx) = test_server(settings);
+        let server = server.start();
+        let channel = ChannelID::default();
+        let status = |participants, full| ChannelStatusReport {
+            exists: true,
+            participants,
+            full,
+        };
+        assert_eq!(
+            server.send(ChannelStatus(channel)).await.unwrap(),
+            ChannelStatusReport {
+                exists: false,
+                participants: 0,
+                full: false,
+            }
+        );
+        preallocate(&server, channel, "1.1.1.1").await;
+        assert_eq!(
+            server.send(ChannelStatus(channel)).await.unwrap(),
+            status(0, false)
+        );
+        connect(&server, channel, "1.1.1.1", false).await;
+        assert_eq!(
+            server.send(ChannelStatus(channel)).await.unwrap(),
+            status(1, false)
+        );
+        connect(&server, channel, "2.2.2.2", false).await;
+        assert_eq!(
+            server.send(ChannelStatus(channel)).await.unwrap(),
+            status(2, true)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_evict_on_full() {
+        let settings = Settings {
+            max_channels: 2,
+            evict_on_full: true,
+            evict_idle_secs: 0,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings.clone());
+        let server = server.start();
+        let oldest = ChannelID::default();
+        let (_, oldest_rcv) = connect(&server, oldest, "1.1.1.1", true).await;
+        let newer = ChannelID::default();
+        let (_, newer_rcv) = connect(&server, newer, "2.2.2.2", true).await;
+
+        let (id, _) = connect(&server, ChannelID::default(), "3.3.3.3", true).await;
+        assert_ne!(id, 0);
+        assert!(sent_metrics(&rx).contains(&"test.channel.evicted:1|c".to_owned()));
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            oldest_rcv.lock().unwrap().last().unwrap().0,
+            MessageType::Evict
+        );
+        assert!(!terminated(&newer_rcv));
+        // Joining the evicted channel finds it gone.
+        assert_eq!(
+            try_connect(&server, oldest, "1.1.1.1", false).await.0,
+            ConnectResult::UnknownChannel
+        );
+
+        // Channels that aren't idle yet are left alone.
+        let (server, _rx) = test_server(Settings {
+            evict_idle_secs: 60,
+            ..settings
+        });
+        let server = server.start();
+        connect(&server, ChannelID::default(), "1.1.1.1", true).await;
+        connect(&server, ChannelID::default(), "2.2.2.2", true).await;
+        assert_eq!(
+            try_connect(&server, ChannelID::default(), "3.3.3.3", true)
+                .await
+                .0,
+            ConnectResult::AtCapacity
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_subnet_limit() {
+        let settings = Settings {
+            max_connections_per_subnet: 2,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let first = ChannelID::default();
+        let (first_id, _) = connect(&server, first, "10.1.2.3", true).await;
+        assert_ne!(first_id, 0);
+        for (remote, accepted) in [
+            ("10.1.2.4", true),
+            ("10.1.2.5", false),
+            ("10.1.3.1", true),
+            ("2001:db8::1", true),
+            ("2001:db8::2", true),
+            ("2001:db8::3", false),
+            ("2001:db8:0:1::1", true),
+        ] {
+            let (id, _) = connect(&server, ChannelID::default(), remote, true).await;
+            assert_eq!(id != 0, accepted, "{}", remote);
+        }
+        assert!(sent_metrics(&rx).contains(&"test.conn.max.subnet:1|c".to_owned()));
+
+        // Leaving frees up a slot.
+        server
+            .send(Disconnect {
+                id: first_id,
+                channel: first,
+                reason: DisconnectReason::None,
+            })
+            .await
+            .unwrap();
+        let (id, _) = connect(&server, ChannelID::default(), "10.1.2.5", true).await;
+        assert_ne!(id, 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_preallocate() {
+        let (server, rx) = test_server(Settings::default());
+        let server = server.start();
+        let channel = ChannelID::default();
+        let PreallocateResult::Ok(welcome) = preallocate(&server, channel, "1.2.3.4").await else {
+            panic!("not preallocated");
+        };
+        assert!(welcome.contains(&channel.as_string()));
+        assert!(sent_metrics(&rx).contains(&"test.channel.preallocated:1|c".to_owned()));
+        // Can't allocate the same id twice.
+        assert_eq!(
+            preallocate(&server, channel, "1.2.3.4").await,
+            PreallocateResult::Taken
         );
 
+        let (id, _) = connect(&server, channel, "1.2.3.4", false).await;
+        assert_ne!(id, 0);
+        let (id, _) = connect(&server, channel, "1.2.3.5", false).await;
+        assert_ne!(id, 0);
+        let (id, _) = connect(&server, ChannelID::default(), "1.2.3.4", false).await;
+        assert_eq!(id, 0);
+
+        // Unused allocations expire.
+        let (mut server, _) = test_server(Settings::default());
+        let expired = ChannelID::default();
+        let reserve = Preallocate {
+            channel: expired,
+            remote: None,
+        };
+        let result = server.handle(reserve, &mut Context::new());
+        assert!(matches!(result, PreallocateResult::Ok(_)));
+        server
+            .preallocated
+            .values_mut()
+            .for_each(|reservation| reservation.expires -= Duration::from_secs(60));
+        let server = server.start();
+        let (id, _) = connect(&server, expired, "1.2.3.4", false).await;
+        assert_eq!(id, 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_preallocate_limits() {
+        let settings = Settings {
+            max_channels: 2,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let reserved = ChannelID::default();
+        let result = preallocate(&server, reserved, "1.1.1.1").await;
+        assert!(matches!(result, PreallocateResult::Ok(_)));
+        let (id, _) = connect(&server, ChannelID::default(), "2.2.2.2", true).await;
+        assert_ne!(id, 0);
+        // The reservation counts as an open channel...
+        assert_eq!(
+            preallocate(&server, ChannelID::default(), "3.3.3.3").await,
+            PreallocateResult::AtCapacity
+        );
+        let (id, _) = connect(&server, ChannelID::default(), "3.3.3.3", true).await;
+        assert_eq!(id, 0);
+        assert!(sent_metrics(&rx).contains(&"test.conn.max.channels:1|c".to_owned()));
+        // ...so there's room for it when its client arrives.
+        let (id, _) = connect(&server, reserved, "4.4.4.4", false).await;
+        assert_ne!(id, 0);
+
+        let settings = Settings {
+            max_connections_per_subnet: 2,
+            ..Default::default()
+        };
+        let (server, rx) = test_server(settings);
+        let server = server.start();
+        let (id, _) = connect(&server, ChannelID::default(), "10.0.0.1", true).await;
+        assert_ne!(id, 0);
+        let reserved = ChannelID::default();
+        let result = preallocate(&server, reserved, "10.0.0.2").await;
+        assert!(matches!(result, PreallocateResult::Ok(_)));
+        assert_eq!(
+            preallocate(&server, ChannelID::default(), "10.0.0.3").await,
+            PreallocateResult::AtCapacity
+        );
+        assert!(sent_metrics(&rx).contains(&"test.conn.max.subnet:1|c".to_owned()));
+        let result = preallocate(&server, ChannelID::default(), "10.0.1.1").await;
+        assert!(matches!(result, PreallocateResult::Ok(_)));
+    }
+
+    #[actix_rt::test]
+    async fn test_principal_count() {
+        let remotes = ["1.1.1.1", "2.2.2.2", "3.3.3.3", "4.4.4.4", "5.5.5.5"];
+        let settings = Settings {
+            max_channel_connections: 5,
+            principal_count: 5,
+            ..Default::default()
+        };
+        let (server, _) = test_server(settings.clone());
+        let server = server.start();
+        let channel = ChannelID::default();
+        for (n, remote) in remotes.iter().enumerate() {
+            let (id, _) = connect(&server, channel, remote, n == 0).await;
+            assert_ne!(id, 0, "{}", remote);
+        }
+
+        // With the pairing default, strangers can't join once there are more
+        // than two parties.
+        let (server, _) = test_server(Settings {
+            principal_count: 2,
+            ..settings
+        });
+        let server = server.start();
+        let channel = ChannelID::default();
+        let mut joined = Vec::new();
+        for (n, remote) in remotes.iter().enumerate() {
+            let (id, _) = connect(&server, channel, remote, n == 0).await;
+            joined.push(id != 0);
+        }
+        assert_eq!(joined, [true, true, true, false, false]);
+        let (id, _) = connect(&server, channel, "2.2.2.2", false).await;
+        assert_ne!(id, 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_backpressure() {
+        let (server, rx) = test_server(Settings::default());
+        let received = Received::default();
+        let client = {
+            let received = received.clone();
+            TestClient::create(|ctx| {
+                ctx.set_mailbox_capacity(1);
+                TestClient { received }
+            })
+        };
+        let addr = client.recipient().into();
+        // The client can't run until we yield, so its mailbox fills up.
+        let results: Vec<Delivery> = (0..4)
+            .map(|_| {
+                let msg = TextMessage(MessageType::Text, "hello".to_owned());
+                deliver(&addr, msg, &server.metrics, false)
+            })
+            .collect();
+        assert_eq!(results[0], Delivery::Sent);
+        assert_eq!(results[3], Delivery::Backlogged);
+        assert!(sent_metrics(&rx).contains(&"test.relay.backpressure:1|c".to_owned()));
+
+        // but nothing was dropped.
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(received.lock().unwrap().len(), 4);
+    }
+
+    #[actix_rt::test]
+    async fn test_prune_gone_session() {
+        let (mut server, _) = test_server(Settings::default());
+        let channel = ChannelID::default();
+        let live = TestClient::default().start();
+        let gone = {
+            let ctx: Context<TestClient> = Context::new();
+            ctx.address()
+        };
+        assert_eq!(
+            deliver(
+                &gone.clone().recipient().into(),
+                TextMessage::terminate(),
+                &server.metrics,
+                false
+            ),
+            Delivery::Gone
+        );
+
+        let mut group = ChannelGroup::default();
+        for (id, addr) in [(1, live.recipient()), (2, gone.recipient())] {
+            server.sessions.insert(id, addr.into());
+            group.participants.insert(id, party(id, None));
+        }
+        server.channels.insert(channel, group);
+        server
+            .send_message(&channel, "hello", 1, Instant::now())
+            .unwrap();
+        assert!(server.sessions.contains_key(&1));
+        assert!(!server.sessions.contains_key(&2));
+        // and from its channel, so it isn't counted or relayed to
+        let participants = &server.channels[&channel].participants;
+        assert!(participants.contains_key(&1));
+        assert!(!participants.contains_key(&2));
+    }
+
+    #[test]
+    fn test_near_limit() {
+        let (mut server, rx) = test_server(Settings {
+            max_exchanges: 10,
+            max_data: 100,
+            near_limit_percent: 80,
+            ..Default::default()
+        });
+        let channel = ChannelID::default();
+        let mut group = ChannelGroup::default();
+        group.participants.insert(1, party(1, None));
+        server.channels.insert(channel, group);
+        let near_limits = |rx: &Receiver<Vec<u8>>| -> Vec<String> {
+            sent_metrics(rx)
+                .into_iter()
+                .filter(|m| m.starts_with("test.conn.near_limit"))
+                .collect()
+        };
+        for _ in 0..7 {
+            server
+                .send_message(&channel, "0123456789", 1, Instant::now())
+                .unwrap();
+        }
+        assert!(near_limits(&rx).is_empty());
+        for _ in 0..2 {
+            server
+                .send_message(&channel, "0123456789", 1, Instant::now())
+                .unwrap();
+        }
+        assert_eq!(
+            near_limits(&rx),
+            vec![
+                "test.conn.near_limit:1|c|#limit:data",
+                "test.conn.near_limit:1|c|#limit:messages"
+            ]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_mailbox_overflow() {
+        let (mut server, rx) = test_server(Settings {
+            session_mailbox_cap: 1,
+            ..Default::default()
+        });
+        let channel = ChannelID::default();
+        let (sender, stalled) = (Received::default(), Received::default());
+        let mut group = ChannelGroup::default();
+        for (id, received) in [(1, sender.clone()), (2, stalled.clone())] {
+            let client = TestClient::create(|ctx| {
+                ctx.set_mailbox_capacity(1);
+                TestClient { received }
+            });
+            server.sessions.insert(id, client.recipient().into());
+            group.participants.insert(id, party(id, None));
+        }
+        server.channels.insert(channel, group);
+        // Nothing runs until we yield, so the stalled client can't keep up.
+        for _ in 0..4 {
+            server
+                .send_message(&channel, "hello", 1, Instant::now())
+                .unwrap();
+        }
+        assert!(sent_metrics(&rx).contains(&"test.conn.mailbox_overflow:1|c".to_owned()));
+        let group = &server.channels[&channel];
+        assert!(group.participants.contains_key(&1));
+        assert!(!group.participants.contains_key(&2));
+
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        assert!(terminated(&stalled));
+        assert!(sender.lock().unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_session_ids() {
+        let (mut server, _) = test_server(Settings::default());
+        let taken = TestClient::default();
+        let taken_rcv = taken.received.clone();
+        server.sessions.insert(1, taken.start().recipient().into());
+        server.last_session_id = usize::MAX;
+        // Wrapped around, past 0 and the id still in use.
+        assert_eq!(server.next_session_id(), 2);
+
+        // The next id is already taken, as if the counter had come all the way round.
+        server.last_session_id = 0;
+        let server = server.start();
+        let channel = ChannelID::default();
+        let (id, received) = connect(&server, channel, "1.1.1.1", true).await;
+        assert_eq!(id, 2);
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        assert!(taken_rcv.lock().unwrap().is_empty());
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_sweep() {
+        let (mut server, rx) = test_server(Settings {
+            conn_lifespan: 300,
+            idle_grace: 30,
+            ..Default::default()
+        });
+        let live = ChannelID::default();
+        let abandoned = ChannelID::default();
+        let old = ChannelID::default();
+        server
+            .sessions
+            .insert(1, TestClient::default().start().recipient().into());
+        let mut group = ChannelGroup::default();
+        group.participants.insert(1, party(1, None));
+        server.channels.insert(live, group);
+        // Its only participant vanished without disconnecting.
+        let mut group = ChannelGroup::default();
+        group.participants.insert(2, party(2, None));
+        server.channels.insert(abandoned, group);
+        server
+            .sessions
+            .insert(3, TestClient::default().start().recipient().into());
+        let mut group = ChannelGroup::default();
+        group.participants.insert(3, party(3, None));
+        group.created = Instant::now() - Duration::from_secs(301);
+        server.channels.insert(old, group);
+
+        server.sweep();
+        assert!(!server.channels.contains_key(&old));
+        // Given `idle_grace` to come back.
+        assert!(server.channels[&abandoned].vacant_since.is_some());
+        server.channels.get_mut(&abandoned).unwrap().vacant_since =
+            Some(Instant::now() - Duration::from_secs(31));
+        server.sweep();
+        assert!(!server.channels.contains_key(&abandoned));
+        assert!(server.channels[&live].vacant_since.is_none());
+        assert_eq!(
+            sent_metrics(&rx)
+                .iter()
+                .filter(|m| *m == "test.channel.swept:1|c")
+                .count(),
+            2
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_shed_buffered() {
+        let (mut server, rx) = test_server(Settings {
+            max_total_buffered_bytes: 60,
+            ..Default::default()
+        });
+        let channel = ChannelID::default();
+        let live = Received::default();
+        // Never started, so it never picks anything up.
+        let stalled: Context<TestClient> = Context::new();
+        let sessions: [(SessionId, SessionAddr); 3] = [
+            (1, TestClient::default().start().recipient().into()),
+            (
+                2,
+                TestClient {
+                    received: live.clone(),
+                }
+                .start()
+                .recipient()
+                .into(),
+            ),
+            (3, stalled.address().recipient().into()),
+        ];
+        // It has fallen behind already.
+        sessions[2].1.buffered.add(50);
+        let mut group = ChannelGroup::default();
+        for (id, addr) in sessions {
+            server.sessions.insert(id, addr);
+            group.participants.insert(id, party(id, None));
+        }
+        server.channels.insert(channel, group);
+
+        server
+            .send_message(&channel, "hello", 1, Instant::now())
+            .unwrap();
+        server.emit_gauges();
+        assert!(sent_metrics(&rx).contains(&"test.buffer.total_bytes:60|g".to_owned()));
+        server.shed_buffered();
+        assert!(server.sessions.contains_key(&3));
+
+        server
+            .send_message(&channel, "hello", 1, Instant::now())
+            .unwrap();
+        server.shed_buffered();
+        assert!(sent_metrics(&rx).contains(&"test.buffer.shed:1|c".to_owned()));
+        // Only the slowest was dropped.
+        let group = &server.channels[&channel];
+        assert!(!group.participants.contains_key(&3));
+        assert!(group.participants.contains_key(&2));
+        assert_eq!(server.total_buffered(), 10);
+
+        actix_rt::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(live.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let mut test_group: Channels = HashMap::new();
+
+        test_group.insert(1, party(1, Some("127.0.0.1")));
+        test_group.insert(2, party(1, Some("127.0.0.2")));
+
         assert!(!reconnect_check(&test_group, &None, None));
         assert!(!reconnect_check(
             &test_group,
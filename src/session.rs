@@ -1,9 +1,14 @@
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
 use cadence::{CountedExt, StatsdClient, Timed};
 use ipnet::IpNet;
-use slog::{debug, error, info};
+use serde_json::json;
+use slog::{debug, error, info, warn};
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use actix::{
     fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, ContextFutureSpawner, Handler,
@@ -11,29 +16,91 @@ use actix::{
 };
 use actix_web_actors::ws;
 
+use crate::audit;
+use crate::auth_hook::AuthHook;
+use crate::channel_token;
 use crate::channelid;
+use crate::event_bus;
+use crate::geo_cache::GeoCache;
+use crate::ip_rate_limit::IPReputation;
 use crate::logging;
 use crate::meta;
+use crate::metrics;
 use crate::server;
 use crate::settings;
-use crate::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL};
+
+// Websocket close code sent when the channel is evicted, per `evict_on_full`.
+const EVICT_CLOSE_CODE: u16 = 4002;
+
+// Most client messages held before the server registers the session. Each is
+// at most `max_message_size`, so this bounds what a client can make us hold.
+const PENDING_LIMIT: usize = 4;
+
+// How long `admin_rate_limit` applies over.
+const ADMIN_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Open the GeoIP database, checking that it can be searched, so a corrupt
+/// file is caught when it's loaded rather than by a connection.
+fn open_iploc(path: &str) -> Result<maxminddb::Reader<Vec<u8>>, maxminddb::MaxMindDBError> {
+    let iploc = maxminddb::Reader::open_readfile(path)?;
+    match iploc.lookup::<maxminddb::geoip2::City>(IpAddr::from([63, 245, 208, 195])) {
+        Err(err @ maxminddb::MaxMindDBError::InvalidDatabaseError(_)) => Err(err),
+        _ => Ok(iploc),
+    }
+}
 
 pub struct WsChannelSessionState {
     pub log: logging::MozLogger,
     pub metrics: Arc<StatsdClient>,
-    pub settings: settings::Settings,
-    pub iploc: maxminddb::Reader<Vec<u8>>,
+    pub settings: Arc<settings::Settings>,
+    /// swapped for a fresh copy of `mmdb_loc` by `reload_iploc`
+    pub iploc: ArcSwap<maxminddb::Reader<Vec<u8>>>,
+    /// the `asn_mmdb_loc` database, if set, swapped by `reload_asnloc`
+    pub asnloc: Option<ArcSwap<maxminddb::Reader<Vec<u8>>>>,
     pub trusted_proxy_list: Vec<IpNet>,
+    pub remote_ip_sources: Vec<String>,
+    pub reserved_channels: Vec<String>,
+    pub backlog: server::Backlog,
+    pub geo_limiter: meta::GeoLimiter,
+    /// recent lookups, if `geo_cache_size` is set
+    pub geo_cache: Option<Mutex<GeoCache>>,
+    pub audit: audit::AuditLog,
+    /// websocket upgrades allowed in progress at once, if limited
+    pub upgrades: Option<Arc<Semaphore>>,
+    /// refuse new channels while set, letting existing ones finish
+    pub maintenance: Arc<AtomicBool>,
+    /// asks `auth_hook_url` whether to allow connections, if set
+    pub auth_hook: Option<AuthHook>,
+    /// checks addresses with `ip_reputation_server`, if set; the
+    /// `ChannelServer`'s own, so both share one client and report cooldown
+    pub iprep: Option<Arc<IPReputation>>,
+    /// when the current `admin_rate_limit` window started, and the admin
+    /// requests made in it
+    pub admin_requests: Mutex<(Instant, u32)>,
+    /// metrics to serve on `prometheus_port`, if set
+    pub prometheus: Option<metrics::PrometheusRegistry>,
 }
 
 impl std::fmt::Debug for WsChannelSessionState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f,
-            "WsChannelSessionState{{ log: {:?}, metrics: {:?}, settings: {:?}, iploc: ..., trusted_proxy_list: {:?}}}",
+            "WsChannelSessionState{{ log: {:?}, metrics: {:?}, settings: {:?}, iploc: ..., trusted_proxy_list: {:?}, remote_ip_sources: {:?}, reserved_channels: {:?}, backlog: {:?}, geo_limiter: {:?}, geo_cache: {:?}, audit: {:?}, upgrades: {:?}, maintenance: {:?}, auth_hook: {:?}, iprep: {:?}, admin_requests: {:?}, prometheus: {:?}}}",
             self.log,
             self.metrics,
             self.settings,
             self.trusted_proxy_list,
+            self.remote_ip_sources,
+            self.reserved_channels,
+            self.backlog,
+            self.geo_limiter,
+            self.geo_cache,
+            self.audit,
+            self.upgrades,
+            self.maintenance,
+            self.auth_hook,
+            self.iprep,
+            self.admin_requests,
+            self.prometheus,
         )
     }
 }
@@ -43,15 +110,28 @@ impl WsChannelSessionState {
         settings: &settings::Settings,
         log: &logging::MozLogger,
         metrics: &Arc<StatsdClient>,
+        backlog: &server::Backlog,
     ) -> Self {
-        let iploc = maxminddb::Reader::open_readfile(&settings.mmdb_loc).unwrap_or_else(|_| {
+        let iploc = open_iploc(&settings.mmdb_loc).unwrap_or_else(|err| {
             panic!(
-                "Could not find mmdb file at {:?}/{}",
+                "Could not load mmdb file at {:?}/{}: {:?}",
                 std::env::current_dir().unwrap().as_path().to_string_lossy(),
-                settings.mmdb_loc
+                settings.mmdb_loc,
+                err
             )
         });
 
+        let asnloc = (!settings.asn_mmdb_loc.is_empty()).then(|| {
+            maxminddb::Reader::open_readfile(&settings.asn_mmdb_loc).unwrap_or_else(|err| {
+                panic!(
+                    "Could not load ASN mmdb file at {:?}/{}: {:?}",
+                    std::env::current_dir().unwrap().as_path().to_string_lossy(),
+                    settings.asn_mmdb_loc,
+                    err
+                )
+            })
+        });
+
         let mut trusted_list: Vec<IpNet> = Vec::new();
         trusted_list.push("10.0.0.0/8".parse().unwrap());
         trusted_list.push("172.16.0.0/12".parse().unwrap());
@@ -78,12 +158,199 @@ impl WsChannelSessionState {
                 }
             }
         }
+        let remote_ip_sources = if settings.remote_ip_sources.trim().is_empty() {
+            meta::DEFAULT_REMOTE_IP_SOURCES
+        } else {
+            &settings.remote_ip_sources
+        }
+        .split(',')
+        .map(|source| source.trim().to_ascii_lowercase())
+        .filter(|source| !source.is_empty())
+        .collect();
+        // Entries ending in "*" reserve every channel id with that prefix.
+        let reserved_channels = settings
+            .reserved_channels
+            .split(',')
+            .map(|id| id.trim().trim_end_matches('=').to_owned())
+            .filter(|id| !id.is_empty())
+            .collect();
         WsChannelSessionState {
             log: log.clone(),
             metrics: metrics.clone(),
-            settings: settings.clone(),
+            settings: Arc::new(settings.clone()),
             trusted_proxy_list: trusted_list,
-            iploc,
+            remote_ip_sources,
+            reserved_channels,
+            iploc: ArcSwap::from_pointee(iploc),
+            asnloc: asnloc.map(ArcSwap::from_pointee),
+            backlog: backlog.clone(),
+            geo_limiter: meta::GeoLimiter::new(
+                settings.max_concurrent_geo,
+                Duration::from_millis(settings.geo_wait_ms),
+            ),
+            geo_cache: (settings.geo_cache_size > 0)
+                .then(|| Mutex::new(GeoCache::new(settings.geo_cache_size))),
+            audit: audit::AuditLog::new(settings)
+                .with_events(event_bus::EventBus::new(settings, log, metrics)),
+            upgrades: if settings.max_inflight_upgrades > 0 {
+                Some(Arc::new(Semaphore::new(settings.max_inflight_upgrades)))
+            } else {
+                None
+            },
+            maintenance: Arc::new(AtomicBool::new(false)),
+            auth_hook: AuthHook::new(settings, log),
+            // Shared from the `ChannelServer` once it's built.
+            iprep: None,
+            admin_requests: Mutex::new((Instant::now(), 0)),
+            prometheus: None,
+        }
+    }
+}
+
+impl WsChannelSessionState {
+    /// Claim a slot for a websocket upgrade, held until the permit is dropped.
+    ///
+    /// Returns `None` if `max_inflight_upgrades` are already in progress.
+    pub fn start_upgrade(&self) -> Option<Option<SemaphorePermit<'_>>> {
+        match &self.upgrades {
+            Some(upgrades) => upgrades.try_acquire().ok().map(Some),
+            None => Some(None),
+        }
+    }
+
+    /// Count an admin request, returning false if `admin_rate_limit` were
+    /// already made this minute.
+    pub fn admit_admin(&self) -> bool {
+        let limit = self.settings.admin_rate_limit;
+        if limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut window = self
+            .admin_requests
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if now.duration_since(window.0) >= ADMIN_RATE_WINDOW {
+            *window = (now, 0);
+        }
+        if window.1 >= limit {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+
+    /// How many websocket upgrades are in progress, if they're limited.
+    pub fn upgrades_in_progress(&self) -> usize {
+        self.upgrades
+            .as_ref()
+            .map(|upgrades| {
+                self.settings
+                    .max_inflight_upgrades
+                    .saturating_sub(upgrades.available_permits())
+            })
+            .unwrap_or(0)
+    }
+
+    /// Re-read `mmdb_loc`, so the GeoIP data can be updated without a
+    /// restart. Lookups in progress finish with the old data. On failure,
+    /// the old data is kept.
+    pub fn reload_iploc(&self) -> bool {
+        match open_iploc(&self.settings.mmdb_loc) {
+            Ok(iploc) => {
+                self.iploc.store(Arc::new(iploc));
+                // Don't keep serving what the old data said.
+                if let Some(Ok(mut cache)) = self.geo_cache.as_ref().map(Mutex::lock) {
+                    cache.clear();
+                }
+                info!(self.log.log, "Reloaded geoip database"; "path" => &self.settings.mmdb_loc);
+                true
+            }
+            Err(err) => {
+                error!(
+                    self.log.log,
+                    "Could not reload geoip database: {:?}", err;
+                    "path" => &self.settings.mmdb_loc,
+                );
+                false
+            }
+        }
+    }
+
+    /// Re-read `asn_mmdb_loc`, as `reload_iploc` does `mmdb_loc`.
+    pub fn reload_asnloc(&self) -> bool {
+        let Some(asnloc) = &self.asnloc else {
+            return false;
+        };
+        match maxminddb::Reader::open_readfile(&self.settings.asn_mmdb_loc) {
+            Ok(reader) => {
+                asnloc.store(Arc::new(reader));
+                info!(self.log.log, "Reloaded ASN database"; "path" => &self.settings.asn_mmdb_loc);
+                true
+            }
+            Err(err) => {
+                error!(
+                    self.log.log,
+                    "Could not reload ASN database: {:?}", err;
+                    "path" => &self.settings.asn_mmdb_loc,
+                );
+                false
+            }
+        }
+    }
+
+    pub fn set_maintenance(&self, maintenance: bool) {
+        self.maintenance.store(maintenance, Ordering::Relaxed);
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    /// Is the channel id set aside for internal use?
+    pub fn is_reserved(&self, channel: &str) -> bool {
+        let channel = channel.trim_end_matches('=');
+        self.reserved_channels
+            .iter()
+            .any(|reserved| match reserved.strip_suffix('*') {
+                Some(prefix) => !prefix.is_empty() && channel.starts_with(prefix),
+                None => channel == reserved,
+            })
+    }
+}
+
+/// Measures round trip time using heartbeat pings.
+///
+/// Each ping carries a sequence number, so a late pong for an earlier ping
+/// isn't mistaken for the answer to the latest one.
+#[derive(Debug, Default)]
+pub struct Rtt {
+    seq: u64,
+    sent: Option<Instant>,
+}
+
+impl Rtt {
+    /// Note a ping is being sent, returning its payload.
+    pub fn ping(&mut self) -> [u8; 8] {
+        self.seq = self.seq.wrapping_add(1);
+        self.sent = Some(Instant::now());
+        self.seq.to_be_bytes()
+    }
+
+    /// The round trip time, if `payload` answers the outstanding ping.
+    pub fn pong(&mut self, payload: &[u8]) -> Option<Duration> {
+        if payload != self.seq.to_be_bytes() {
+            return None;
+        }
+        self.sent.take().map(|sent| sent.elapsed())
+    }
+
+    /// A coarse description of a round trip time for clients.
+    pub fn quality(rtt: Duration) -> &'static str {
+        match rtt.as_millis() {
+            0..=150 => "good",
+            151..=500 => "fair",
+            _ => "poor",
         }
     }
 }
@@ -91,23 +358,59 @@ impl WsChannelSessionState {
 pub struct WsChannelSession {
     /// unique session id
     pub id: usize,
-    /// Client must send ping at least once per CLIENT_TIMEOUT seconds,
+    /// Client must send ping at least once per `client_timeout`,
     /// otherwise we drop connection.
     pub hb: Instant,
+    /// when the connection was upgraded
+    pub started: Instant,
+    /// heartbeat round trip time
+    pub rtt: Rtt,
     // max channel lifespan
     pub expiry: Duration,
+    /// how often heartbeat pings are sent
+    pub heartbeat: Duration,
+    /// how long before lack of client response causes a timeout
+    pub client_timeout: Duration,
     /// joined channel
     pub channel: channelid::ChannelID,
     /// is the first time we're connecting?
     pub initial_connection: bool,
     /// peer name
     pub meta: meta::SenderData,
+    /// did the client connect over TLS?
+    pub secure: bool,
+    /// who is creating the channel, per `integration_header`
+    pub integration: Option<String>,
+    /// if set, sent to the client before closing, instead of joining the channel
+    pub rejection: Option<String>,
+    /// limits for the channel, if this session creates it
+    pub limits: channel_token::ChannelLimits,
+    /// the major version of the client, if it gave one
+    pub protocol: Option<u64>,
+    /// the negotiated `Sec-WebSocket-Protocol`, if the client asked for one
+    pub subprotocol: Option<&'static str>,
+    /// octets the server has queued for this session
+    pub buffered: server::Buffered,
+    /// the client's own `X-Correlation-Id`, per `accept_correlation_id`
+    pub client_correlation_id: Option<String>,
+    /// the client's token to reclaim its place, per `resume_token_secret`
+    pub resume: Option<String>,
+    /// messages received before the server assigned `id`
+    pub pending: Vec<String>,
+    /// frames sent to the client, numbering each in turn
+    pub seq: u64,
     /// Address wrapper for Channel server
     pub addr: Addr<server::ChannelServer>,
     /// logging pointer
     pub log: logging::MozLogger,
     /// metrics reporting pointer
     pub metrics: Arc<cadence::StatsdClient>,
+    /// pending message count for the Channel server
+    pub backlog: server::Backlog,
+    /// configuration options
+    pub settings: Arc<settings::Settings>,
+    /// security audit records
+    pub audit: audit::AuditLog,
 }
 
 impl Actor for WsChannelSession {
@@ -116,8 +419,26 @@ impl Actor for WsChannelSession {
     /// Method is called on actor start.
     /// We register ws session with server
     fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(rejection) = self.rejection.take() {
+            // Rejections are JSON, but don't fail to send one that isn't.
+            let rejection = serde_json::from_str(&rejection).unwrap_or(rejection.into());
+            self.send_frame(
+                ctx,
+                server::frame(&self.settings, server::FrameType::Error, rejection),
+            );
+            ctx.close(Some(ws::CloseCode::Policy.into()));
+            ctx.stop();
+            return;
+        }
         // we'll start heartbeat process on session start.
         self.hb(ctx);
+        if self.settings.send_presence && self.settings.presence_interval > 0 {
+            self.presence(ctx);
+        }
+        if self.settings.session_mailbox_cap > 0 {
+            // The server drops us rather than queue past this.
+            ctx.set_mailbox_capacity(self.settings.session_mailbox_cap);
+        }
 
         let meta = self.meta.clone();
 
@@ -127,35 +448,102 @@ impl Actor for WsChannelSession {
         // HttpContext::state() is instance of ChannelServer, state is shared
         // across all routes within application
         let addr = ctx.address();
+        self.backlog.enter();
         self.addr
             .send(server::Connect {
                 addr: addr.recipient(),
                 channel: self.channel,
                 initial_connect: self.initial_connection,
                 remote: meta.remote,
+                country: meta.country,
+                integration: self.integration.clone(),
+                limits: self.limits,
+                protocol: self.protocol,
+                buffered: self.buffered.clone(),
+                client_correlation_id: self.client_correlation_id.clone(),
+                resume: self.resume.clone(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 let remote = &act.meta.remote;
                 match res {
-                    Ok(session_id) => {
-                        if session_id == 0 {
-                            ctx.stop()
-                        }
-                        let _ = act.metrics.incr("conn.create");
+                    Ok(server::ConnectResult::Ok(session_id)) => {
+                        act.audit.record(
+                            &act.meta,
+                            act.initial_connection,
+                            audit::Outcome::Accepted,
+                        );
+                        act.metrics
+                            .incr_with_tags("conn.create")
+                            .with_tag("secure", if act.secure { "true" } else { "false" })
+                            .send();
                         debug!(
                             act.log.log,
                             "Starting new session";
                             "session" => session_id,
+                            "subprotocol" => act.subprotocol,
+                            act.meta.log_kv(act.settings.compact_meta_logs),
+                        );
+                        act.id = session_id;
+                        // Now the server knows who sent them.
+                        for text in std::mem::take(&mut act.pending) {
+                            act.relay(&text);
+                        }
+                    }
+                    Ok(server::ConnectResult::VersionMismatch) => {
+                        info!(
+                            act.log.log,
+                            "Connection refused: version mismatch";
+                            "remote_ip" => remote,
+                            "protocol" => act.protocol,
+                        );
+                        act.audit.record(
+                            &act.meta,
+                            act.initial_connection,
+                            audit::Outcome::Rejected("version_mismatch"),
+                        );
+                        let error = json!({"error": "version_mismatch"});
+                        act.send_frame(
+                            ctx,
+                            server::frame(&act.settings, server::FrameType::Error, error),
+                        );
+                        ctx.close(Some(ws::CloseCode::Policy.into()));
+                        ctx.stop()
+                    }
+                    Ok(rejected) => {
+                        info!(
+                            act.log.log,
+                            "Connection refused: {:?}", rejected;
                             "remote_ip" => remote,
                         );
-                        act.id = res.expect("Error getting session")
+                        // Server wide limits are counted by the server.
+                        let metric = match rejected {
+                            server::ConnectResult::ChannelFull => Some("conn.max.conn"),
+                            server::ConnectResult::UnknownChannel => Some("conn.reject.unknown"),
+                            server::ConnectResult::Untrusted => Some("conn.reject.untrusted"),
+                            server::ConnectResult::Draining => Some("conn.reject.draining"),
+                            _ => None,
+                        };
+                        if let Some(metric) = metric {
+                            act.metrics.incr(metric).ok();
+                        }
+                        act.audit.record(
+                            &act.meta,
+                            act.initial_connection,
+                            audit::Outcome::Rejected(rejected.reason()),
+                        );
+                        ctx.stop()
                     }
                     Err(err) => {
                         error!(act.log.log,
                         "Unhandled Error: {:?}", err;
                         "remote_ip" => remote,
                         );
+                        act.audit.record(
+                            &act.meta,
+                            act.initial_connection,
+                            audit::Outcome::Rejected("server_error"),
+                        );
                         ctx.stop()
                     }
                 }
@@ -176,11 +564,16 @@ impl Actor for WsChannelSession {
             "conn.length",
             Instant::now().duration_since(self.hb).as_millis() as u64,
         );
-        self.addr.do_send(server::Disconnect {
+        self.send_server(server::Disconnect {
             channel: self.channel,
             id: self.id,
             reason: server::DisconnectReason::None,
         });
+        // Only sessions the server let in have an id.
+        if self.id != 0 {
+            self.audit
+                .disconnected(&self.meta, self.initial_connection, self.started.elapsed());
+        }
         Running::Stop
     }
 }
@@ -189,6 +582,7 @@ impl Handler<server::TextMessage> for WsChannelSession {
     type Result = ();
 
     fn handle(&mut self, msg: server::TextMessage, ctx: &mut Self::Context) {
+        self.buffered.sub(msg.1.len());
         match msg.0 {
             server::MessageType::Terminate => {
                 debug!(
@@ -197,9 +591,36 @@ impl Handler<server::TextMessage> for WsChannelSession {
                     "session" => &self.id,
                     "remote_ip" => &self.meta.remote,
                 );
+                ctx.close(Some(ws::CloseCode::Normal.into()));
                 ctx.stop();
             }
-            server::MessageType::Text => ctx.text(msg.1),
+            server::MessageType::Drain => {
+                debug!(
+                    self.log.log,
+                    "Draining session";
+                    "session" => &self.id,
+                    "remote_ip" => &self.meta.remote,
+                );
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Other(self.settings.drain_close_code),
+                    description: Some("server_draining".to_owned()),
+                }));
+                ctx.stop();
+            }
+            server::MessageType::Evict => {
+                debug!(
+                    self.log.log,
+                    "Evicting session";
+                    "session" => &self.id,
+                    "remote_ip" => &self.meta.remote,
+                );
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Other(EVICT_CLOSE_CODE),
+                    description: Some("channel_evicted".to_owned()),
+                }));
+                ctx.stop();
+            }
+            server::MessageType::Text | server::MessageType::Control => self.send_frame(ctx, msg.1),
         }
     }
 }
@@ -229,27 +650,47 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChannelSession
                 self.hb = Instant::now();
                 ctx.pong(&msg);
             }
-            ws::Message::Pong(_) => {
+            ws::Message::Pong(payload) => {
                 self.hb = Instant::now();
+                if let Some(rtt) = self.rtt.pong(&payload) {
+                    self.report_rtt(rtt, ctx);
+                }
             }
             ws::Message::Text(text) => {
                 self.hb = Instant::now();
-                let m = text.trim();
-                self.addr.do_send(server::ClientMessage {
-                    id: self.id,
-                    message_type: server::MessageType::Text,
-                    msg: m.to_owned(),
-                    channel: self.channel,
-                    sender: self.meta.clone(),
-                })
+                if self.too_large(text.len(), ctx) || self.too_many_pending(ctx) {
+                    return;
+                }
+                self.relay(&text);
+            }
+            ws::Message::Binary(bin) => {
+                if !self.settings.relay_binary_as_text {
+                    info!(
+                        self.log.log,
+                        "Unexpected binary";
+                        "remote_ip" => &self.meta.remote,
+                    );
+                    return;
+                }
+                self.hb = Instant::now();
+                if self.too_large(bin.len(), ctx) || self.too_many_pending(ctx) {
+                    return;
+                }
+                // Peers expect text, so never pass along anything that isn't.
+                match std::str::from_utf8(&bin) {
+                    Ok(text) => self.relay(text),
+                    Err(err) => {
+                        warn!(
+                            self.log.log,
+                            "Rejecting invalid UTF-8: {:?}", err;
+                            "remote_ip" => &self.meta.remote,
+                        );
+                        self.metrics.incr("conn.bad_utf8").ok();
+                    }
+                }
             }
-            ws::Message::Binary(_) => info!(
-                self.log.log,
-                "Unexpected binary";
-                "remote_ip" => &self.meta.remote,
-            ),
             ws::Message::Close(_) => {
-                self.addr.do_send(server::Disconnect {
+                self.send_server(server::Disconnect {
                     id: self.id,
                     channel: self.channel,
                     reason: server::DisconnectReason::None,
@@ -271,13 +712,91 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChannelSession
 }
 
 impl WsChannelSession {
+    /// Pass a client message along to the other channel participants.
+    ///
+    /// Until the server has registered the session, messages are held, since
+    /// the server can't tell them apart from anyone else's.
+    fn relay(&mut self, text: &str) {
+        if self.id == 0 {
+            self.pending.push(text.to_owned());
+            return;
+        }
+        self.send_server(server::ClientMessage {
+            id: self.id,
+            message_type: server::MessageType::Text,
+            msg: text.trim().to_owned(),
+            channel: self.channel,
+            sender: self.meta.clone(),
+            received: Instant::now(),
+        })
+    }
+
+    /// Close the session if a client message is over `max_message_size`,
+    /// letting the client know why.
+    fn too_large(&mut self, len: usize, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        let max = self.settings.max_message_size;
+        if max == 0 || len <= max {
+            return false;
+        }
+        warn!(
+            self.log.log,
+            "Message too large, closing";
+            "session" => &self.id,
+            "size" => len,
+            "remote_ip" => &self.meta.remote,
+        );
+        self.metrics.incr("conn.max.message_size").ok();
+        let error = json!({"error": "message_too_large", "max": max});
+        self.send_frame(
+            ctx,
+            server::frame(&self.settings, server::FrameType::Error, error),
+        );
+        ctx.close(Some(ws::CloseCode::Size.into()));
+        self.send_server(server::Disconnect {
+            id: self.id,
+            channel: self.channel,
+            reason: server::DisconnectReason::ExcessData,
+        });
+        ctx.stop();
+        true
+    }
+
+    /// Close the session if it already holds `PENDING_LIMIT` messages that
+    /// arrived before the server registered it.
+    fn too_many_pending(&mut self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        if self.id != 0 || self.pending.len() < PENDING_LIMIT {
+            return false;
+        }
+        warn!(
+            self.log.log,
+            "Too many messages before joining, closing";
+            "remote_ip" => &self.meta.remote,
+        );
+        self.metrics.incr("conn.max.pending").ok();
+        self.pending.clear();
+        ctx.close(Some(ws::CloseCode::Policy.into()));
+        ctx.stop();
+        true
+    }
+
+    /// Send a message to the Channel server, tracking it in the server backlog.
+    fn send_server<M>(&self, msg: M)
+    where
+        M: actix::Message + Send + 'static,
+        M::Result: Send,
+        server::ChannelServer: Handler<M>,
+    {
+        self.backlog.enter();
+        self.addr.do_send(msg);
+    }
+
     /// helper method that sends ping to client every second.
     ///
     /// also this method checks heartbeats from client
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        ctx.run_interval(self.heartbeat, |act, ctx| {
             // check client heartbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.hb) > act.client_timeout {
                 // heartbeat timed out
                 info!(
                     act.log.log,
@@ -288,7 +807,7 @@ impl WsChannelSession {
                 );
 
                 // notify server
-                act.addr.do_send(server::Disconnect {
+                act.send_server(server::Disconnect {
                     id: act.id,
                     channel: act.channel,
                     reason: server::DisconnectReason::Timeout,
@@ -308,7 +827,7 @@ impl WsChannelSession {
                     "remote_ip" => &act.meta.remote,
                 );
                 act.metrics.incr("conn.timeout").ok();
-                act.addr.do_send(server::Disconnect {
+                act.send_server(server::Disconnect {
                     id: act.id,
                     channel: act.channel,
                     reason: server::DisconnectReason::Timeout,
@@ -317,7 +836,551 @@ impl WsChannelSession {
                 return;
             }
             // Send the ping.
-            ctx.ping(b"");
+            let payload = act.rtt.ping();
+            ctx.ping(&payload);
+        });
+    }
+
+    /// Send the client a frame, numbering it with `seq` unless
+    /// `legacy_envelope` is set.
+    fn send_frame(&mut self, ctx: &mut ws::WebsocketContext<Self>, frame: String) {
+        match frame.strip_prefix('{') {
+            Some(rest) if !self.settings.legacy_envelope => {
+                self.seq += 1;
+                ctx.text(format!("{{\"seq\":{},{}", self.seq, rest));
+            }
+            _ => ctx.text(frame),
+        }
+    }
+
+    /// Record a heartbeat round trip, and let the client know how its
+    /// connection is doing if `report_quality` is set.
+    fn report_rtt(&mut self, rtt: Duration, ctx: &mut ws::WebsocketContext<Self>) {
+        self.metrics
+            .time("conn.rtt_ms", rtt.as_millis() as u64)
+            .ok();
+        if self.settings.report_quality {
+            let quality = json!({
+                "type": "quality",
+                "rtt_ms": rtt.as_millis() as u64,
+                "quality": Rtt::quality(rtt),
+            });
+            self.send_frame(
+                ctx,
+                server::frame(&self.settings, server::FrameType::Control, quality),
+            );
+        }
+    }
+
+    /// helper method that periodically tells the client how many sessions
+    /// are in the channel.
+    fn presence(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        let interval = Duration::from_secs(self.settings.presence_interval);
+        ctx.run_interval(interval, |act, ctx| {
+            act.backlog.enter();
+            act.addr
+                .send(server::ChannelInfo(act.channel))
+                .into_actor(act)
+                .then(|res, act, ctx| {
+                    if let Ok(count) = res {
+                        let presence = json!({"type": "presence", "count": count});
+                        let presence =
+                            server::frame(&act.settings, server::FrameType::Presence, presence);
+                        act.send_frame(ctx, presence);
+                    }
+                    fut::ready(())
+                })
+                .spawn(ctx);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use actix::Actor;
+    use actix_codec::{Decoder, Encoder};
+    use actix_http::ws::{Codec, Frame};
+    use actix_web::{error::PayloadError, web::Bytes};
+    use bytes::BytesMut;
+    use cadence::SpyMetricSink;
+    use crossbeam_channel::Receiver;
+    use futures::{
+        channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+        StreamExt,
+    };
+
+    use crate::channelid::ChannelID;
+
+    /// A websocket session driven by raw frames rather than a network connection.
+    struct TestSession {
+        input: UnboundedSender<Result<Bytes, PayloadError>>,
+        output: UnboundedReceiver<Bytes>,
+        buf: BytesMut,
+        metrics: Receiver<Vec<u8>>,
+        audit: audit::test::Records,
+        /// how long `recv()` waits for a frame
+        wait: Duration,
+    }
+
+    impl TestSession {
+        fn start(
+            settings: settings::Settings,
+            server: &Addr<server::ChannelServer>,
+            channel: ChannelID,
+            initial_connection: bool,
+        ) -> Self {
+            Self::start_with(settings, server, channel, initial_connection, |_| {})
+        }
+
+        /// Start a session, adjusting it before it runs.
+        fn start_with(
+            settings: settings::Settings,
+            server: &Addr<server::ChannelServer>,
+            channel: ChannelID,
+            initial_connection: bool,
+            adjust: impl FnOnce(&mut WsChannelSession),
+        ) -> Self {
+            let (metrics, sink) = SpyMetricSink::new();
+            let (audit, records) = audit::test::capture();
+            let mut session = WsChannelSession {
+                id: 0,
+                hb: Instant::now(),
+                started: Instant::now(),
+                rtt: Rtt::default(),
+                expiry: Duration::from_secs(settings.conn_lifespan),
+                heartbeat: Duration::from_secs(settings.heartbeat),
+                client_timeout: Duration::from_secs(settings.client_timeout),
+                channel,
+                initial_connection,
+                meta: meta::SenderData {
+                    remote: Some("1.2.3.4".to_owned()),
+                    ..Default::default()
+                },
+                secure: false,
+                integration: None,
+                rejection: None,
+                limits: Default::default(),
+                protocol: None,
+                subprotocol: None,
+                buffered: Default::default(),
+                client_correlation_id: None,
+                resume: None,
+                pending: Vec::new(),
+                seq: 0,
+                addr: server.clone(),
+                log: logging::MozLogger::new_human(),
+                metrics: Arc::new(cadence::StatsdClient::from_sink("test", sink)),
+                backlog: server::Backlog::default(),
+                settings: Arc::new(settings),
+                audit,
+            };
+            adjust(&mut session);
+            let (input, rx) = mpsc::unbounded();
+            // The session only runs while its output is being polled.
+            let (tx, output) = mpsc::unbounded();
+            let mut stream = Box::pin(ws::WebsocketContext::create(session, rx));
+            actix_rt::spawn(async move {
+                while let Some(Ok(bytes)) = stream.next().await {
+                    if tx.unbounded_send(bytes).is_err() {
+                        break;
+                    }
+                }
+            });
+            Self {
+                input,
+                output,
+                buf: BytesMut::new(),
+                metrics,
+                audit: records,
+                wait: Duration::from_millis(500),
+            }
+        }
+
+        /// Send a frame as a client would.
+        fn send(&self, msg: ws::Message) {
+            let mut buf = BytesMut::new();
+            Codec::new().client_mode().encode(msg, &mut buf).unwrap();
+            self.input.unbounded_send(Ok(buf.freeze())).unwrap();
+        }
+
+        /// Read the next frame sent to the client.
+        async fn recv(&mut self) -> Option<Frame> {
+            let mut codec = Codec::new().client_mode();
+            loop {
+                if let Some(frame) = codec.decode(&mut self.buf).unwrap() {
+                    return Some(frame);
+                }
+                match actix_rt::time::timeout(self.wait, self.output.next()).await {
+                    Ok(Some(bytes)) => self.buf.extend_from_slice(&bytes),
+                    _ => return None,
+                }
+            }
+        }
+
+        /// Read frames until a text frame arrives.
+        async fn recv_text(&mut self) -> Option<String> {
+            while let Some(frame) = self.recv().await {
+                if let Frame::Text(text) = frame {
+                    return Some(String::from_utf8(text.to_vec()).unwrap());
+                }
+            }
+            None
+        }
+
+        fn sent_metrics(&self) -> Vec<String> {
+            self.metrics
+                .try_iter()
+                .map(|m| String::from_utf8(m).unwrap())
+                .collect()
+        }
+    }
+
+    fn test_server(settings: &settings::Settings) -> Addr<server::ChannelServer> {
+        let metrics = Arc::new(cadence::StatsdClient::from_sink(
+            "test",
+            cadence::NopMetricSink,
+        ));
+        server::ChannelServer::new(settings, &logging::MozLogger::new_human(), metrics).start()
+    }
+
+    #[actix_rt::test]
+    async fn test_binary_as_text() {
+        let settings = settings::Settings {
+            relay_binary_as_text: true,
+            ..Default::default()
+        };
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut sender = TestSession::start(settings.clone(), &server, channel, true);
+        let mut peer = TestSession::start(settings, &server, channel, false);
+        // welcome messages
+        assert!(sender.recv_text().await.is_some());
+        assert!(peer.recv_text().await.is_some());
+
+        sender.send(ws::Message::Binary(Bytes::from_static(b"\xff\xfe\xfd")));
+        sender.send(ws::Message::Binary(Bytes::from_static(b"hello")));
+        let relayed: serde_json::Value =
+            serde_json::from_str(&peer.recv_text().await.unwrap()).unwrap();
+        assert_eq!(relayed["message"], "hello");
+        assert!(peer.recv_text().await.is_none());
+        assert!(sender
+            .sent_metrics()
+            .contains(&"test.conn.bad_utf8:1|c".to_owned()));
+    }
+
+    #[actix_rt::test]
+    async fn test_binary_ignored() {
+        let settings = settings::Settings::default();
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut sender = TestSession::start(settings.clone(), &server, channel, true);
+        let mut peer = TestSession::start(settings, &server, channel, false);
+        assert!(sender.recv_text().await.is_some());
+        assert!(peer.recv_text().await.is_some());
+
+        sender.send(ws::Message::Binary(Bytes::from_static(b"hello")));
+        assert!(peer.recv_text().await.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_audit() {
+        let settings = settings::Settings {
+            max_channel_connections: 1,
+            ..Default::default()
+        };
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut sender = TestSession::start(settings.clone(), &server, channel, true);
+        assert!(sender.recv_text().await.is_some());
+        let mut peer = TestSession::start(settings.clone(), &server, channel, false);
+        assert!(peer.recv_text().await.is_none());
+        let mut guess = TestSession::start(settings, &server, ChannelID::default(), false);
+        assert!(guess.recv_text().await.is_none());
+        // Relayed messages don't produce more records.
+        sender.send(ws::Message::Text("hello".into()));
+        assert!(sender.recv_text().await.is_none());
+
+        let records = sender.audit.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["action"], "create");
+        assert_eq!(records[0]["outcome"], "accepted");
+        let records = peer.audit.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["action"], "join");
+        assert_eq!(records[0]["outcome"], "rejected");
+        assert_eq!(records[0]["reason"], "channel_full");
+        // A guessed channel id is told apart from a full channel.
+        let records = guess.audit.lock().unwrap();
+        assert_eq!(records[0]["reason"], "unknown_channel");
+    }
+
+    #[actix_rt::test]
+    async fn test_reject_metrics() {
+        let settings = settings::Settings {
+            max_channel_connections: 1,
+            ..Default::default()
+        };
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut guess = TestSession::start(settings.clone(), &server, channel, false);
+        assert!(guess.recv_text().await.is_none());
+        assert!(guess
+            .sent_metrics()
+            .contains(&"test.conn.reject.unknown:1|c".to_owned()));
+
+        let mut sender = TestSession::start(settings.clone(), &server, channel, true);
+        assert!(sender.recv_text().await.is_some());
+        let mut peer = TestSession::start(settings, &server, channel, false);
+        assert!(peer.recv_text().await.is_none());
+        let metrics = peer.sent_metrics();
+        assert!(metrics.contains(&"test.conn.max.conn:1|c".to_owned()));
+        assert!(!metrics.iter().any(|m| m.starts_with("test.conn.create")));
+    }
+
+    #[actix_rt::test]
+    async fn test_drain_close_code() {
+        let settings = settings::Settings {
+            drain_close_code: 4042,
+            ..Default::default()
+        };
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut session = TestSession::start(settings, &server, channel, true);
+        assert!(session.recv_text().await.is_some());
+        server
+            .send(server::DrainChannel(channel, Duration::ZERO))
+            .await
+            .unwrap();
+        // the draining notice, then the close
+        assert!(session.recv_text().await.is_some());
+        match session.recv().await {
+            Some(Frame::Close(Some(reason))) => {
+                assert_eq!(reason.code, ws::CloseCode::Other(4042));
+                assert_eq!(reason.description.as_deref(), Some("server_draining"));
+            }
+            other => panic!("Expected close, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_open_iploc() {
+        assert!(open_iploc("mmdb/latest/GeoLite2-City.mmdb").is_ok());
+        let mut buf = std::fs::read("mmdb/latest/GeoLite2-City.mmdb").unwrap();
+        buf.iter_mut().take(4096).for_each(|b| *b = 0xff);
+        let path = std::env::temp_dir().join(format!("corrupt-{}.mmdb", std::process::id()));
+        std::fs::write(&path, buf).unwrap();
+        let result = open_iploc(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            result,
+            Err(maxminddb::MaxMindDBError::InvalidDatabaseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_rtt() {
+        let mut rtt = Rtt::default();
+        let first = rtt.ping();
+        let second = rtt.ping();
+        std::thread::sleep(Duration::from_millis(10));
+        // a late pong for an earlier ping
+        assert_eq!(rtt.pong(&first), None);
+        assert!(rtt.pong(&second).unwrap() >= Duration::from_millis(10));
+        // only measured once
+        assert_eq!(rtt.pong(&second), None);
+        assert_eq!(rtt.pong(b""), None);
+
+        assert_eq!(Rtt::quality(Duration::from_millis(20)), "good");
+        assert_eq!(Rtt::quality(Duration::from_millis(300)), "fair");
+        assert_eq!(Rtt::quality(Duration::from_secs(2)), "poor");
+    }
+
+    #[actix_rt::test]
+    async fn test_rejection() {
+        let settings = settings::Settings::default();
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut session = TestSession::start_with(settings, &server, channel, true, |session| {
+            session.rejection = Some(r#"{"error":"client_too_old","min":"2.0.0"}"#.to_owned())
         });
+        let rejection: serde_json::Value =
+            serde_json::from_str(&session.recv_text().await.unwrap()).unwrap();
+        assert_eq!(rejection["error"], "client_too_old");
+        match session.recv().await {
+            Some(Frame::Close(Some(reason))) => assert_eq!(reason.code, ws::CloseCode::Policy),
+            other => panic!("Expected close, got {:?}", other),
+        }
+        // and never joined the channel
+        assert_eq!(server.send(server::ChannelInfo(channel)).await.unwrap(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_max_message_size() {
+        let settings = settings::Settings {
+            max_message_size: 8,
+            ..Default::default()
+        };
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut sender = TestSession::start(settings.clone(), &server, channel, true);
+        assert!(sender.recv_text().await.is_some());
+        let mut peer = TestSession::start(settings, &server, channel, false);
+        assert!(peer.recv_text().await.is_some());
+
+        sender.send(ws::Message::Text("12345678".into()));
+        assert!(peer.recv_text().await.is_some());
+        sender.send(ws::Message::Text("123456789".into()));
+        let error: serde_json::Value =
+            serde_json::from_str(&sender.recv_text().await.unwrap()).unwrap();
+        assert_eq!(error, json!({"error": "message_too_large", "max": 8}));
+        match sender.recv().await {
+            Some(Frame::Close(Some(reason))) => assert_eq!(reason.code, ws::CloseCode::Size),
+            other => panic!("Expected close, got {:?}", other),
+        }
+        assert!(sender
+            .sent_metrics()
+            .contains(&"test.conn.max.message_size:1|c".to_owned()));
+    }
+
+    #[actix_rt::test]
+    async fn test_secure_tag() {
+        let settings = settings::Settings::default();
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut sender =
+            TestSession::start_with(settings.clone(), &server, channel, true, |session| {
+                session.secure = true
+            });
+        assert!(sender.recv_text().await.is_some());
+        let mut peer = TestSession::start(settings, &server, channel, false);
+        assert!(peer.recv_text().await.is_some());
+
+        assert!(sender
+            .sent_metrics()
+            .contains(&"test.conn.create:1|c|#secure:true".to_owned()));
+        assert!(peer
+            .sent_metrics()
+            .contains(&"test.conn.create:1|c|#secure:false".to_owned()));
+    }
+
+    #[actix_rt::test]
+    async fn test_send_before_welcome() {
+        let settings = settings::Settings::default();
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut sender = TestSession::start(settings.clone(), &server, channel, true);
+        assert!(sender.recv_text().await.is_some());
+        let mut peer = TestSession::start(settings, &server, channel, false);
+        // Don't wait for the welcome.
+        peer.send(ws::Message::Text("hello".into()));
+
+        let msg: serde_json::Value =
+            serde_json::from_str(&sender.recv_text().await.unwrap()).unwrap();
+        assert_eq!(msg["message"], "hello");
+        // The peer only gets its welcome, not its own message.
+        let welcome: serde_json::Value =
+            serde_json::from_str(&peer.recv_text().await.unwrap()).unwrap();
+        assert!(welcome["channelid"].is_string());
+        assert!(peer.recv_text().await.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_envelope() {
+        let settings = settings::Settings {
+            legacy_envelope: false,
+            max_message_size: 8,
+            ..Default::default()
+        };
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut sender = TestSession::start(settings.clone(), &server, channel, true);
+        let welcome: serde_json::Value =
+            serde_json::from_str(&sender.recv_text().await.unwrap()).unwrap();
+        assert_eq!(welcome["type"], "control");
+        assert_eq!(welcome["seq"], 1);
+        assert_eq!(welcome["payload"]["channelid"], channel.as_string());
+        let mut peer = TestSession::start(settings.clone(), &server, channel, false);
+        assert!(peer.recv_text().await.is_some());
+
+        sender.send(ws::Message::Text("hello".into()));
+        let msg: serde_json::Value =
+            serde_json::from_str(&peer.recv_text().await.unwrap()).unwrap();
+        assert_eq!(msg["type"], "message");
+        assert_eq!(msg["seq"], 2);
+        assert_eq!(msg["payload"], "hello");
+        assert!(msg["sender"].is_object());
+
+        // Frames from the session itself are numbered along with the rest.
+        sender.send(ws::Message::Text("123456789".into()));
+        let error: serde_json::Value =
+            serde_json::from_str(&sender.recv_text().await.unwrap()).unwrap();
+        assert_eq!(
+            error,
+            json!({
+                "type": "error",
+                "seq": 2,
+                "payload": {"error": "message_too_large", "max": 8},
+            })
+        );
+
+        let mut rejected = TestSession::start_with(settings, &server, channel, false, |session| {
+            session.rejection = Some(r#"{"error":"client_too_old","min":"2.0.0"}"#.to_owned())
+        });
+        let rejection: serde_json::Value =
+            serde_json::from_str(&rejected.recv_text().await.unwrap()).unwrap();
+        assert_eq!(rejection["type"], "error");
+        assert_eq!(rejection["seq"], 1);
+        assert_eq!(rejection["payload"]["error"], "client_too_old");
+    }
+
+    /// Wait for a presence message reporting `count` sessions.
+    async fn await_presence(session: &mut TestSession, count: usize) {
+        let expected = json!({"type": "presence", "count": count});
+        while let Some(text) = session.recv_text().await {
+            if serde_json::from_str::<serde_json::Value>(&text).unwrap() == expected {
+                return;
+            }
+        }
+        panic!("No presence message for {} sessions", count);
+    }
+
+    #[actix_rt::test]
+    async fn test_presence() {
+        let settings = settings::Settings {
+            send_presence: true,
+            presence_interval: 1,
+            ..Default::default()
+        };
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut sender = TestSession::start(settings.clone(), &server, channel, true);
+        sender.wait = Duration::from_secs(2);
+        await_presence(&mut sender, 1).await;
+
+        let mut peer = TestSession::start(settings, &server, channel, false);
+        assert!(peer.recv_text().await.is_some());
+        await_presence(&mut sender, 2).await;
+
+        peer.send(ws::Message::Close(None));
+        await_presence(&mut sender, 1).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_presence_without_relay() {
+        let settings = settings::Settings {
+            relay_enabled: false,
+            send_presence: true,
+            presence_interval: 1,
+            ..Default::default()
+        };
+        let server = test_server(&settings);
+        let channel = ChannelID::default();
+        let mut sender = TestSession::start(settings.clone(), &server, channel, true);
+        sender.wait = Duration::from_secs(2);
+        let mut peer = TestSession::start(settings, &server, channel, false);
+        assert!(peer.recv_text().await.is_some());
+        await_presence(&mut sender, 2).await;
     }
 }
@@ -3,8 +3,15 @@ use std::env;
 use config::{Config, ConfigError, Environment, File};
 use serde::{Deserialize, Serialize};
 
+use crate::channelid::{CHANNELID_LEN, MAX_CHANNELID_LEN, MIN_CHANNELID_LEN};
+
 static PREFIX: &str = "PAIR";
 
+// Rough memory cost of a session: its actor, websocket buffers and mailbox.
+const SESSION_BYTES: u64 = 64 * 1024;
+// Rough memory cost of a channel's bookkeeping, apart from its sessions.
+const CHANNEL_BYTES: u64 = 4 * 1024;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(default)]
 pub struct Settings {
@@ -18,15 +25,110 @@ pub struct Settings {
     pub debug: bool,                  // In debug mode? (false)
     pub verbose: bool,                // Verbose Errors? (false)
     pub mmdb_loc: String,             // MaxMind database path ("mmdb/latest/GeoLite2-City.mmdb")
-    pub statsd_host: String,          // Metric statsd host (localhost)
+    pub statsd_host: String,          // Metric statsd host[:port] (localhost:8125)
     pub trusted_proxy_list: String,   // comma delimited list of proxy hosts ("")
+    pub remote_ip_sources: String,    // Headers, or "peer", to find the remote IP in, in order
     pub ip_reputation_server: String, // IP Reputation server. Leave blank to disable ("")
     pub iprep_min: u8,                // Minimum IP Reputation (0)
+    pub iprep_cache_ttl: u64,         // Seconds to remember a reputation, 0 to skip (60)
     pub ip_violation: String,         // Name of the abuse violation
+    pub iprepd_report_cooldown: u64,  // Seconds before reporting an IP again (300)
+    pub iprepd_deadline_ms: u64,      // Max wait for a reputation check, 0 for no limit (250)
     pub heartbeat: u64,               // Heartbeat rate in seconds for pings (5)
     pub human_logs: bool,             // Show "Human readable" logs (false)
+    pub log_format: String,           // "json", "human" or "logfmt" ("json")
     pub default_lang: String,         // Default language if none presented? (None)
     pub metric_name: String,          // Optional replacement metric name
+    pub gauge_interval: u64,          // Seconds between gauge reports, 0 to disable (10)
+    pub pad_channel_links: bool,      // Include base64 padding in welcome links (false)
+    pub max_channel_age: u64,         // Max channel lifetime in seconds, 0 for unlimited (0)
+    pub relay_latency_metrics: bool,  // Report in-server relay latency per message (false)
+    pub expose_stats: bool,           // Answer client `{"type":"stats"}` requests (false)
+    pub reserved_channels: String,    // comma delimited reserved channel ids or "prefix*" ("")
+    pub log_features: bool,           // Log which optional features are enabled at startup (true)
+    pub max_concurrent_geo: usize,    // Max geo lookups in progress, 0 for unlimited (0)
+    pub geo_wait_ms: u64,             // Wait for a geo lookup slot before skipping geo (0)
+    pub max_meta_processing_ms: u64,  // Give up on sender geo/language data after this (0)
+    pub emit_events: bool,            // Log a structured analytics event per closed channel (false)
+    pub relay_binary_as_text: bool,   // Relay binary frames that are valid UTF-8 as text (false)
+    pub closed_lru_size: usize,       // Recently closed channels to remember, 0 to disable (1000)
+    pub closed_ttl: u64,              // Seconds to remember a closed channel (300)
+    pub closed_abuse_ttl: u64,        // Seconds to remember a channel closed for abuse (3600)
+    pub distinct_ip_window: u64,      // Seconds per distinct remote IP count, 0 to disable (60)
+    pub security_audit: bool,         // Log an audit record per connection attempt (false)
+    pub session_mailbox_cap: usize,   // Max messages queued per session, 0 for unbounded (0)
+    pub send_presence: bool,          // Periodically send clients the session count (false)
+    pub presence_interval: u64,       // Seconds between presence messages (5)
+    pub strict_ws_version: bool,      // Reject Sec-WebSocket-Version other than 13 (false)
+    pub static_dir: String,           // Directory of files to serve, e.g. a demo client ("")
+    pub static_path: String,          // Path to serve `static_dir` under ("/static")
+    pub subnet_prefix_v4: u8,         // IPv4 prefix length for subnet limits (24)
+    pub subnet_prefix_v6: u8,         // IPv6 prefix length for subnet limits (64)
+    pub require_preallocated: bool,   // Only connect to channels from POST /v1/channels (false)
+    pub preallocate_ttl: u64,         // Seconds a preallocated channel waits for a client (60)
+    pub compact_meta_logs: bool,      // Log sender data as one JSON "meta" field (false)
+    pub max_control_messages: usize,  // Control requests per connection, 0 for unlimited (0)
+    pub include_timestamp: bool,      // Add the relay time as "ts" to relayed messages (false)
+    pub relay_enabled: bool,          // Relay client messages, false for control only (true)
+    pub principal_count: usize,       // Parties that may join a channel from any address (2)
+    pub snapshot_mode: bool,          // Send late joiners the channel's latest message (false)
+    pub max_channels: usize,          // Max open channels, 0 for unlimited (0)
+    pub max_sessions: usize,          // Max connected sessions, 0 for unlimited (0)
+    pub memory_budget_mb: u64,        // Derive unset max_channels/max_sessions from this (0)
+    pub drain_close_code: u16,        // Websocket close code sent when draining (4001)
+    pub report_quality: bool,         // Send clients a quality hint from heartbeat RTT (false)
+    pub channelid_len: usize,         // Bytes of randomness in a channel id, 16 to 64 (16)
+    pub integration_header: String,   // Header identifying who is creating a channel ("")
+    pub public_path_prefix: String,   // Path the server is mounted under by a proxy ("")
+    pub max_inflight_upgrades: usize, // Websocket upgrades in progress at once, 0 unlimited (0)
+    pub admin_token: String,          // Bearer token for /__channels__, blank to disable ("")
+    pub min_client_version: String,   // Refuse clients older than this semver, if set ("")
+    pub trust_loopback_in_xff: bool,  // Accept loopback addresses in forwarding headers (false)
+    pub near_limit_percent: usize,    // Report conn.near_limit at this % of a limit, 0 to skip (80)
+    pub max_message_size: usize,      // Max octets in one client message, 0 for unlimited (0)
+    pub channel_token_secret: String, // HMAC secret for per channel limit tokens ("")
+    pub quota_errors: bool,           // Tell clients which limit closed their channel (true)
+    pub aggregate_metrics: String,    // Comma delimited counters to sum before sending ("")
+    pub aggregate_interval: u64,      // Seconds between sending aggregated counters (10)
+    pub presence_events: bool,        // Tell participants when a peer joins or leaves (false)
+    pub report_load: bool,            // Include the server's load in /__lbheartbeat__ (false)
+    pub relay_caps: bool,             // Pass `{"type":"caps"}` messages to peers unmetered (false)
+    pub match_versions: bool,         // Refuse joins with another major client version (false)
+    pub reconnect_coalesce_ms: u64,   // Resume a session dropped this recently, 0 to skip (0)
+    pub geo_cache_size: usize,        // Recent GeoIP lookups to remember, 0 to skip (0)
+    pub event_bus_url: String,        // Publish connection events to this NATS url ("")
+    pub event_bus_queue: usize,       // Max events waiting to be published (1000)
+    pub asn_mmdb_loc: String,         // MaxMind ASN database path, for `asn` and `org` ("")
+    pub auth_hook_url: String,        // Ask this url whether to allow connections ("")
+    pub auth_hook_deadline_ms: u64,   // Max wait for the auth hook, 0 for no limit (250)
+    pub geo_include_timezone: bool,   // Include the sender's timezone in sender data (true)
+    pub admin_rate_limit: u32,        // Admin requests allowed a minute, 0 for unlimited (60)
+    pub accept_correlation_id: bool,  // Log clients' X-Correlation-Id with their channel (false)
+    pub evict_on_full: bool,          // Evict the oldest idle channel at max_channels (false)
+    pub evict_idle_secs: u64,         // Seconds a channel must be idle to be evicted (60)
+    pub resume_token_secret: String,  // HMAC secret for tokens to reclaim a dropped slot ("")
+    pub legacy_envelope: bool,        // Send frames in their old, unnumbered shapes (true)
+    pub sweep_interval: u64,          // Seconds between abandoned channel sweeps, 0 to skip (0)
+    pub idle_grace: u64,              // Seconds a channel may have no one connected (30)
+    pub prometheus_port: u16,         // Serve Prometheus /metrics on this port, 0 to skip (0)
+    // Max sessions per subnet, 0 for unlimited (0)
+    pub max_connections_per_subnet: usize,
+    // Only create channels at "/v1/ws/", not "/v1/ws" (false)
+    pub require_ws_trailing_slash: bool,
+    // Max open channels created per `integration_header` value, 0 for unlimited (0)
+    pub max_channels_per_integration: usize,
+    // Let in clients that don't give a version when `min_client_version` is set (true)
+    pub allow_unknown_client_version: bool,
+    // Accept-Language entries to consider, 0 for unlimited (0)
+    pub max_accept_language_entries: usize,
+    // Octets queued across all sessions before the slowest are dropped, 0 for unlimited (0)
+    pub max_total_buffered_bytes: usize,
+    // Let addresses in ("open") or not ("closed") when iprepd fails or is too slow ("open")
+    pub iprepd_timeout_policy: String,
+    // Let connections in ("allow") or not ("deny") when the auth hook fails ("allow")
+    pub auth_hook_error_policy: String,
+    // Report addresses that trip channel quotas to iprepd (true)
+    pub iprepd_report_violators: bool,
 }
 
 impl Default for Settings {
@@ -44,13 +146,99 @@ impl Default for Settings {
             mmdb_loc: "mmdb/latest/GeoLite2-City.mmdb".to_owned(),
             statsd_host: "localhost:8125".to_owned(),
             trusted_proxy_list: "".to_owned(),
+            remote_ip_sources: crate::meta::DEFAULT_REMOTE_IP_SOURCES.to_owned(),
             ip_reputation_server: "".to_owned(),
             iprep_min: 0,
+            iprep_cache_ttl: 60,
             ip_violation: "channel_abuse".to_owned(),
+            iprepd_report_cooldown: 300,
+            iprepd_deadline_ms: 250,
+            iprepd_timeout_policy: "open".to_owned(),
             heartbeat: 5,
             human_logs: false,
+            log_format: "json".to_owned(),
             default_lang: "en".to_owned(),
             metric_name: env!("CARGO_PKG_NAME").to_owned(),
+            gauge_interval: 10,
+            pad_channel_links: false,
+            max_channel_age: 0,
+            relay_latency_metrics: false,
+            expose_stats: false,
+            reserved_channels: "".to_owned(),
+            log_features: true,
+            max_concurrent_geo: 0,
+            geo_wait_ms: 0,
+            max_meta_processing_ms: 0,
+            emit_events: false,
+            relay_binary_as_text: false,
+            closed_lru_size: 1000,
+            closed_ttl: 300,
+            closed_abuse_ttl: 3600,
+            distinct_ip_window: 60,
+            security_audit: false,
+            session_mailbox_cap: 0,
+            send_presence: false,
+            presence_interval: 5,
+            strict_ws_version: false,
+            require_ws_trailing_slash: false,
+            static_dir: "".to_owned(),
+            static_path: "/static".to_owned(),
+            max_connections_per_subnet: 0,
+            subnet_prefix_v4: 24,
+            subnet_prefix_v6: 64,
+            require_preallocated: false,
+            preallocate_ttl: 60,
+            compact_meta_logs: false,
+            max_control_messages: 0,
+            include_timestamp: false,
+            relay_enabled: true,
+            principal_count: 2,
+            snapshot_mode: false,
+            max_channels: 0,
+            max_sessions: 0,
+            memory_budget_mb: 0,
+            drain_close_code: 4001,
+            report_quality: false,
+            channelid_len: CHANNELID_LEN,
+            integration_header: "".to_owned(),
+            public_path_prefix: "".to_owned(),
+            max_inflight_upgrades: 0,
+            admin_token: "".to_owned(),
+            min_client_version: "".to_owned(),
+            trust_loopback_in_xff: false,
+            near_limit_percent: 80,
+            max_message_size: 0,
+            channel_token_secret: "".to_owned(),
+            quota_errors: true,
+            aggregate_metrics: "".to_owned(),
+            aggregate_interval: 10,
+            presence_events: false,
+            report_load: false,
+            relay_caps: false,
+            match_versions: false,
+            reconnect_coalesce_ms: 0,
+            geo_cache_size: 0,
+            event_bus_url: "".to_owned(),
+            event_bus_queue: 1000,
+            asn_mmdb_loc: "".to_owned(),
+            auth_hook_url: "".to_owned(),
+            auth_hook_deadline_ms: 250,
+            auth_hook_error_policy: "allow".to_owned(),
+            geo_include_timezone: true,
+            admin_rate_limit: 60,
+            accept_correlation_id: false,
+            evict_on_full: false,
+            evict_idle_secs: 60,
+            resume_token_secret: "".to_owned(),
+            legacy_envelope: true,
+            sweep_interval: 0,
+            idle_grace: 30,
+            prometheus_port: 0,
+            iprepd_report_violators: true,
+            max_accept_language_entries: 0,
+            max_total_buffered_bytes: 0,
+            allow_unknown_client_version: true,
+            max_channels_per_integration: 0,
         }
     }
 }
@@ -64,6 +252,255 @@ impl Settings {
         // start with any local config file.
         config = config.add_source(File::with_name(&format!("config/{}", env)).required(false));
         config = config.add_source(Environment::with_prefix(PREFIX));
-        config.build()?.try_deserialize::<Self>()
+        let settings = config.build()?.try_deserialize::<Self>()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Reject settings that can't work together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if !(MIN_CHANNELID_LEN..=MAX_CHANNELID_LEN).contains(&self.channelid_len) {
+            return Err(ConfigError::Message(format!(
+                "channelid_len must be between {} and {}",
+                MIN_CHANNELID_LEN, MAX_CHANNELID_LEN
+            )));
+        }
+        if self.heartbeat == 0 || self.heartbeat >= self.client_timeout {
+            return Err(ConfigError::Message(format!(
+                "heartbeat ({}s) must be non-zero and less than client_timeout ({}s)",
+                self.heartbeat, self.client_timeout
+            )));
+        }
+        if !self.min_client_version.is_empty()
+            && semver::Version::parse(&self.min_client_version).is_err()
+        {
+            return Err(ConfigError::Message(format!(
+                "min_client_version is not a semver version: {:?}",
+                self.min_client_version
+            )));
+        }
+        if !["open", "closed"].contains(&self.iprepd_timeout_policy.as_str()) {
+            return Err(ConfigError::Message(format!(
+                "iprepd_timeout_policy must be \"open\" or \"closed\": {:?}",
+                self.iprepd_timeout_policy
+            )));
+        }
+        if !["allow", "deny"].contains(&self.auth_hook_error_policy.as_str()) {
+            return Err(ConfigError::Message(format!(
+                "auth_hook_error_policy must be \"allow\" or \"deny\": {:?}",
+                self.auth_hook_error_policy
+            )));
+        }
+        let prefix = &self.public_path_prefix;
+        if prefix.split('/').any(|part| part == "." || part == "..")
+            || prefix
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || "?#%\\".contains(c))
+        {
+            return Err(ConfigError::Message(format!(
+                "public_path_prefix is not a valid path: {:?}",
+                prefix
+            )));
+        }
+        Ok(())
+    }
+
+    /// `public_path_prefix`, as either "" or "/path" without a trailing slash.
+    pub fn public_path(&self) -> String {
+        let prefix = self.public_path_prefix.trim_matches('/');
+        if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", prefix)
+        }
+    }
+
+    /// Fill in any of `max_channels` and `max_sessions` left unset from
+    /// `memory_budget_mb`, using rough estimates of what each costs.
+    ///
+    /// A session is estimated at `SESSION_BYTES`, and a channel at
+    /// `CHANNEL_BYTES` plus one session for each of its `principal_count`
+    /// parties, so a 1024MB budget allows 16384 sessions and 7943 channels.
+    pub fn apply_memory_budget(&mut self) {
+        if self.memory_budget_mb == 0 {
+            return;
+        }
+        let budget = self.memory_budget_mb * 1024 * 1024;
+        if self.max_sessions == 0 {
+            self.max_sessions = (budget / SESSION_BYTES) as usize;
+        }
+        if self.max_channels == 0 {
+            let channel = CHANNEL_BYTES + SESSION_BYTES * self.principal_count as u64;
+            self.max_channels = (budget / channel) as usize;
+        }
+    }
+
+    /// Which optional subsystems this configuration enables.
+    pub fn features(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("geo", !self.mmdb_loc.is_empty()),
+            ("metrics", !self.statsd_host.is_empty()),
+            ("iprepd", !self.ip_reputation_server.is_empty()),
+            ("max_channel_age", self.max_channel_age > 0),
+            ("relay_latency_metrics", self.relay_latency_metrics),
+            ("expose_stats", self.expose_stats),
+            ("pad_channel_links", self.pad_channel_links),
+            ("reserved_channels", !self.reserved_channels.is_empty()),
+            ("max_concurrent_geo", self.max_concurrent_geo > 0),
+            ("emit_events", self.emit_events),
+            ("relay_binary_as_text", self.relay_binary_as_text),
+            ("security_audit", self.security_audit),
+            ("session_mailbox_cap", self.session_mailbox_cap > 0),
+            ("send_presence", self.send_presence),
+            ("strict_ws_version", self.strict_ws_version),
+            ("static_dir", !self.static_dir.is_empty()),
+            ("require_preallocated", self.require_preallocated),
+            ("include_timestamp", self.include_timestamp),
+            ("relay_enabled", self.relay_enabled),
+            ("snapshot_mode", self.snapshot_mode),
+            ("report_quality", self.report_quality),
+            ("max_channels", self.max_channels > 0),
+            ("max_sessions", self.max_sessions > 0),
+            ("max_inflight_upgrades", self.max_inflight_upgrades > 0),
+            ("admin", !self.admin_token.is_empty()),
+            ("min_client_version", !self.min_client_version.is_empty()),
+            ("max_message_size", self.max_message_size > 0),
+            ("channel_token", !self.channel_token_secret.is_empty()),
+            ("presence_events", self.presence_events),
+            ("relay_caps", self.relay_caps),
+            ("match_versions", self.match_versions),
+            ("reconnect_coalesce", self.reconnect_coalesce_ms > 0),
+            ("geo_cache", self.geo_cache_size > 0),
+            ("event_bus", !self.event_bus_url.is_empty()),
+            ("asn", !self.asn_mmdb_loc.is_empty()),
+            ("auth_hook", !self.auth_hook_url.is_empty()),
+            ("accept_correlation_id", self.accept_correlation_id),
+            ("evict_on_full", self.evict_on_full),
+            ("resume_token", !self.resume_token_secret.is_empty()),
+            ("legacy_envelope", self.legacy_envelope),
+            ("sweep", self.sweep_interval > 0),
+            ("prometheus", self.prometheus_port > 0),
+            (
+                "max_connections_per_subnet",
+                self.max_connections_per_subnet > 0,
+            ),
+            (
+                "max_channels_per_integration",
+                !self.integration_header.is_empty() && self.max_channels_per_integration > 0,
+            ),
+        ]
+    }
+
+    /// A single line summary of `features()`, e.g. "geo=on metrics=off".
+    pub fn feature_summary(&self) -> String {
+        self.features()
+            .iter()
+            .map(|(name, enabled)| format!("{}={}", name, if *enabled { "on" } else { "off" }))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_features() {
+        let settings = Settings {
+            statsd_host: "".to_owned(),
+            expose_stats: true,
+            ..Default::default()
+        };
+        let summary = settings.feature_summary();
+        assert!(summary.starts_with("geo=on metrics=off iprepd=off "));
+        assert!(summary.contains(" expose_stats=on "));
+
+        let settings = Settings {
+            ip_reputation_server: "http://iprepd".to_owned(),
+            ..settings
+        };
+        assert!(settings.features().contains(&("iprepd", true)));
+        assert!(settings.feature_summary().contains(" iprepd=on "));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Settings::default().validate().is_ok());
+        for (len, valid) in [(15, false), (16, true), (64, true), (65, false)] {
+            let settings = Settings {
+                channelid_len: len,
+                ..Default::default()
+            };
+            assert_eq!(settings.validate().is_ok(), valid, "{}", len);
+        }
+        for (prefix, valid) in [
+            ("/pair", true),
+            ("svc/pair/", true),
+            ("/../admin", false),
+            ("/pair?x=1", false),
+            ("/my pair", false),
+        ] {
+            let settings = Settings {
+                public_path_prefix: prefix.to_owned(),
+                ..Default::default()
+            };
+            assert_eq!(settings.validate().is_ok(), valid, "{}", prefix);
+        }
+        for (version, valid) in [("", true), ("2.1.0", true), ("2.1", false)] {
+            let settings = Settings {
+                min_client_version: version.to_owned(),
+                ..Default::default()
+            };
+            assert_eq!(settings.validate().is_ok(), valid, "{}", version);
+        }
+        for (heartbeat, client_timeout, valid) in [(5, 30, true), (0, 30, false), (30, 30, false)] {
+            let settings = Settings {
+                heartbeat,
+                client_timeout,
+                ..Default::default()
+            };
+            assert_eq!(settings.validate().is_ok(), valid, "{}", heartbeat);
+        }
+        for (policy, valid) in [("open", true), ("closed", true), ("shut", false)] {
+            let settings = Settings {
+                iprepd_timeout_policy: policy.to_owned(),
+                ..Default::default()
+            };
+            assert_eq!(settings.validate().is_ok(), valid, "{}", policy);
+        }
+        for (policy, valid) in [("allow", true), ("deny", true), ("open", false)] {
+            let settings = Settings {
+                auth_hook_error_policy: policy.to_owned(),
+                ..Default::default()
+            };
+            assert_eq!(settings.validate().is_ok(), valid, "{}", policy);
+        }
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let mut settings = Settings {
+            memory_budget_mb: 1024,
+            ..Default::default()
+        };
+        settings.apply_memory_budget();
+        assert_eq!(settings.max_sessions, 16384);
+        assert_eq!(settings.max_channels, 7943);
+
+        let mut settings = Settings {
+            memory_budget_mb: 1024,
+            max_channels: 10,
+            ..Default::default()
+        };
+        settings.apply_memory_budget();
+        assert_eq!(settings.max_sessions, 16384);
+        assert_eq!(settings.max_channels, 10);
+
+        // no budget, no limits
+        let mut settings = Settings::default();
+        settings.apply_memory_budget();
+        assert_eq!(settings.max_sessions, 0);
+        assert_eq!(settings.max_channels, 0);
     }
 }